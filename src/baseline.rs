@@ -0,0 +1,68 @@
+//! Compare a run against a previously saved result (`--baseline`).
+
+use serde::{Deserialize, Serialize};
+use std::fs;
+
+use crate::PingResult;
+
+/// Relative latency growth that counts as a regression.
+const LATENCY_REGRESSION: f64 = 0.2;
+/// Packet loss growth, in percentage points, that counts as a regression.
+const LOSS_REGRESSION: f64 = 1.0;
+
+/// Change from the baseline: current minus baseline for every metric.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Delta {
+    pub latency: i64,
+    pub latency_p95: i64,
+    pub packetloss: f64,
+    pub packets_received: i64,
+    pub regressed: bool,
+}
+
+/// Load a result saved with `--save-baseline` (or any peckr JSON output):
+/// either a single result or an array of them.
+pub fn load(path: &str) -> Result<Vec<PingResult>, String> {
+    let contents = fs::read_to_string(path)
+        .map_err(|e| format!("Failed to read baseline {}: {}", path, e))?;
+    let value: serde_json::Value = serde_json::from_str(&contents)
+        .map_err(|e| format!("Invalid baseline {}: {}", path, e))?;
+    let results = if value.is_array() {
+        serde_json::from_value(value)
+    } else {
+        serde_json::from_value(value).map(|result| vec![result])
+    };
+    results.map_err(|e| format!("Invalid baseline {}: {}", path, e))
+}
+
+pub fn save(path: &str, results: &[&PingResult]) -> Result<(), String> {
+    let json = match results {
+        [result] => serde_json::to_string_pretty(result),
+        _ => serde_json::to_string_pretty(results),
+    }
+    .map_err(|e| e.to_string())?;
+    fs::write(path, json + "\n").map_err(|e| format!("Failed to write baseline {}: {}", path, e))
+}
+
+fn latency_regressed(current: i64, baseline: i64) -> bool {
+    current > baseline && (current - baseline) as f64 > baseline as f64 * LATENCY_REGRESSION
+}
+
+/// A run regressed when it stopped meeting its thresholds, lost noticeably
+/// more packets, or got more than 20% slower on average or at p95.
+pub fn compare(current: &PingResult, baseline: &PingResult) -> Option<Delta> {
+    let (now, then) = (current.data.as_ref()?, baseline.data.as_ref()?);
+    let packetloss = now.packetloss - then.packetloss;
+    let regressed = (baseline.success && !current.success)
+        || packetloss > LOSS_REGRESSION
+        || latency_regressed(now.latency, then.latency)
+        || latency_regressed(now.latency_p95, then.latency_p95);
+
+    Some(Delta {
+        latency: now.latency - then.latency,
+        latency_p95: now.latency_p95 - then.latency_p95,
+        packetloss,
+        packets_received: now.packets_received as i64 - then.packets_received as i64,
+        regressed,
+    })
+}
@@ -0,0 +1,113 @@
+//! Field-name casing of the JSON output (`--json-case`), and dropping its
+//! null fields (`--omit-null`).
+//!
+//! The result types keep their historical field names, which mix run-together
+//! words (`servername`, `packetloss`) with snake_case (`packets_sent`). The
+//! casing is applied to the serialized value so every JSON writer shares it.
+//! Nulls are dropped the same way, since whether a field is null depends on
+//! the run rather than on its type.
+
+use clap::ValueEnum;
+use serde::Serialize;
+use serde_json::{Map, Value};
+
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum JsonCase {
+    /// Field names as they have always been emitted
+    Original,
+    /// snake_case, with the run-together names split into words
+    Snake,
+    /// camelCase
+    Camel,
+}
+
+/// Historical names that are several words run together.
+const LEGACY_NAMES: [(&str, &str); 4] = [
+    ("checkname", "check_name"),
+    ("servername", "server_name"),
+    ("resulttype", "result_type"),
+    ("packetloss", "packet_loss"),
+];
+
+fn snake(key: &str) -> String {
+    LEGACY_NAMES
+        .iter()
+        .find(|(legacy, _)| *legacy == key)
+        .map(|(_, snake)| snake.to_string())
+        .unwrap_or_else(|| key.to_string())
+}
+
+fn camel(key: &str) -> String {
+    let mut out = String::with_capacity(key.len());
+    let mut upper = false;
+    for c in snake(key).chars() {
+        if c == '_' {
+            upper = true;
+        } else if upper {
+            out.extend(c.to_uppercase());
+            upper = false;
+        } else {
+            out.push(c);
+        }
+    }
+    out
+}
+
+fn rename_keys(value: Value, rename: &dyn Fn(&str) -> String) -> Value {
+    match value {
+        Value::Object(map) => Value::Object(
+            map.into_iter()
+                .map(|(key, value)| (rename(&key), rename_keys(value, rename)))
+                .collect::<Map<String, Value>>(),
+        ),
+        Value::Array(items) => Value::Array(items.into_iter().map(|v| rename_keys(v, rename)).collect()),
+        other => other,
+    }
+}
+
+/// Remove the fields whose value is null, at any depth. Nulls inside arrays
+/// stay, so positions keep their meaning.
+fn omit_nulls(value: Value) -> Value {
+    match value {
+        Value::Object(map) => Value::Object(
+            map.into_iter()
+                .filter(|(_, value)| !value.is_null())
+                .map(|(key, value)| (key, omit_nulls(value)))
+                .collect::<Map<String, Value>>(),
+        ),
+        Value::Array(items) => Value::Array(items.into_iter().map(omit_nulls).collect()),
+        other => other,
+    }
+}
+
+/// The serialized value with `case` applied and, with `omit_null`, its null
+/// fields removed, for writers other than JSON.
+pub fn to_value<T: Serialize + ?Sized>(value: &T, case: JsonCase, omit_null: bool) -> Result<Value, serde_json::Error> {
+    let mut value = serde_json::to_value(value)?;
+    if omit_null {
+        value = omit_nulls(value);
+    }
+    Ok(match case {
+        JsonCase::Original => value,
+        JsonCase::Snake => rename_keys(value, &snake),
+        JsonCase::Camel => rename_keys(value, &camel),
+    })
+}
+
+pub fn to_json<T: Serialize + ?Sized>(
+    value: &T,
+    case: JsonCase,
+    omit_null: bool,
+    pretty: bool,
+) -> Result<String, serde_json::Error> {
+    let value = match (case, omit_null) {
+        (JsonCase::Original, false) if pretty => return serde_json::to_string_pretty(value),
+        (JsonCase::Original, false) => return serde_json::to_string(value),
+        _ => to_value(value, case, omit_null)?,
+    };
+    if pretty {
+        serde_json::to_string_pretty(&value)
+    } else {
+        serde_json::to_string(&value)
+    }
+}
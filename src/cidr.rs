@@ -0,0 +1,105 @@
+//! Address prefixes for `--expect-source`.
+
+use std::net::IpAddr;
+use std::str::FromStr;
+
+/// An address prefix such as `192.0.2.0/24`. A bare address is a /32 (or
+/// /128 for IPv6).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Cidr {
+    network: IpAddr,
+    prefix: u8,
+}
+
+fn mask(bits: u32, prefix: u8) -> u128 {
+    match prefix {
+        0 => 0,
+        p => (u128::MAX << (bits - p as u32)) & (u128::MAX >> (128 - bits)),
+    }
+}
+
+fn to_bits(ip: IpAddr) -> (u128, u32) {
+    match ip {
+        IpAddr::V4(ip) => (u32::from(ip) as u128, 32),
+        IpAddr::V6(ip) => (u128::from(ip), 128),
+    }
+}
+
+impl Cidr {
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        let ((network, bits), (ip, ip_bits)) = (to_bits(self.network), to_bits(ip));
+        bits == ip_bits && (network ^ ip) & mask(bits, self.prefix) == 0
+    }
+}
+
+impl FromStr for Cidr {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, String> {
+        let (address, prefix) = match s.split_once('/') {
+            Some((address, prefix)) => (address, Some(prefix)),
+            None => (s, None),
+        };
+        let network: IpAddr = address.parse().map_err(|_| format!("invalid address '{}'", address))?;
+        let bits = to_bits(network).1 as u8;
+        let prefix = match prefix {
+            Some(prefix) => prefix
+                .parse::<u8>()
+                .ok()
+                .filter(|p| *p <= bits)
+                .ok_or_else(|| format!("invalid prefix length '{}'", prefix))?,
+            None => bits,
+        };
+        Ok(Cidr { network, prefix })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cidr(s: &str) -> Cidr {
+        s.parse().unwrap_or_else(|e| panic!("{}: {}", s, e))
+    }
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn zero_prefix_matches_its_whole_family() {
+        assert!(cidr("0.0.0.0/0").contains(ip("203.0.113.9")));
+        assert!(cidr("10.0.0.0/0").contains(ip("255.255.255.255")));
+        assert!(!cidr("0.0.0.0/0").contains(ip("::1")));
+        assert!(cidr("::/0").contains(ip("2001:db8::1")));
+        assert!(!cidr("::/0").contains(ip("127.0.0.1")));
+    }
+
+    #[test]
+    fn full_prefix_matches_one_address() {
+        assert!(cidr("192.0.2.1/32").contains(ip("192.0.2.1")));
+        assert!(!cidr("192.0.2.1/32").contains(ip("192.0.2.0")));
+        assert_eq!(cidr("192.0.2.1"), cidr("192.0.2.1/32"));
+        assert!(cidr("2001:db8::1/128").contains(ip("2001:db8::1")));
+        assert!(!cidr("2001:db8::1/128").contains(ip("2001:db8::2")));
+        assert_eq!(cidr("2001:db8::1"), cidr("2001:db8::1/128"));
+    }
+
+    #[test]
+    fn partial_prefixes_mask_the_host_bits() {
+        assert!(cidr("192.0.2.0/24").contains(ip("192.0.2.255")));
+        assert!(!cidr("192.0.2.0/24").contains(ip("192.0.3.0")));
+        assert!(cidr("10.0.0.0/9").contains(ip("10.127.255.255")));
+        assert!(!cidr("10.0.0.0/9").contains(ip("10.128.0.0")));
+        assert!(cidr("2001:db8::/32").contains(ip("2001:db8:ffff::1")));
+        assert!(!cidr("2001:db8::/32").contains(ip("2001:db9::")));
+    }
+
+    #[test]
+    fn invalid_prefixes_are_rejected() {
+        assert!("192.0.2.0/33".parse::<Cidr>().is_err());
+        assert!("::/129".parse::<Cidr>().is_err());
+        assert!("192.0.2.0/x".parse::<Cidr>().is_err());
+        assert!("192.0.2/24".parse::<Cidr>().is_err());
+    }
+}
@@ -0,0 +1,53 @@
+//! Targets listed by an HTTP endpoint (`--targets-from`).
+//!
+//! A GET of the URL must answer with a JSON array of targets. Each entry is
+//! a target as it would be given on the command line, or an object with
+//! such a `target` field, so the entries of a service registry can be
+//! served as they are; other fields are ignored:
+//!
+//! ```json
+//! ["10.0.0.5", "db.internal:5432", {"target": "cache.internal", "zone": "b"}]
+//! ```
+
+use std::time::Duration;
+
+use serde_json::Value;
+
+use crate::http::Endpoint;
+
+/// Limit on connecting and reading the whole list.
+const FETCH_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Fetch the list at `url`, without duplicates, in the order listed.
+pub async fn fetch(url: &str) -> Result<Vec<String>, String> {
+    let failed = |e: String| format!("Failed to fetch --targets-from {}: {}", url, e);
+    let endpoint = Endpoint::parse("--targets-from", url)?;
+    let body = match tokio::time::timeout(FETCH_TIMEOUT, endpoint.get()).await {
+        Ok(body) => body.map_err(failed)?,
+        Err(_) => return Err(failed("timed out".to_string())),
+    };
+    parse(&body).map_err(failed)
+}
+
+fn parse(body: &str) -> Result<Vec<String>, String> {
+    let entries: Vec<Value> = serde_json::from_str(body).map_err(|e| format!("not a JSON array of targets: {}", e))?;
+    let mut targets: Vec<String> = Vec::with_capacity(entries.len());
+    for entry in &entries {
+        let target = match entry {
+            Value::String(target) => target,
+            Value::Object(fields) => match fields.get("target") {
+                Some(Value::String(target)) => target,
+                _ => return Err(format!("entry {} has no \"target\" string", entry)),
+            },
+            _ => return Err(format!("entry {} is neither a target nor an object", entry)),
+        };
+        let target = target.trim();
+        if target.is_empty() {
+            return Err("empty target in the list".to_string());
+        }
+        if !targets.iter().any(|known| known == target) {
+            targets.push(target.to_string());
+        }
+    }
+    Ok(targets)
+}
@@ -0,0 +1,174 @@
+//! Resolution through one DNS server (`--dns-server`).
+//!
+//! Split-horizon setups answer differently depending on who asks, so this
+//! sends plain recursive queries for the A and AAAA records straight to the
+//! given server over UDP instead of going through the system resolver and
+//! whatever it is configured to forward to.
+
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::time::Duration;
+
+use tokio::net::UdpSocket;
+
+const TYPE_A: u16 = 1;
+const TYPE_AAAA: u16 = 28;
+const CLASS_IN: u16 = 1;
+/// Recursion desired, the only flag a stub resolver sets.
+const FLAG_RD: u16 = 0x0100;
+const FLAG_QR: u16 = 0x8000;
+const RCODE_NXDOMAIN: u16 = 3;
+/// Largest reply accepted without EDNS; larger answers arrive truncated,
+/// and the records that fit are used.
+const MAX_MESSAGE: usize = 512;
+
+/// `IP` or `IP:PORT`, with IPv6 addresses bracketed when a port is given.
+/// The port defaults to 53.
+pub fn parse_server(value: &str) -> Result<SocketAddr, String> {
+    if let Ok(ip) = value.parse::<IpAddr>() {
+        return Ok(SocketAddr::new(ip, 53));
+    }
+    value
+        .parse()
+        .map_err(|_| format!("invalid DNS server '{}' (use IP or IP:PORT)", value))
+}
+
+fn encode_query(id: u16, host: &str, qtype: u16) -> Result<Vec<u8>, String> {
+    let mut query = Vec::with_capacity(18 + host.len());
+    query.extend(id.to_be_bytes());
+    query.extend(FLAG_RD.to_be_bytes());
+    // One question, no answer, authority or additional records.
+    query.extend([0, 1, 0, 0, 0, 0, 0, 0]);
+    for label in host.strip_suffix('.').unwrap_or(host).split('.') {
+        if label.is_empty() || label.len() > 63 {
+            return Err(format!("invalid hostname '{}'", host));
+        }
+        query.push(label.len() as u8);
+        query.extend(label.as_bytes());
+    }
+    query.push(0);
+    query.extend(qtype.to_be_bytes());
+    query.extend(CLASS_IN.to_be_bytes());
+    Ok(query)
+}
+
+fn read_u16(message: &[u8], at: usize) -> Option<u16> {
+    message.get(at..at + 2).map(|bytes| u16::from_be_bytes([bytes[0], bytes[1]]))
+}
+
+/// Offset just past the name starting at `at`. A compression pointer ends
+/// the name in place, so pointers are never followed.
+fn skip_name(message: &[u8], mut at: usize) -> Option<usize> {
+    loop {
+        match *message.get(at)? {
+            0 => return Some(at + 1),
+            len if len & 0xc0 == 0xc0 => return Some(at + 2),
+            len => at += 1 + len as usize,
+        }
+    }
+}
+
+/// The addresses in the answer section of a reply to query `id`. CNAMEs are
+/// skipped: a recursive server includes the records they lead to.
+fn parse_answers(message: &[u8], id: u16) -> Result<Vec<IpAddr>, String> {
+    let malformed = || "malformed DNS reply".to_string();
+    let flags = read_u16(message, 2).ok_or_else(malformed)?;
+    if read_u16(message, 0) != Some(id) || flags & FLAG_QR == 0 {
+        return Err(malformed());
+    }
+    match flags & 0x000f {
+        0 => {}
+        RCODE_NXDOMAIN => return Err("no such host".to_string()),
+        rcode => return Err(format!("DNS server returned rcode {}", rcode)),
+    }
+    let questions = read_u16(message, 4).ok_or_else(malformed)?;
+    let answers = read_u16(message, 6).ok_or_else(malformed)?;
+
+    let mut at = 12;
+    for _ in 0..questions {
+        at = skip_name(message, at).ok_or_else(malformed)? + 4;
+    }
+    let mut addrs = Vec::new();
+    for _ in 0..answers {
+        at = skip_name(message, at).ok_or_else(malformed)?;
+        let (Some(rtype), Some(class), Some(len)) =
+            (read_u16(message, at), read_u16(message, at + 2), read_u16(message, at + 8))
+        else {
+            break;
+        };
+        let Some(data) = message.get(at + 10..at + 10 + len as usize) else {
+            break;
+        };
+        match (rtype, class, data.len()) {
+            (TYPE_A, CLASS_IN, 4) => addrs.push(IpAddr::V4(Ipv4Addr::new(data[0], data[1], data[2], data[3]))),
+            (TYPE_AAAA, CLASS_IN, 16) => {
+                let octets: [u8; 16] = data.try_into().map_err(|_| malformed())?;
+                addrs.push(IpAddr::V6(Ipv6Addr::from(octets)));
+            }
+            _ => {}
+        }
+        at += 10 + len as usize;
+    }
+    Ok(addrs)
+}
+
+/// Ask `server` for one record type, giving up after `timeout`. Replies
+/// with another ID are stale or spoofed and ignored. With `vrf` the query
+/// is routed through that VRF like the probes.
+async fn query(host: &str, server: SocketAddr, qtype: u16, vrf: Option<&str>, timeout: Duration) -> Result<Vec<IpAddr>, String> {
+    let id = u16::from_be_bytes(*uuid::Uuid::new_v4().as_bytes().first_chunk().unwrap_or(&[0; 2]));
+    let request = encode_query(id, host, qtype)?;
+    let local = if server.is_ipv4() { "0.0.0.0:0" } else { "[::]:0" };
+    let socket = UdpSocket::bind(local)
+        .await
+        .map_err(|e| format!("Failed to open DNS socket: {}", e))?;
+    #[cfg(target_os = "linux")]
+    if let Some(vrf) = vrf {
+        socket2::SockRef::from(&socket)
+            .bind_device(Some(vrf.as_bytes()))
+            .map_err(|e| format!("Failed to bind DNS socket to --vrf {}: {}", vrf, e))?;
+    }
+    #[cfg(not(target_os = "linux"))]
+    let _ = vrf;
+    // Connecting filters out datagrams from anyone but the server.
+    socket
+        .connect(server)
+        .await
+        .map_err(|e| format!("Failed to reach DNS server {}: {}", server, e))?;
+    socket
+        .send(&request)
+        .await
+        .map_err(|e| format!("Failed to query DNS server {}: {}", server, e))?;
+
+    let exchange = async {
+        let mut buf = [0u8; MAX_MESSAGE];
+        loop {
+            let len = socket
+                .recv(&mut buf)
+                .await
+                .map_err(|e| format!("DNS server {} failed: {}", server, e))?;
+            if read_u16(&buf[..len], 0) == Some(id) {
+                return parse_answers(&buf[..len], id);
+            }
+        }
+    };
+    tokio::time::timeout(timeout, exchange)
+        .await
+        .unwrap_or_else(|_| Err(format!("DNS server {} did not answer", server)))
+}
+
+/// IPv4 addresses first, then IPv6, like the system resolver's usual order.
+/// An error only when neither query got an answer.
+pub async fn resolve(host: &str, server: SocketAddr, vrf: Option<&str>, timeout: Duration) -> Result<Vec<IpAddr>, String> {
+    let (v4, v6) = tokio::join!(
+        query(host, server, TYPE_A, vrf, timeout),
+        query(host, server, TYPE_AAAA, vrf, timeout)
+    );
+    let addrs: Vec<IpAddr> = match (v4, v6) {
+        (Err(e), Err(_)) => return Err(e),
+        (v4, v6) => v4.into_iter().chain(v6).flatten().collect(),
+    };
+    if addrs.is_empty() {
+        return Err(format!("DNS server {} returned no addresses", server));
+    }
+    Ok(addrs)
+}
@@ -0,0 +1,97 @@
+//! Per-packet events appended to a file (`--event-log`), with rotation.
+//!
+//! The events are the NDJSON `--event-socket` streams, whatever `RUST_LOG`
+//! says, so a file can be fed to `--replay`. With `--event-log-max-size` or
+//! `--event-log-rotate-every` the file is rotated like logrotate does it:
+//! PATH becomes PATH.1, PATH.1 becomes PATH.2 and so on, the oldest beyond
+//! `--event-log-keep` is deleted, and a fresh PATH is opened. Rotation only
+//! happens between two events, under the same lock as the writes, so no
+//! event is split or lost at the boundary.
+
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+struct Rotating {
+    path: String,
+    max_size: Option<u64>,
+    every: Option<Duration>,
+    keep: u32,
+    file: File,
+    size: u64,
+    opened: Instant,
+}
+
+impl Rotating {
+    fn due(&self, next: usize) -> bool {
+        let full = self.max_size.is_some_and(|max_size| self.size > 0 && self.size + next as u64 > max_size);
+        full || self.every.is_some_and(|every| self.opened.elapsed() >= every)
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        self.file.flush()?;
+        let _ = fs::remove_file(format!("{}.{}", self.path, self.keep));
+        for n in (1..self.keep).rev() {
+            let from = format!("{}.{}", self.path, n);
+            if fs::metadata(&from).is_ok() {
+                fs::rename(&from, format!("{}.{}", self.path, n + 1))?;
+            }
+        }
+        // The rename is atomic: readers see the old file or the new one.
+        fs::rename(&self.path, format!("{}.1", self.path))?;
+        self.file = OpenOptions::new().create(true).append(true).open(&self.path)?;
+        self.size = 0;
+        self.opened = Instant::now();
+        Ok(())
+    }
+}
+
+static LOG: Mutex<Option<Rotating>> = Mutex::new(None);
+
+/// Open `path` for appending; events written before this are not kept.
+pub fn open(path: &str, max_size: Option<u64>, every: Option<Duration>, keep: u32) -> io::Result<()> {
+    let file = OpenOptions::new().create(true).append(true).open(path)?;
+    let size = file.metadata()?.len();
+    *LOG.lock().unwrap() = Some(Rotating {
+        path: path.to_string(),
+        max_size,
+        every,
+        keep,
+        file,
+        size,
+        opened: Instant::now(),
+    });
+    Ok(())
+}
+
+/// Log writer that appends each event to the file, rotating it first when
+/// it is due.
+pub struct EventLog;
+
+impl Write for EventLog {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut log = LOG.lock().unwrap();
+        let Some(log) = log.as_mut() else {
+            return Ok(buf.len());
+        };
+        if log.due(buf.len()) {
+            if let Err(e) = log.rotate() {
+                // Keep writing to the current file rather than losing events.
+                eprintln!("Failed to rotate --event-log {}: {}", log.path, e);
+                log.size = 0;
+                log.opened = Instant::now();
+            }
+        }
+        log.file.write_all(buf)?;
+        log.size += buf.len() as u64;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match LOG.lock().unwrap().as_mut() {
+            Some(log) => log.file.flush(),
+            None => Ok(()),
+        }
+    }
+}
@@ -0,0 +1,58 @@
+//! Live packet events for a local consumer (`--event-socket`).
+//!
+//! peckr listens on a Unix domain socket and writes every per-packet event
+//! to each connected client as NDJSON, in the format of `--log-format json`,
+//! so a sidecar on the same host can follow a run without tailing a file.
+//! The events go out whatever `RUST_LOG` says, and a saved stream can be
+//! re-summarized with `--replay`. A client that disconnects or stops reading
+//! is dropped; the probe never waits for one.
+
+use std::io;
+
+#[cfg(unix)]
+use std::os::unix::net::{UnixListener, UnixStream};
+
+#[cfg(unix)]
+static CLIENTS: std::sync::Mutex<Vec<UnixStream>> = std::sync::Mutex::new(Vec::new());
+
+/// Bind `path` and accept clients in the background. A socket left behind
+/// by an earlier run is replaced; any other file at `path` is an error.
+#[cfg(unix)]
+pub fn listen(path: &str) -> io::Result<()> {
+    use std::os::unix::fs::FileTypeExt;
+
+    if std::fs::symlink_metadata(path).is_ok_and(|meta| meta.file_type().is_socket()) {
+        std::fs::remove_file(path)?;
+    }
+    let listener = UnixListener::bind(path)?;
+    std::thread::spawn(move || {
+        for client in listener.incoming().flatten() {
+            // A full socket buffer fails the write instead of blocking.
+            if client.set_nonblocking(true).is_ok() {
+                CLIENTS.lock().unwrap().push(client);
+            }
+        }
+    });
+    Ok(())
+}
+
+#[cfg(not(unix))]
+pub fn listen(_path: &str) -> io::Result<()> {
+    Err(io::Error::new(io::ErrorKind::Unsupported, "--event-socket is only supported on Unix"))
+}
+
+/// Log writer that copies each event to every client, dropping the ones
+/// the write fails for.
+pub struct Clients;
+
+impl io::Write for Clients {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        #[cfg(unix)]
+        CLIENTS.lock().unwrap().retain_mut(|client| client.write_all(buf).is_ok());
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
@@ -0,0 +1,336 @@
+//! A tiny boolean expression language for `--success-expr`.
+//!
+//! ```text
+//! expr    := and ("||" and)*
+//! and     := unary ("&&" unary)*
+//! unary   := "!" unary | "(" expr ")" | compare
+//! compare := operand ("<" | "<=" | ">" | ">=" | "==" | "!=") operand
+//! operand := number | metric
+//! ```
+//!
+//! Metrics are resolved when the expression is parsed, so a typo is reported
+//! at startup instead of silently evaluating to false at the end of a run.
+
+use std::fmt;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Metric {
+    /// Packet loss percentage.
+    Loss,
+    /// Average RTT in milliseconds.
+    Avg,
+    P50,
+    P95,
+    P99,
+    Sent,
+    Received,
+}
+
+impl Metric {
+    pub const NAMES: [(&'static str, Metric); 8] = [
+        ("loss", Metric::Loss),
+        ("avg", Metric::Avg),
+        ("latency", Metric::Avg),
+        ("p50", Metric::P50),
+        ("p95", Metric::P95),
+        ("p99", Metric::P99),
+        ("sent", Metric::Sent),
+        ("received", Metric::Received),
+    ];
+
+    fn from_name(name: &str) -> Option<Metric> {
+        Self::NAMES.iter().find(|(n, _)| *n == name).map(|(_, m)| *m)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Operand {
+    Number(f64),
+    Metric(Metric),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum CompareOp {
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    Eq,
+    Ne,
+}
+
+impl CompareOp {
+    fn symbol(self) -> &'static str {
+        match self {
+            CompareOp::Lt => "<",
+            CompareOp::Le => "<=",
+            CompareOp::Gt => ">",
+            CompareOp::Ge => ">=",
+            CompareOp::Eq => "==",
+            CompareOp::Ne => "!=",
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Expr {
+    Or(Box<Expr>, Box<Expr>),
+    And(Box<Expr>, Box<Expr>),
+    Not(Box<Expr>),
+    Compare(Operand, CompareOp, Operand),
+}
+
+impl Expr {
+    pub fn eval(&self, value: &dyn Fn(Metric) -> f64) -> bool {
+        let operand = |o: &Operand| match o {
+            Operand::Number(n) => *n,
+            Operand::Metric(m) => value(*m),
+        };
+        match self {
+            Expr::Or(a, b) => a.eval(value) || b.eval(value),
+            Expr::And(a, b) => a.eval(value) && b.eval(value),
+            Expr::Not(e) => !e.eval(value),
+            Expr::Compare(lhs, op, rhs) => {
+                let (lhs, rhs) = (operand(lhs), operand(rhs));
+                match op {
+                    CompareOp::Lt => lhs < rhs,
+                    CompareOp::Le => lhs <= rhs,
+                    CompareOp::Gt => lhs > rhs,
+                    CompareOp::Ge => lhs >= rhs,
+                    CompareOp::Eq => lhs == rhs,
+                    CompareOp::Ne => lhs != rhs,
+                }
+            }
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Token {
+    Number(f64),
+    Ident(String),
+    Compare(CompareOp),
+    And,
+    Or,
+    Not,
+    Open,
+    Close,
+}
+
+impl fmt::Display for Token {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Token::Number(n) => write!(f, "{}", n),
+            Token::Ident(name) => write!(f, "{}", name),
+            Token::Compare(op) => write!(f, "{}", op.symbol()),
+            Token::And => write!(f, "&&"),
+            Token::Or => write!(f, "||"),
+            Token::Not => write!(f, "!"),
+            Token::Open => write!(f, "("),
+            Token::Close => write!(f, ")"),
+        }
+    }
+}
+
+fn tokenize(input: &str) -> Result<Vec<Token>, String> {
+    let chars: Vec<char> = input.chars().collect();
+    let mut tokens = Vec::new();
+    let mut i = 0;
+    while i < chars.len() {
+        let c = chars[i];
+        let next = chars.get(i + 1).copied();
+        let (token, len) = match (c, next) {
+            (c, _) if c.is_whitespace() => {
+                i += 1;
+                continue;
+            }
+            ('&', Some('&')) => (Token::And, 2),
+            ('|', Some('|')) => (Token::Or, 2),
+            ('<', Some('=')) => (Token::Compare(CompareOp::Le), 2),
+            ('>', Some('=')) => (Token::Compare(CompareOp::Ge), 2),
+            ('=', Some('=')) => (Token::Compare(CompareOp::Eq), 2),
+            ('!', Some('=')) => (Token::Compare(CompareOp::Ne), 2),
+            ('<', _) => (Token::Compare(CompareOp::Lt), 1),
+            ('>', _) => (Token::Compare(CompareOp::Gt), 1),
+            ('!', _) => (Token::Not, 1),
+            ('(', _) => (Token::Open, 1),
+            (')', _) => (Token::Close, 1),
+            (c, _) if c.is_ascii_digit() || c == '.' => {
+                let len = chars[i..].iter().take_while(|c| c.is_ascii_digit() || **c == '.').count();
+                let text: String = chars[i..i + len].iter().collect();
+                let number = text.parse().map_err(|_| format!("invalid number '{}'", text))?;
+                (Token::Number(number), len)
+            }
+            (c, _) if c.is_ascii_alphabetic() || c == '_' => {
+                let len = chars[i..].iter().take_while(|c| c.is_ascii_alphanumeric() || **c == '_').count();
+                (Token::Ident(chars[i..i + len].iter().collect()), len)
+            }
+            (c, _) => return Err(format!("unexpected character '{}'", c)),
+        };
+        tokens.push(token);
+        i += len;
+    }
+    Ok(tokens)
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    pos: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos)
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.pos).cloned();
+        self.pos += 1;
+        token
+    }
+
+    fn or(&mut self) -> Result<Expr, String> {
+        let mut expr = self.and()?;
+        while self.peek() == Some(&Token::Or) {
+            self.pos += 1;
+            expr = Expr::Or(Box::new(expr), Box::new(self.and()?));
+        }
+        Ok(expr)
+    }
+
+    fn and(&mut self) -> Result<Expr, String> {
+        let mut expr = self.unary()?;
+        while self.peek() == Some(&Token::And) {
+            self.pos += 1;
+            expr = Expr::And(Box::new(expr), Box::new(self.unary()?));
+        }
+        Ok(expr)
+    }
+
+    fn unary(&mut self) -> Result<Expr, String> {
+        match self.peek() {
+            Some(Token::Not) => {
+                self.pos += 1;
+                Ok(Expr::Not(Box::new(self.unary()?)))
+            }
+            Some(Token::Open) => {
+                self.pos += 1;
+                let expr = self.or()?;
+                match self.next() {
+                    Some(Token::Close) => Ok(expr),
+                    _ => Err("missing ')'".to_string()),
+                }
+            }
+            _ => self.compare(),
+        }
+    }
+
+    fn compare(&mut self) -> Result<Expr, String> {
+        let lhs = self.operand()?;
+        let op = match self.next() {
+            Some(Token::Compare(op)) => op,
+            Some(token) => return Err(format!("expected a comparison, found '{}'", token)),
+            None => return Err("expected a comparison".to_string()),
+        };
+        let rhs = self.operand()?;
+        Ok(Expr::Compare(lhs, op, rhs))
+    }
+
+    fn operand(&mut self) -> Result<Operand, String> {
+        match self.next() {
+            Some(Token::Number(n)) => Ok(Operand::Number(n)),
+            Some(Token::Ident(name)) => Metric::from_name(&name).map(Operand::Metric).ok_or_else(|| {
+                let known: Vec<&str> = Metric::NAMES.iter().map(|(n, _)| *n).collect();
+                format!("unknown metric '{}' (known: {})", name, known.join(", "))
+            }),
+            Some(token) => Err(format!("expected a metric or number, found '{}'", token)),
+            None => Err("unexpected end of expression".to_string()),
+        }
+    }
+}
+
+pub fn parse(input: &str) -> Result<Expr, String> {
+    let mut parser = Parser {
+        tokens: tokenize(input)?,
+        pos: 0,
+    };
+    let expr = parser.or()?;
+    match parser.peek() {
+        None => Ok(expr),
+        Some(token) => Err(format!("unexpected '{}'", token)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn metrics(metric: Metric) -> f64 {
+        match metric {
+            Metric::Loss => 2.0,
+            Metric::Avg => 40.0,
+            Metric::Sent => 100.0,
+            Metric::Received => 98.0,
+            Metric::P50 | Metric::P95 | Metric::P99 => 50.0,
+        }
+    }
+
+    fn eval(input: &str) -> bool {
+        parse(input).unwrap_or_else(|e| panic!("{}: {}", input, e)).eval(&metrics)
+    }
+
+    #[test]
+    fn and_binds_tighter_than_or() {
+        let compare = |metric, op, n| Expr::Compare(Operand::Metric(metric), op, Operand::Number(n));
+        assert_eq!(
+            parse("loss > 50 || loss < 5 && sent > 1000").unwrap(),
+            Expr::Or(
+                Box::new(compare(Metric::Loss, CompareOp::Gt, 50.0)),
+                Box::new(Expr::And(
+                    Box::new(compare(Metric::Loss, CompareOp::Lt, 5.0)),
+                    Box::new(compare(Metric::Sent, CompareOp::Gt, 1000.0))
+                ))
+            )
+        );
+        assert!(eval("loss < 5 || avg > 100 && sent > 1000"));
+        assert!(!eval("(loss < 5 || avg > 100) && sent > 1000"));
+    }
+
+    #[test]
+    fn not_and_parentheses() {
+        assert!(eval("!(loss > 5)"));
+        assert!(!eval("!loss < 5"));
+        assert!(eval("!!(avg < 50)"));
+        assert!(eval("((loss < 5)) && (p95 <= 50)"));
+    }
+
+    #[test]
+    fn comparison_operators() {
+        assert!(eval("avg < 41") && !eval("avg < 40"));
+        assert!(eval("avg <= 40") && !eval("avg <= 39.5"));
+        assert!(eval("avg > 39") && !eval("avg > 40"));
+        assert!(eval("avg >= 40") && !eval("avg >= 40.5"));
+        assert!(eval("received == 98") && !eval("received == 100"));
+        assert!(eval("received != sent") && !eval("sent != 100"));
+        assert!(eval("latency == avg"));
+    }
+
+    #[test]
+    fn unknown_metrics_are_rejected_at_parse_time() {
+        let e = parse("lose < 5").unwrap_err();
+        assert!(e.starts_with("unknown metric 'lose'"), "{}", e);
+        assert!(parse("loss < 5 && jitter < 10").is_err());
+    }
+
+    #[test]
+    fn malformed_expressions_are_rejected() {
+        assert_eq!(parse("loss < 5 )").unwrap_err(), "unexpected ')'");
+        assert_eq!(parse("loss < 5 avg").unwrap_err(), "unexpected 'avg'");
+        assert_eq!(parse("(loss < 5").unwrap_err(), "missing ')'");
+        assert_eq!(parse("loss < 5 &").unwrap_err(), "unexpected character '&'");
+        assert_eq!(parse("loss 5").unwrap_err(), "expected a comparison, found '5'");
+        assert_eq!(parse("loss <").unwrap_err(), "unexpected end of expression");
+        assert_eq!(parse("1.2.3 < loss").unwrap_err(), "invalid number '1.2.3'");
+        assert!(parse("").is_err());
+    }
+}
@@ -0,0 +1,53 @@
+//! fping-compatible summary lines (`--format fping`).
+//!
+//! One line per target in the format `fping -c` prints at the end, so
+//! tooling that parses fping can read peckr unchanged:
+//!
+//! ```text
+//! example.com : xmt/rcv/%loss = 5/5/0%, min/avg/max = 11.2/11.9/13.4
+//! ```
+
+use std::time::Duration;
+
+use crate::{PingResult, PingStats};
+
+/// A time in milliseconds with fping's precision: three decimals below
+/// 1 ms, two below 10 ms, one below 100 ms, none above.
+fn ms(rtt: Duration) -> String {
+    let t = rtt.as_secs_f64() * 1000.0;
+    if t < 1.0 {
+        format!("{:.3}", t)
+    } else if t < 10.0 {
+        format!("{:.2}", t)
+    } else if t < 100.0 {
+        format!("{:.1}", t)
+    } else {
+        format!("{:.0}", t)
+    }
+}
+
+pub fn render(reports: &[(PingResult, PingStats)]) -> String {
+    // fping pads the names to the longest one so the columns line up.
+    let width = reports.iter().map(|(result, _)| result.servername.len()).max().unwrap_or(0);
+    let mut out = String::new();
+    for (result, stats) in reports {
+        // Whole percent, truncated, as fping computes it.
+        let loss = match stats.sent {
+            0 => 0,
+            sent => (sent - stats.received) * 100 / sent,
+        };
+        out += &format!(
+            "{:<width$} : xmt/rcv/%loss = {}/{}/{}%",
+            result.servername,
+            stats.sent,
+            stats.received,
+            loss,
+            width = width
+        );
+        if let Some((min, max)) = stats.rtt_range {
+            out += &format!(", min/avg/max = {}/{}/{}", ms(min), ms(stats.avg_rtt()), ms(max));
+        }
+        out.push('\n');
+    }
+    out
+}
@@ -0,0 +1,45 @@
+//! Flat single-stat results for Grafana (`--format grafana`).
+//!
+//! Grafana's JSON and Infinity datasources bind panel fields by name and do
+//! not reach into nested objects easily, so each target is reduced to four
+//! top-level fields:
+//!
+//! ```json
+//! {"target": "example.com", "value": 11.93, "status": "ok", "time": 1760450348629}
+//! ```
+
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde::Serialize;
+
+use crate::casing::{self, JsonCase};
+use crate::{PingResult, PingStats, Status};
+
+#[derive(Serialize)]
+struct Stat<'a> {
+    target: &'a str,
+    /// Average RTT in milliseconds, to the microsecond.
+    value: f64,
+    status: Status,
+    /// Unix time in milliseconds, the unit Grafana assumes for timestamps.
+    time: u64,
+}
+
+/// One object per target, and an array of them when there are several,
+/// like the JSON summary.
+pub fn render(reports: &[(PingResult, PingStats)], case: JsonCase, pretty: bool) -> Result<String, serde_json::Error> {
+    let time = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_millis() as u64;
+    let stats: Vec<Stat> = reports
+        .iter()
+        .map(|(result, stats)| Stat {
+            target: &result.servername,
+            value: stats.avg_rtt().as_micros() as f64 / 1000.0,
+            status: result.status,
+            time,
+        })
+        .collect();
+    match stats.as_slice() {
+        [stat] => casing::to_json(stat, case, false, pretty),
+        stats => casing::to_json(stats, case, false, pretty),
+    }
+}
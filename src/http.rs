@@ -0,0 +1,116 @@
+//! A minimal HTTP/1 client for the plain `http://` URLs of `--webhook` and
+//! `--targets-from`.
+
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+use crate::target;
+
+/// Largest response body read; a target list is far smaller.
+const MAX_BODY: usize = 1 << 20;
+
+pub struct Endpoint {
+    /// `host[:port]` as given, for the Host header.
+    pub authority: String,
+    host: String,
+    port: u16,
+    path: String,
+}
+
+impl Endpoint {
+    /// Parse the URL given to `flag`, named in the errors.
+    pub fn parse(flag: &str, url: &str) -> Result<Self, String> {
+        let rest = match url.split_once("://") {
+            Some((scheme, rest)) if scheme.eq_ignore_ascii_case("http") => rest,
+            Some((scheme, _)) => return Err(format!("Unsupported {} scheme {}: only http:// URLs are supported", flag, scheme)),
+            None => return Err(format!("Invalid {} URL {}: expected http://host[:port]/path", flag, url)),
+        };
+        let (authority, path) = match rest.find(['/', '?']) {
+            Some(split) => (&rest[..split], &rest[split..]),
+            None => (rest, "/"),
+        };
+        let parsed = target::parse(url);
+        if parsed.host.is_empty() {
+            return Err(format!("Invalid {} URL {}: no host", flag, url));
+        }
+        Ok(Self {
+            authority: authority.rsplit_once('@').map_or(authority, |(_, host)| host).to_string(),
+            host: parsed.host.to_string(),
+            port: parsed.port.unwrap_or(80),
+            path: match path.starts_with('?') {
+                true => format!("/{}", path),
+                false => path.to_string(),
+            },
+        })
+    }
+
+    /// One POST; Ok when the endpoint answered with a 2xx status.
+    pub async fn post(&self, body: &str) -> Result<(), String> {
+        let mut stream = TcpStream::connect((self.host.as_str(), self.port))
+            .await
+            .map_err(|e| e.to_string())?;
+        let request = format!(
+            "POST {} HTTP/1.1\r\nHost: {}\r\nUser-Agent: peckr/{}\r\nContent-Type: application/json\r\n\
+             Content-Length: {}\r\nConnection: close\r\n\r\n{}",
+            self.path,
+            self.authority,
+            env!("CARGO_PKG_VERSION"),
+            body.len(),
+            body
+        );
+        stream.write_all(request.as_bytes()).await.map_err(|e| e.to_string())?;
+
+        // Only the status line matters.
+        let mut response = Vec::new();
+        let mut buf = [0u8; 512];
+        while !response.contains(&b'\n') {
+            match stream.read(&mut buf).await.map_err(|e| e.to_string())? {
+                0 => break,
+                len => response.extend_from_slice(&buf[..len]),
+            }
+        }
+        check_status(&response)
+    }
+
+    /// One GET, returning the body of a 2xx answer. The request is HTTP/1.0
+    /// so the body comes as it is, never chunked, and ends with the
+    /// connection.
+    pub async fn get(&self) -> Result<String, String> {
+        let mut stream = TcpStream::connect((self.host.as_str(), self.port))
+            .await
+            .map_err(|e| e.to_string())?;
+        let request = format!(
+            "GET {} HTTP/1.0\r\nHost: {}\r\nUser-Agent: peckr/{}\r\nAccept: application/json\r\n\r\n",
+            self.path,
+            self.authority,
+            env!("CARGO_PKG_VERSION")
+        );
+        stream.write_all(request.as_bytes()).await.map_err(|e| e.to_string())?;
+
+        let mut response = Vec::new();
+        (&mut stream)
+            .take(MAX_BODY as u64 + 8192)
+            .read_to_end(&mut response)
+            .await
+            .map_err(|e| e.to_string())?;
+        check_status(&response)?;
+        let body = match response.windows(4).position(|window| window == b"\r\n\r\n") {
+            Some(end) => &response[end + 4..],
+            None => return Err("no end to the HTTP headers".to_string()),
+        };
+        if body.len() > MAX_BODY {
+            return Err(format!("response larger than {} bytes", MAX_BODY));
+        }
+        String::from_utf8(body.to_vec()).map_err(|_| "response body is not UTF-8".to_string())
+    }
+}
+
+fn check_status(response: &[u8]) -> Result<(), String> {
+    let status_line = String::from_utf8_lossy(response);
+    let status_line = status_line.lines().next().unwrap_or_default();
+    match status_line.split_whitespace().nth(1).and_then(|code| code.parse::<u16>().ok()) {
+        Some(200..=299) => Ok(()),
+        Some(code) => Err(format!("HTTP {}", code)),
+        None => Err("no HTTP response".to_string()),
+    }
+}
@@ -0,0 +1,759 @@
+//! ICMP echo client on top of surge-ping's socket.
+//!
+//! surge-ping's `Client` only hands back a decoded header, which is not
+//! enough once we need to look at what a reply actually carried. This keeps
+//! surge-ping for socket setup (`Config`, DGRAM/RAW fallback, TTL, binding)
+//! and does the echo framing and reply matching itself. The same socket can
+//! send ICMP Timestamp requests (type 13) in place of echoes, and echoes to
+//! a broadcast or multicast address, gathering every reply.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use surge_ping::{AsyncSocket, Config};
+use tokio::sync::{mpsc, oneshot};
+use tokio::task::JoinHandle;
+use tracing::debug;
+
+use crate::pcap::Capture;
+
+const ICMP_ECHO_REPLY: u8 = 0;
+const ICMP_DEST_UNREACHABLE: u8 = 3;
+const ICMP_ECHO_REQUEST: u8 = 8;
+const ICMP_TIME_EXCEEDED: u8 = 11;
+const ICMP_TIMESTAMP_REQUEST: u8 = 13;
+const ICMP_TIMESTAMP_REPLY: u8 = 14;
+/// Destination unreachable code for "fragmentation needed and DF set".
+const ICMP_FRAG_NEEDED: u8 = 4;
+
+/// Size of the ICMP echo header preceding the payload.
+pub const ICMP_HEADER_LEN: usize = 8;
+
+/// Bytes needed at the start of the payload to carry a send timestamp.
+pub const TIMESTAMP_LEN: usize = 16;
+
+const TIMESTAMP_MAGIC: &[u8; 4] = b"PECK";
+
+/// Originate, receive and transmit times after a Timestamp message header.
+const CLOCK_FIELDS_LEN: usize = 12;
+
+/// Milliseconds in a day; ICMP timestamps count from midnight UT.
+const DAY_MS: u32 = 86_400_000;
+
+/// Set on a timestamp that is not milliseconds since midnight UT (RFC 792).
+const NON_STANDARD: u32 = 1 << 31;
+
+/// The clock fields of an ICMP Timestamp reply, in milliseconds since
+/// midnight UT.
+#[derive(Debug, Clone, Copy)]
+pub struct RemoteClock {
+    /// Our send time, echoed back.
+    pub originate: u32,
+    /// When the target received the request.
+    pub receive: u32,
+    /// When the target sent the reply.
+    pub transmit: u32,
+}
+
+impl RemoteClock {
+    /// Estimated offset of the target's clock from ours, in milliseconds,
+    /// assuming the path is symmetric: the mean of what the clock fields
+    /// say about each direction. `received` is when the reply arrived here.
+    /// None when the target does not report standard timestamps.
+    pub fn offset_ms(&self, received: SystemTime) -> Option<f64> {
+        if (self.receive | self.transmit) & NON_STANDARD != 0 {
+            return None;
+        }
+        let received = ms_since_midnight(received);
+        let outbound = day_diff(self.receive, self.originate);
+        let inbound = day_diff(self.transmit, received);
+        Some((outbound + inbound) as f64 / 2.0)
+    }
+}
+
+/// `a - b` on the daily clock, in (-12h, 12h], so a pair straddling
+/// midnight is still close.
+fn day_diff(a: u32, b: u32) -> i64 {
+    let diff = (a as i64 - b as i64).rem_euclid(DAY_MS as i64);
+    if diff > DAY_MS as i64 / 2 {
+        diff - DAY_MS as i64
+    } else {
+        diff
+    }
+}
+
+fn ms_since_midnight(at: SystemTime) -> u32 {
+    (at.duration_since(UNIX_EPOCH).unwrap_or_default().as_millis() % DAY_MS as u128) as u32
+}
+
+/// Read the clock fields from the body of a Timestamp reply.
+pub fn parse_remote_clock(payload: &[u8]) -> Option<RemoteClock> {
+    let field = |at: usize| Some(u32::from_be_bytes(payload.get(at..at + 4)?.try_into().ok()?));
+    Some(RemoteClock {
+        originate: field(0)?,
+        receive: field(4)?,
+        transmit: field(8)?,
+    })
+}
+
+/// A reply matched to one of our echo requests.
+#[derive(Debug, Clone)]
+pub struct Reply {
+    pub received_at: Instant,
+    /// Address the reply came from, normally the target itself.
+    pub source: IpAddr,
+    pub payload: Vec<u8>,
+    /// TTL of the reply's IP header. Only raw sockets see the header.
+    pub ttl: Option<u8>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Echo {
+    pub rtt: Duration,
+    /// For `--http`, the part of `rtt` the TCP handshake took.
+    pub handshake: Option<Duration>,
+    pub reply: Reply,
+}
+
+/// The answer to a TTL-limited probe: either the echo reply from the target
+/// or an ICMP error from a router along the path.
+#[derive(Debug, Clone)]
+pub struct Hop {
+    pub rtt: Duration,
+    pub source: IpAddr,
+    /// The probe got as far as it can go: the target replied, or the path
+    /// reported the destination unreachable.
+    pub reached: bool,
+}
+
+#[derive(Debug)]
+pub enum PingError {
+    Timeout,
+    Io(io::Error),
+    /// The network answered our request with an ICMP error instead of a reply.
+    Icmp { source: IpAddr, icmp_type: u8, code: u8 },
+    /// A router could not forward the request without fragmenting it.
+    /// `mtu` is the next-hop MTU it reported (RFC 1191), if any.
+    TooBig { source: IpAddr, mtu: Option<u16> },
+    /// A request with the same host/identifier/sequence is still waiting.
+    Duplicate,
+    /// The echo reply came after the timeout, within the late window.
+    Late { source: IpAddr, rtt: Duration },
+}
+
+impl fmt::Display for PingError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PingError::Timeout => write!(f, "Request timeout"),
+            PingError::Io(e) => write!(f, "io error: {}", e),
+            PingError::Icmp { source, icmp_type: ICMP_DEST_UNREACHABLE, code } => {
+                write!(f, "Destination unreachable from {} (code {})", source, code)
+            }
+            PingError::Icmp { source, icmp_type: ICMP_TIME_EXCEEDED, code } => {
+                write!(f, "Time exceeded from {} (code {})", source, code)
+            }
+            PingError::Icmp { source, icmp_type, code } => {
+                write!(f, "ICMP type {} code {} from {}", icmp_type, code, source)
+            }
+            PingError::TooBig { source, mtu: Some(mtu) } => {
+                write!(f, "Fragmentation needed from {} (next-hop MTU {})", source, mtu)
+            }
+            PingError::TooBig { source, mtu: None } => write!(f, "Fragmentation needed from {}", source),
+            PingError::Duplicate => write!(f, "Multiple identical requests"),
+            PingError::Late { source, rtt } => {
+                write!(f, "Late reply from {} after {:.2} ms", source, rtt.as_secs_f64() * 1000.0)
+            }
+        }
+    }
+}
+
+impl PingError {
+    pub fn next_hop_mtu(&self) -> Option<u16> {
+        match self {
+            PingError::TooBig { mtu, .. } => *mtu,
+            _ => None,
+        }
+    }
+}
+
+impl std::error::Error for PingError {}
+
+impl From<io::Error> for PingError {
+    fn from(e: io::Error) -> Self {
+        PingError::Io(e)
+    }
+}
+
+#[derive(PartialEq, Eq, Hash, Clone, Copy)]
+struct Token(IpAddr, Option<u16>, u16);
+
+/// What the receive task hands to a waiting request.
+struct Response {
+    received_at: Instant,
+    source: IpAddr,
+    ttl: Option<u8>,
+    kind: ResponseKind,
+}
+
+enum ResponseKind {
+    Reply(Vec<u8>),
+    /// `mtu` is the next-hop MTU field of a fragmentation-needed error.
+    Icmp { icmp_type: u8, code: u8, mtu: u16 },
+}
+
+type Waiters = Arc<Mutex<HashMap<Token, oneshot::Sender<Response>>>>;
+
+/// Requests to a broadcast or multicast address, by identifier and sequence:
+/// every echo reply that matches goes to the sender, whoever it is from.
+type Collectors = Arc<Mutex<HashMap<(Option<u16>, u16), mpsc::UnboundedSender<Response>>>>;
+
+/// Shared echo client; clone it into every task that needs to ping.
+#[derive(Clone)]
+pub struct EchoClient {
+    socket: AsyncSocket,
+    waiters: Waiters,
+    collectors: Collectors,
+    recv: Arc<JoinHandle<()>>,
+    /// Held across a send so a probe's TTL change cannot leak into another
+    /// request going out on the same socket.
+    send_lock: Arc<tokio::sync::Mutex<()>>,
+    capture: Option<Arc<Capture>>,
+    /// How long past its timeout an echo request still waits for a late
+    /// reply.
+    late_window: Duration,
+}
+
+impl Drop for EchoClient {
+    fn drop(&mut self) {
+        if Arc::strong_count(&self.recv) <= 1 {
+            self.recv.abort();
+        }
+    }
+}
+
+impl EchoClient {
+    /// With a `capture`, every request sent and every reply matched to one
+    /// is also written to it. With `any_source`, an echo reply from another
+    /// address than the one pinged is still matched to its request when
+    /// identifier and sequence leave no doubt which one it answers.
+    pub fn new(config: &Config, capture: Option<Arc<Capture>>, any_source: bool) -> io::Result<Self> {
+        let socket = AsyncSocket::new(config)?;
+        let waiters = Waiters::default();
+        let collectors = Collectors::default();
+        let recv = tokio::spawn(recv_task(
+            socket.clone(),
+            waiters.clone(),
+            collectors.clone(),
+            capture.clone(),
+            any_source,
+        ));
+        Ok(Self {
+            socket,
+            waiters,
+            collectors,
+            recv: Arc::new(recv),
+            send_lock: Arc::default(),
+            capture,
+            late_window: Duration::ZERO,
+        })
+    }
+
+    /// Keep waiting `window` past an echo request's timeout: a reply then
+    /// is [`PingError::Late`] instead of a timeout.
+    pub fn with_late_window(mut self, window: Duration) -> Self {
+        self.late_window = window;
+        self
+    }
+
+    pub fn socket(&self) -> &AsyncSocket {
+        &self.socket
+    }
+
+    /// Whether this is a raw socket rather than an unprivileged ping socket.
+    pub fn privileged(&self) -> bool {
+        self.socket.get_type() == socket2::Type::RAW
+    }
+
+    /// Linux ping sockets (unprivileged DGRAM) own the identifier field, so
+    /// replies can only be matched on sequence there.
+    fn kernel_owns_identifier(&self) -> bool {
+        cfg!(any(target_os = "linux", target_os = "android"))
+            && self.socket.get_type() == socket2::Type::DGRAM
+    }
+
+    pub async fn ping(
+        &self,
+        host: IpAddr,
+        ident: u16,
+        seq: u16,
+        payload: &[u8],
+        timeout: Duration,
+    ) -> Result<Echo, PingError> {
+        let (sent_at, response) = self
+            .exchange(ICMP_ECHO_REQUEST, host, ident, seq, payload, timeout, None, self.late_window)
+            .await?;
+        answer(sent_at, response)
+    }
+
+    /// Send an ICMP Timestamp request instead of an echo. The reply's
+    /// payload holds the clock fields; see [`parse_remote_clock`]. Needs a
+    /// raw socket: ping sockets only carry echoes.
+    pub async fn timestamp(
+        &self,
+        host: IpAddr,
+        ident: u16,
+        seq: u16,
+        timeout: Duration,
+    ) -> Result<Echo, PingError> {
+        let mut fields = [0; CLOCK_FIELDS_LEN];
+        fields[..4].copy_from_slice(&ms_since_midnight(SystemTime::now()).to_be_bytes());
+        let (sent_at, response) = self
+            .exchange(ICMP_TIMESTAMP_REQUEST, host, ident, seq, &fields, timeout, None, Duration::ZERO)
+            .await?;
+        answer(sent_at, response)
+    }
+
+    /// Send one echo request with its TTL set to `ttl`, for tracing the path
+    /// to `host`. A time-exceeded error names the router at that hop.
+    pub async fn probe(
+        &self,
+        host: IpAddr,
+        ident: u16,
+        seq: u16,
+        ttl: u8,
+        payload: &[u8],
+        timeout: Duration,
+    ) -> Result<Hop, PingError> {
+        let (sent_at, response) =
+            self.exchange(ICMP_ECHO_REQUEST, host, ident, seq, payload, timeout, Some(ttl), Duration::ZERO).await?;
+        let reached = match response.kind {
+            ResponseKind::Reply(_) => true,
+            ResponseKind::Icmp { icmp_type, .. } => icmp_type == ICMP_DEST_UNREACHABLE,
+        };
+        Ok(Hop {
+            rtt: response.received_at.saturating_duration_since(sent_at),
+            source: response.source,
+            reached,
+        })
+    }
+
+    /// Send one echo request to a broadcast or multicast `host` and gather
+    /// every reply that arrives within `timeout`, first reply first. Times
+    /// out when nobody answered.
+    pub async fn ping_all(
+        &self,
+        host: IpAddr,
+        ident: u16,
+        seq: u16,
+        payload: &[u8],
+        timeout: Duration,
+    ) -> Result<Vec<Echo>, PingError> {
+        let ident = (!self.kernel_owns_identifier()).then_some(ident);
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        {
+            let mut collectors = self.collectors.lock().unwrap();
+            if collectors.contains_key(&(ident, seq)) {
+                return Err(PingError::Duplicate);
+            }
+            collectors.insert((ident, seq), tx);
+        }
+
+        let sent_at = match self.send_request(ICMP_ECHO_REQUEST, host, ident, seq, payload, None).await {
+            Ok(sent_at) => sent_at,
+            Err(e) => {
+                self.collectors.lock().unwrap().remove(&(ident, seq));
+                return Err(e.into());
+            }
+        };
+        let mut replies = Vec::new();
+        let deadline = tokio::time::Instant::from_std(sent_at + timeout);
+        while let Ok(Some(response)) = tokio::time::timeout_at(deadline, rx.recv()).await {
+            // Errors from the path say nothing about who is listening.
+            if let Ok(echo) = answer(sent_at, response) {
+                replies.push(echo);
+            }
+        }
+        self.collectors.lock().unwrap().remove(&(ident, seq));
+        match replies.is_empty() {
+            true => Err(PingError::Timeout),
+            false => Ok(replies),
+        }
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    async fn exchange(
+        &self,
+        request: u8,
+        host: IpAddr,
+        ident: u16,
+        seq: u16,
+        payload: &[u8],
+        timeout: Duration,
+        ttl: Option<u8>,
+        late_window: Duration,
+    ) -> Result<(Instant, Response), PingError> {
+        let ident = (!self.kernel_owns_identifier()).then_some(ident);
+        let token = || Token(host, ident, seq);
+
+        let (tx, rx) = oneshot::channel();
+        {
+            let mut waiters = self.waiters.lock().unwrap();
+            if waiters.contains_key(&token()) {
+                return Err(PingError::Duplicate);
+            }
+            waiters.insert(token(), tx);
+        }
+
+        let sent_at = match self.send_request(request, host, ident, seq, payload, ttl).await {
+            Ok(sent_at) => sent_at,
+            Err(e) => {
+                self.waiters.lock().unwrap().remove(&token());
+                return Err(e.into());
+            }
+        };
+
+        let mut rx = rx;
+        let late = match tokio::time::timeout(timeout, &mut rx).await {
+            Ok(Ok(response)) => return Ok((sent_at, response)),
+            Ok(Err(_)) => return Err(PingError::Io(io::ErrorKind::BrokenPipe.into())),
+            Err(_) => tokio::time::timeout(late_window, rx).await,
+        };
+        match late {
+            Ok(Ok(response)) if matches!(response.kind, ResponseKind::Reply(_)) => Err(PingError::Late {
+                source: response.source,
+                rtt: response.received_at.saturating_duration_since(sent_at),
+            }),
+            // An error from the path after the timeout changes nothing.
+            _ => {
+                self.waiters.lock().unwrap().remove(&token());
+                Err(PingError::Timeout)
+            }
+        }
+    }
+
+    /// Put one request on the wire, returning when it left.
+    async fn send_request(
+        &self,
+        request: u8,
+        host: IpAddr,
+        ident: Option<u16>,
+        seq: u16,
+        payload: &[u8],
+        ttl: Option<u8>,
+    ) -> io::Result<Instant> {
+        let mut packet = encode_request(request, ident, seq, payload);
+        let _send = self.send_lock.lock().await;
+        let dest = SocketAddr::new(host, 0);
+        let previous = ttl.map(|ttl| self.swap_ttl(ttl as u32)).transpose()?;
+        if let Some(capture) = &self.capture {
+            let ttl = ttl.map(u32::from).or_else(|| self.socket_ttl().ok()).unwrap_or(0);
+            capture.sent(host, ttl as u8, &packet);
+        }
+        let sent = self.socket.send_to(&mut packet, &dest).await;
+        if let Some(previous) = previous {
+            let _ = self.swap_ttl(previous);
+        }
+        sent.map(|_| Instant::now())
+    }
+
+    #[cfg(unix)]
+    fn with_sock_ref<T>(&self, f: impl FnOnce(socket2::SockRef<'_>) -> io::Result<T>) -> io::Result<T> {
+        use std::os::fd::BorrowedFd;
+
+        // SAFETY: the descriptor is owned by `self.socket`, which outlives this borrow.
+        let fd = unsafe { BorrowedFd::borrow_raw(self.socket.get_native_sock()) };
+        f(socket2::SockRef::from(&fd))
+    }
+
+    #[cfg(not(unix))]
+    fn with_sock_ref<T>(&self, _f: impl FnOnce(socket2::SockRef<'_>) -> io::Result<T>) -> io::Result<T> {
+        Err(io::Error::new(io::ErrorKind::Unsupported, "socket options are only supported on Unix"))
+    }
+
+    pub fn socket_ttl(&self) -> io::Result<u32> {
+        self.with_sock_ref(|socket| socket.ttl())
+    }
+
+    pub fn socket_tos(&self) -> io::Result<u32> {
+        self.with_sock_ref(|socket| socket.tos())
+    }
+
+    /// Set DF on every request and never fragment locally
+    /// (`IP_PMTUDISC_DO`): a request larger than the route's MTU then fails
+    /// to send instead of leaving in fragments.
+    #[cfg(target_os = "linux")]
+    pub fn set_dont_fragment(&self) -> io::Result<()> {
+        use std::os::fd::AsRawFd;
+
+        self.with_sock_ref(|socket| {
+            let value: libc::c_int = libc::IP_PMTUDISC_DO;
+            // SAFETY: `value` outlives the call and the length matches its type.
+            let ret = unsafe {
+                libc::setsockopt(
+                    socket.as_raw_fd(),
+                    libc::IPPROTO_IP,
+                    libc::IP_MTU_DISCOVER,
+                    &value as *const libc::c_int as *const libc::c_void,
+                    std::mem::size_of::<libc::c_int>() as libc::socklen_t,
+                )
+            };
+            match ret {
+                0 => Ok(()),
+                _ => Err(io::Error::last_os_error()),
+            }
+        })
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    pub fn set_dont_fragment(&self) -> io::Result<()> {
+        Err(io::Error::new(io::ErrorKind::Unsupported, "--dont-fragment is only supported on Linux"))
+    }
+
+    /// Allow requests to a broadcast address (`SO_BROADCAST`), and send the
+    /// ones to a multicast group with the socket's unicast TTL instead of
+    /// the multicast default of 1 (`IP_MULTICAST_TTL`).
+    pub fn enable_one_to_many(&self) -> io::Result<()> {
+        self.with_sock_ref(|socket| {
+            socket.set_broadcast(true)?;
+            socket.set_multicast_ttl_v4(socket.ttl()?)
+        })
+    }
+
+    /// Set the socket's unicast TTL, returning the one it replaces.
+    fn swap_ttl(&self, ttl: u32) -> io::Result<u32> {
+        self.with_sock_ref(|socket| {
+            let previous = socket.ttl()?;
+            socket.set_ttl(ttl)?;
+            Ok(previous)
+        })
+    }
+}
+
+/// Turn the response to a request sent at `sent_at` into its outcome.
+fn answer(sent_at: Instant, response: Response) -> Result<Echo, PingError> {
+    let rtt = response.received_at.saturating_duration_since(sent_at);
+    match response.kind {
+        ResponseKind::Reply(payload) => Ok(Echo {
+            rtt,
+            handshake: None,
+            reply: Reply {
+                received_at: response.received_at,
+                source: response.source,
+                payload,
+                ttl: response.ttl,
+            },
+        }),
+        ResponseKind::Icmp { icmp_type: ICMP_DEST_UNREACHABLE, code: ICMP_FRAG_NEEDED, mtu } => {
+            Err(PingError::TooBig {
+                source: response.source,
+                mtu: (mtu != 0).then_some(mtu),
+            })
+        }
+        ResponseKind::Icmp { icmp_type, code, .. } => Err(PingError::Icmp {
+            source: response.source,
+            icmp_type,
+            code,
+        }),
+    }
+}
+
+pub fn checksum(data: &[u8]) -> u16 {
+    let mut sum = data
+        .chunks(2)
+        .map(|pair| u16::from_be_bytes([pair[0], *pair.get(1).unwrap_or(&0)]) as u32)
+        .sum::<u32>();
+    while sum >> 16 != 0 {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+/// An echo or timestamp request; both share the identifier and sequence
+/// layout.
+fn encode_request(request: u8, ident: Option<u16>, seq: u16, payload: &[u8]) -> Vec<u8> {
+    let mut packet = vec![0; ICMP_HEADER_LEN + payload.len()];
+    packet[0] = request;
+    packet[6..8].copy_from_slice(&seq.to_be_bytes());
+    packet[ICMP_HEADER_LEN..].copy_from_slice(payload);
+    // With a kernel-owned identifier the kernel fills in and checksums for us.
+    if let Some(ident) = ident {
+        packet[4..6].copy_from_slice(&ident.to_be_bytes());
+        let sum = checksum(&packet);
+        packet[2..4].copy_from_slice(&sum.to_be_bytes());
+    }
+    packet
+}
+
+/// Strip the IPv4 header off a raw datagram.
+fn strip_ipv4_header(buf: &[u8]) -> Option<&[u8]> {
+    let header_len = ((*buf.first()? & 0x0f) as usize) * 4;
+    if header_len < 20 || buf.len() < header_len {
+        return None;
+    }
+    Some(&buf[header_len..])
+}
+
+enum Decoded<'a> {
+    Reply { ident: u16, seq: u16, payload: &'a [u8] },
+    Error { icmp_type: u8, code: u8, mtu: u16, dest: IpAddr, ident: u16, seq: u16 },
+}
+
+fn decode_icmp(message: &[u8]) -> Option<Decoded<'_>> {
+    if message.len() < ICMP_HEADER_LEN {
+        return None;
+    }
+    let ident = u16::from_be_bytes([message[4], message[5]]);
+    let seq = u16::from_be_bytes([message[6], message[7]]);
+    match message[0] {
+        ICMP_ECHO_REPLY | ICMP_TIMESTAMP_REPLY => Some(Decoded::Reply {
+            ident,
+            seq,
+            payload: &message[ICMP_HEADER_LEN..],
+        }),
+        ICMP_DEST_UNREACHABLE | ICMP_TIME_EXCEEDED => {
+            // The error quotes the IP header and first 8 bytes of our request.
+            let quoted = strip_ipv4_header(&message[ICMP_HEADER_LEN..])?;
+            let original = &message[ICMP_HEADER_LEN..];
+            let dest = Ipv4Addr::new(original[16], original[17], original[18], original[19]);
+            if quoted.len() < ICMP_HEADER_LEN || !matches!(quoted[0], ICMP_ECHO_REQUEST | ICMP_TIMESTAMP_REQUEST) {
+                return None;
+            }
+            Some(Decoded::Error {
+                icmp_type: message[0],
+                code: message[1],
+                // Where an echo has its sequence number, a fragmentation-
+                // needed error has the next-hop MTU (RFC 1191).
+                mtu: seq,
+                dest: IpAddr::V4(dest),
+                ident: u16::from_be_bytes([quoted[4], quoted[5]]),
+                seq: u16::from_be_bytes([quoted[6], quoted[7]]),
+            })
+        }
+        _ => None,
+    }
+}
+
+/// The one waiter for `token`'s identifier and sequence, whatever host it
+/// pinged. `None` when no request or several could be the one answered.
+fn any_source_token(waiters: &HashMap<Token, oneshot::Sender<Response>>, token: Token) -> Option<Token> {
+    let mut candidates = waiters.keys().filter(|t| t.1 == token.1 && t.2 == token.2);
+    match (candidates.next(), candidates.next()) {
+        (Some(only), None) => Some(*only),
+        _ => None,
+    }
+}
+
+async fn recv_task(
+    socket: AsyncSocket,
+    waiters: Waiters,
+    collectors: Collectors,
+    capture: Option<Arc<Capture>>,
+    any_source: bool,
+) {
+    let raw = socket.get_type() == socket2::Type::RAW;
+    let kernel_ident = cfg!(any(target_os = "linux", target_os = "android")) && !raw;
+    let mut buf = [0; 65536];
+    loop {
+        let Ok((size, addr)) = socket.recv_from(&mut buf).await else {
+            continue;
+        };
+        let received_at = Instant::now();
+
+        let message = if raw {
+            match strip_ipv4_header(&buf[..size]) {
+                Some(message) => message,
+                None => continue,
+            }
+        } else {
+            &buf[..size]
+        };
+
+        let Some(decoded) = decode_icmp(message) else {
+            continue;
+        };
+        let ident_key = |ident: u16| (!kernel_ident).then_some(ident);
+        let is_reply = matches!(decoded, Decoded::Reply { .. });
+        let (token, kind) = match decoded {
+            Decoded::Reply { ident, seq, payload } => (
+                Token(addr.ip(), ident_key(ident), seq),
+                ResponseKind::Reply(payload.to_vec()),
+            ),
+            Decoded::Error { icmp_type, code, mtu, dest, ident, seq } => (
+                Token(dest, ident_key(ident), seq),
+                ResponseKind::Icmp { icmp_type, code, mtu },
+            ),
+        };
+        let response = Response {
+            received_at,
+            source: addr.ip(),
+            // Raw datagrams start with the IPv4 header, TTL at offset 8.
+            ttl: raw.then(|| buf[8]),
+            kind,
+        };
+
+        let waiter = {
+            let mut waiters = waiters.lock().unwrap();
+            match waiters.remove(&token) {
+                None if any_source && is_reply => any_source_token(&waiters, token).and_then(|t| waiters.remove(&t)),
+                waiter => waiter,
+            }
+        };
+        let collector = match (&waiter, is_reply) {
+            (None, true) => collectors.lock().unwrap().get(&(token.1, token.2)).cloned(),
+            _ => None,
+        };
+        if waiter.is_none() && collector.is_none() {
+            debug!("no one is waiting for ICMP packet from {}", addr.ip());
+            continue;
+        }
+        match &capture {
+            Some(capture) if raw => capture.ip(&buf[..size]),
+            Some(capture) => capture.received(addr.ip(), message),
+            None => {}
+        }
+        // If the send fails the pinger already gave up on this reply.
+        match (waiter, collector) {
+            (Some(waiter), _) => {
+                let _ = waiter.send(response);
+            }
+            (None, Some(collector)) => {
+                let _ = collector.send(response);
+            }
+            (None, None) => {}
+        }
+    }
+}
+
+fn timestamp_epoch() -> Instant {
+    static EPOCH: OnceLock<Instant> = OnceLock::new();
+    *EPOCH.get_or_init(Instant::now)
+}
+
+/// Build a `size`-byte payload, filled like ping(8) with an incrementing byte
+/// pattern. With `timestamp` set and room for it, the first
+/// [`TIMESTAMP_LEN`] bytes carry the full sequence number and a monotonic
+/// send time so the reply can be checked against the request it answers.
+pub fn build_payload(size: usize, sequence: u32, timestamp: bool) -> Vec<u8> {
+    let mut payload: Vec<u8> = (0..size).map(|i| i as u8).collect();
+    if timestamp && size >= TIMESTAMP_LEN {
+        let nanos = timestamp_epoch().elapsed().as_nanos() as u64;
+        payload[..4].copy_from_slice(TIMESTAMP_MAGIC);
+        payload[4..8].copy_from_slice(&sequence.to_be_bytes());
+        payload[8..16].copy_from_slice(&nanos.to_be_bytes());
+    }
+    payload
+}
+
+/// Read back what [`build_payload`] embedded: the sequence number and the
+/// instant the request was built.
+pub fn parse_timestamp(payload: &[u8]) -> Option<(u32, Instant)> {
+    if payload.len() < TIMESTAMP_LEN || &payload[..4] != TIMESTAMP_MAGIC {
+        return None;
+    }
+    let sequence = u32::from_be_bytes(payload[4..8].try_into().ok()?);
+    let nanos = u64::from_be_bytes(payload[8..16].try_into().ok()?);
+    Some((sequence, timestamp_epoch() + Duration::from_nanos(nanos)))
+}
@@ -1,281 +1,5016 @@
-use std::time::Duration;
-use std::net::IpAddr;
+mod baseline;
+mod casing;
+mod cidr;
+mod discovery;
+mod dns;
+mod event_log;
+mod event_socket;
+mod expr;
+mod fping;
+mod grafana;
+mod http;
+mod icmp;
+mod msgpack;
+mod mtr;
+mod pcap;
+mod prometheus;
+mod replay;
+mod statsd;
+mod syslog;
+mod target;
+mod tcp;
+mod template;
+mod tui;
+mod vrf;
+mod webhook;
+
+use std::future::Future;
+use std::pin::Pin;
+use std::process::ExitCode;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use std::net::{IpAddr, SocketAddr};
 use std::sync::Arc;
-use clap::Parser;
-use serde::Serialize;
-use surge_ping::{Client, Config, ICMP, PingIdentifier, PingSequence};
-use tracing::{error, info};
-use tokio::time::timeout;
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicU8, Ordering};
+use clap::parser::ValueSource;
+use clap::{CommandFactory, FromArgMatches, Parser, ValueEnum};
+use serde::{Deserialize, Serialize};
+use surge_ping::{Config, ICMP};
+use tracing::{debug, error, info, info_span, warn, Instrument};
+use tracing_subscriber::filter::{filter_fn, EnvFilter, LevelFilter};
+use tracing_subscriber::fmt::writer::BoxMakeWriter;
+use tracing_subscriber::prelude::*;
 use tokio::net::lookup_host;
-use tokio::sync::Mutex;
+use tokio::sync::{Mutex, OwnedSemaphorePermit, Semaphore};
+use tokio::task::{JoinHandle, JoinSet};
 use tokio::signal;
+use casing::JsonCase;
+use icmp::{EchoClient, Echo, PingError, Reply, TIMESTAMP_LEN};
+
+/// How far the RTT recomputed from an embedded send timestamp may drift from
+/// the measured one before the reply is counted as a timestamp mismatch.
+const TIMESTAMP_TOLERANCE: Duration = Duration::from_millis(5);
+
+/// RTTs below this to anything but loopback are faster than any real
+/// network path and point at a broken clock or a virtualized NIC.
+const PLAUSIBLE_RTT_FLOOR: Duration = Duration::from_micros(10);
+
+/// How far the wall clock may run ahead of or behind the monotonic clock
+/// over one packet before we assume the system clock was stepped.
+const CLOCK_STEP_THRESHOLD: Duration = Duration::from_millis(50);
 
 #[derive(Parser, Clone)]
 #[command(author, version, about = "ICMP ping utility with JSON output")]
 struct Args {
-    /// Target host to ping
-    target: String,
+    /// Target host(s) to ping
+    #[arg(required_unless_present_any = ["replay", "self_test", "targets_from"])]
+    targets: Vec<String>,
+
+    /// Check that peckr itself works: ping 127.0.0.1 and report on the socket, timing and JSON output
+    #[arg(
+        long = "self-test",
+        conflicts_with_all = ["targets", "replay", "tcp", "mtr", "target_fallback", "compare_direct", "await_healthy", "wait_down"]
+    )]
+    self_test: bool,
+
+    /// Also ping the targets listed by this http:// URL, a JSON array fetched at startup
+    #[arg(
+        long = "targets-from",
+        value_name = "URL",
+        conflicts_with_all = ["replay", "self_test", "mtr", "target_fallback", "compare_direct"]
+    )]
+    targets_from: Option<String>,
+
+    /// Fetch --targets-from again every DURATION (e.g. 30s, 5m): ping targets that appear, stop those that disappear
+    #[arg(
+        long = "targets-refresh",
+        value_parser = parse_duration,
+        requires = "targets_from",
+        conflicts_with_all = ["rounds", "await_healthy", "wait_down", "server_name"]
+    )]
+    targets_refresh: Option<Duration>,
+
+    /// Probe this host instead when the single target does not resolve or never answers
+    #[arg(long = "target-fallback", conflicts_with_all = ["replay", "mtr", "await_healthy", "wait_down"])]
+    target_fallback: Option<String>,
+
+    /// Resolve targets by querying this DNS server (IP or IP:PORT) instead of the system resolver
+    #[arg(long = "dns-server", value_parser = dns::parse_server, conflicts_with = "replay")]
+    dns_server: Option<SocketAddr>,
+
+    /// Fail unless every address the target resolves to is one of these (repeatable)
+    #[arg(long = "expect-ip", conflicts_with_all = ["replay", "target_fallback"])]
+    expect_ip: Vec<IpAddr>,
+
+    /// Fail unless every address the target resolves to is public: not private, shared, loopback, link-local or multicast
+    #[arg(long = "public-only", conflicts_with_all = ["replay", "self_test", "private_only"])]
+    public_only: bool,
+
+    /// Fail unless every address the target resolves to is internal: private, shared, loopback or link-local
+    #[arg(long = "private-only", conflicts_with = "replay")]
+    private_only: bool,
+
+    /// Resolve the target again every DURATION (e.g. 30s, 5m) and log when its addresses change
+    #[arg(long = "reresolve-every", value_parser = parse_duration, conflicts_with = "replay")]
+    reresolve_every: Option<Duration>,
+
+    /// Fail when --reresolve-every finds the addresses changed
+    #[arg(long = "fail-on-reresolve-change", requires = "reresolve_every")]
+    fail_on_reresolve_change: bool,
+
+    /// Never resolve names: every target, and the --statsd and --webhook hosts, must be IP addresses
+    #[arg(long = "no-dns", conflicts_with = "dns_server")]
+    no_dns: bool,
+
+    /// Also ping this host directly and report how much latency the target (a relay) adds
+    #[arg(long = "compare-direct", conflicts_with_all = ["replay", "mtr", "target_fallback"])]
+    compare_direct: Option<String>,
+
+    /// Re-summarize the packet events of a recorded run (JSON logs) instead of pinging
+    #[arg(long = "replay", conflicts_with = "targets")]
+    replay: Option<String>,
 
     /// Stop after sending COUNT packets (0 for endless mode)
     #[arg(short = 'c', long = "count", default_value_t = 30)]
     count: u32,
 
-    /// Wait INTERVAL milliseconds between sending each packet
+    /// Stop once N packets have been sent in all, whatever --count says; a safety net for endless runs left behind
+    #[arg(long = "max-packets")]
+    max_packets: Option<u32>,
+
+    /// Spread the run over DURATION (e.g. 10s, 500ms, 2m): derives --interval from --count, or the count from --interval
+    #[arg(long = "duration", value_parser = parse_duration)]
+    duration: Option<Duration>,
+
+    /// With --duration, end the drain when the duration is up (true) or let it run its full window after the last send (false)
+    #[arg(long = "deadline-includes-drain", value_name = "BOOL", action = clap::ArgAction::Set, default_value_t = true)]
+    deadline_includes_drain: bool,
+
+    /// Before measuring, ping until the first reply (up to --gate-timeout) and discard those packets
+    #[arg(long = "gate-on-first-reply", conflicts_with_all = ["replay", "mtr"])]
+    gate_on_first_reply: bool,
+
+    /// Longest --gate-on-first-reply waits for a reply before measuring anyway (e.g. 30s)
+    #[arg(long = "gate-timeout", value_parser = parse_duration, default_value = "30s")]
+    gate_timeout: Duration,
+
+    /// Send N packets first as a warmup, reported separately from the measurement that follows
+    #[arg(long = "warmup-count", conflicts_with_all = ["warmup_duration", "replay", "mtr"])]
+    warmup_count: Option<u32>,
+
+    /// Warm up for DURATION (e.g. 5s) at --interval before the measurement
+    #[arg(long = "warmup-duration", value_parser = parse_duration, conflicts_with_all = ["replay", "mtr"])]
+    warmup_duration: Option<Duration>,
+
+    /// Run the whole measurement N times and report each round and all of them pooled
+    #[arg(long = "rounds", default_value_t = 1, conflicts_with_all = ["await_healthy", "wait_down", "replay", "mtr"])]
+    rounds: u32,
+
+    /// Pause between --rounds (e.g. 10m)
+    #[arg(long = "round-gap", value_parser = parse_duration, requires = "rounds")]
+    round_gap: Option<Duration>,
+
+    /// Repeat --count-packet windows until one passes the thresholds, up to --await-timeout
+    #[arg(long = "await-healthy")]
+    await_healthy: bool,
+
+    /// Give up on --await-healthy after DURATION (e.g. 90s, 5m)
+    #[arg(long = "await-timeout", value_parser = parse_duration, default_value = "5m")]
+    await_timeout: Duration,
+
+    /// Ping until the target stops answering (--down-after consecutive losses), up to --wait-timeout
+    #[arg(long = "wait-down")]
+    wait_down: bool,
+
+    /// Give up on --wait-down after DURATION (e.g. 90s, 5m)
+    #[arg(long = "wait-timeout", value_parser = parse_duration, default_value = "5m")]
+    wait_timeout: Duration,
+
+    /// Stop once N replies have been received (whichever of this and --count comes first)
+    #[arg(long = "until-received")]
+    until_received: Option<u32>,
+
+    /// ICMP identifier of the first target, the next target using the one after it and so on (default: from the process ID)
+    #[arg(long = "identifier-base")]
+    identifier_base: Option<u16>,
+
+    /// Wait INTERVAL milliseconds between sending each packet (or seconds with --interval-unit s)
     #[arg(short = 'i', long = "interval", default_value_t = 100)]
     interval: u64,
 
-    /// Time to wait for a response, in milliseconds
+    /// Unit of --interval
+    #[arg(long = "interval-unit", value_enum, default_value_t = TimeUnit::Ms)]
+    interval_unit: TimeUnit,
+
+    /// Give up with a timeout result if resolving the targets and opening the sockets takes longer (e.g. 3s, 500ms)
+    #[arg(long = "startup-timeout", value_parser = parse_duration, default_value = "5s")]
+    startup_timeout: Duration,
+
+    /// Time to wait for a response, in milliseconds (or seconds with --timeout-unit s)
     #[arg(short = 'W', long = "timeout", default_value_t = 1000)]
     timeout: u64,
 
+    /// Unit of --timeout and --min-timeout
+    #[arg(long = "timeout-unit", value_enum, default_value_t = TimeUnit::Ms)]
+    timeout_unit: TimeUnit,
+
+    /// Time out each packet at FACTOR times the target's average RTT, between --min-timeout and --timeout
+    #[arg(long = "adaptive-timeout", value_parser = parse_positive_f64)]
+    adaptive_timeout: Option<f64>,
+
+    /// Lower bound for --adaptive-timeout, in milliseconds (or seconds with --timeout-unit s)
+    #[arg(long = "min-timeout", default_value_t = 100)]
+    min_timeout: u64,
+
+    /// Keep at most N RTT samples per target for percentiles and exemplars, decimating beyond that
+    #[arg(long = "max-samples", default_value_t = 100_000)]
+    max_samples: usize,
+
+    /// Add the retained RTT samples to the result as data.samples_ms; up to --max-samples per target, so output can get large
+    #[arg(long = "include-samples")]
+    include_samples: bool,
+
+    /// Number of payload bytes to send in each packet
+    #[arg(short = 's', long = "size", default_value_t = 0)]
+    size: usize,
+
+    /// Cycle the payload size through these byte counts, one per packet, and report loss and latency per size
+    #[arg(
+        long = "size-sweep",
+        value_delimiter = ',',
+        conflicts_with_all = ["size", "tcp", "mtr", "bandwidth", "timestamp_icmp", "replay"]
+    )]
+    size_sweep: Vec<usize>,
+
+    /// Estimate path capacity from how far apart the replies to a burst of large packets arrive
+    #[arg(long = "bandwidth")]
+    bandwidth: bool,
+
+    /// Embed a send timestamp in the payload and verify it on receipt (needs --size >= 16)
+    #[arg(long = "timestamp")]
+    timestamp: bool,
+
+    /// Send ICMP Timestamp requests (type 13) instead of echoes and estimate the target's clock offset
+    #[arg(long = "timestamp-icmp")]
+    timestamp_icmp: bool,
+
+    /// Sequence number of the first packet
+    #[arg(long = "seq-start", default_value_t = 0)]
+    seq_start: u16,
+
     /// Set Time to Live
     #[arg(short = 't', long = "ttl", default_value_t = 64)]
     ttl: u8,
 
+    /// Count replies from outside this prefix as failures (repeatable)
+    #[arg(long = "expect-source")]
+    expect_source: Vec<cidr::Cidr>,
+
+    /// Include the command line and the effective settings in every result, as `config`
+    #[arg(long = "embed-config")]
+    embed_config: bool,
+
+    /// Anycast target: accept replies from any address, report which ones answered, and do not count hop count changes
+    #[arg(long = "anycast", conflicts_with = "expect_source")]
+    anycast: bool,
+
+    /// Report every address that answered, with its reply count and latency
+    #[arg(long = "responders")]
+    responders: bool,
+
+    /// Ping a broadcast address or multicast group and gather every reply to each request
+    #[arg(
+        long = "broadcast",
+        visible_alias = "multicast",
+        conflicts_with_all = ["tcp", "mtr", "timestamp_icmp", "bandwidth", "expect_source"]
+    )]
+    broadcast: bool,
+
     /// Maximum acceptable packet loss percentage
     #[arg(short = 'L', long = "max-loss", default_value_t = 5.0)]
     max_loss: f64,
 
-    /// Maximum acceptable round-trip time in milliseconds
-    #[arg(short = 'M', long = "max-latency", default_value_t = 800)]
-    max_latency: u64,
+    /// Maximum acceptable average round-trip time in milliseconds, or a percentile's as pNN:MS (repeatable, all must hold)
+    #[arg(
+        short = 'M',
+        long = "max-latency",
+        value_name = "[pNN:]MS",
+        value_parser = parse_latency_limit,
+        default_value = "800"
+    )]
+    max_latency: Vec<LatencyLimit>,
+
+    /// Maximum acceptable 95th percentile round-trip time in milliseconds
+    #[arg(long = "max-p95")]
+    max_p95: Option<u64>,
+
+    /// Maximum acceptable RFC 3550 jitter in milliseconds
+    #[arg(long = "max-jitter")]
+    max_jitter: Option<f64>,
+
+    /// Maximum acceptable average --http time to first byte in milliseconds
+    #[arg(long = "max-ttfb", requires = "http")]
+    max_ttfb: Option<u64>,
+
+    /// Maximum acceptable percentage of replies that arrived out of order
+    #[arg(long = "max-reorder")]
+    max_reorder: Option<f64>,
+
+    /// Packet loss percentage above which a passing target is reported as degraded
+    #[arg(long = "warn-loss")]
+    warn_loss: Option<f64>,
 
-    /// Server name for reporting (defaults to target)
+    /// Round-trip time in milliseconds above which a passing target is reported as degraded
+    #[arg(long = "warn-latency")]
+    warn_latency: Option<u64>,
+
+    /// Name of this prober in the results (defaults to the system hostname)
+    #[arg(long = "prober-host")]
+    prober_host: Option<String>,
+
+    /// Identifies this invocation in every result and log event
+    #[arg(skip = uuid::Uuid::new_v4().to_string())]
+    run_id: String,
+
+    /// When this invocation started; per-packet log events are timed from it
+    #[arg(skip = Instant::now())]
+    started_at: Instant,
+
+    /// Server name for reporting (defaults to target, single target only)
     #[arg(short = 'n', long = "name")]
     server_name: Option<String>,
 
+    /// Probe with a TCP handshake to PORT instead of an ICMP echo
+    #[arg(long = "tcp")]
+    tcp: Option<u16>,
+
+    /// With --tcp, send an HTTP GET for PATH on every connection and time the first byte of the response
+    #[arg(long = "http", value_name = "PATH", requires = "tcp")]
+    http: Option<String>,
+
+    /// Bind TCP probes to this local source port (--tcp only)
+    #[arg(long = "source-port")]
+    source_port: Option<u16>,
+
+    /// Send each packet from the next of these local addresses in turn, so consecutive probes hash onto different ECMP paths
+    #[arg(long = "source-rotate", value_delimiter = ',')]
+    source_rotate: Vec<IpAddr>,
+
+    /// Report loss and latency per --source-rotate address
+    #[arg(long = "per-source-stats", requires = "source_rotate")]
+    per_source_stats: bool,
+
+    /// Trace the path MTR-style: probe every hop each round and report per-hop statistics
+    #[arg(long = "mtr")]
+    mtr: bool,
+
+    /// Highest TTL probed by --mtr
+    #[arg(long = "max-hops", default_value_t = 30)]
+    max_hops: u8,
+
     /// Quiet output. Only show summary at end
     #[arg(short = 'q', long = "quiet")]
     quiet: bool,
+
+    /// Print nothing when every target is ok; on failure or degradation print the result and held-back logs (implies --quiet)
+    #[arg(long = "only-on-failure", conflicts_with_all = ["report_every", "mtr"])]
+    only_on_failure: bool,
+
+    /// Exit with bit flags saying why the run failed instead of 0/1/2 (see Exit Status in the README)
+    #[arg(long = "rich-exit-codes", conflicts_with_all = ["self_test", "mtr", "wait_down"])]
+    rich_exit_codes: bool,
+
+    /// Full-screen dashboard on the terminal while pinging; the summary prints when it closes (JSON unless --format is given)
+    #[arg(long, conflicts_with_all = ["mtr", "replay", "self_test", "report_every", "only_on_failure"])]
+    tui: bool,
+
+    /// Print socket and timing diagnostics to stderr before pinging; -vv adds per-packet socket detail
+    #[arg(short = 'v', long = "verbose", action = clap::ArgAction::Count)]
+    verbose: u8,
+
+    /// Send on multiples of INTERVAL since the Unix epoch, so runs on different hosts line up
+    #[arg(long = "align")]
+    align: bool,
+
+    /// Consecutive losses after which a target is considered down
+    #[arg(long = "down-after", default_value_t = 3)]
+    down_after: u32,
+
+    /// While a target is down, double the interval after every further loss
+    #[arg(long = "down-backoff")]
+    down_backoff: bool,
+
+    /// Upper bound for the --down-backoff interval, in milliseconds
+    #[arg(long = "max-backoff", default_value_t = 60000)]
+    max_backoff: u64,
+
+    /// Maximum packets in flight per target; sending waits while this many are unanswered
+    #[arg(long = "window", default_value_t = 16)]
+    window: usize,
+
+    /// After the last send, wait up to MS milliseconds for outstanding replies (defaults to --timeout plus --late-window)
+    #[arg(long = "drain")]
+    drain: Option<u64>,
+
+    /// Keep waiting MS milliseconds past --timeout and count a reply then as late instead of lost
+    #[arg(long = "late-window", default_value_t = 0, conflicts_with_all = ["tcp", "broadcast", "timestamp_icmp"])]
+    late_window: u64,
+
+    /// Print the running summary every SECONDS while pinging
+    #[arg(long = "report-every")]
+    report_every: Option<u64>,
+
+    /// Send metrics to a StatsD/DogStatsD server at HOST:PORT, every --report-every and at the end
+    #[arg(long = "statsd")]
+    statsd: Option<String>,
+
+    /// Also send each target's summary to the local syslog, at a severity matching its status
+    #[arg(long = "syslog")]
+    syslog: bool,
+
+    /// With --syslog, also log every reply and loss
+    #[arg(long = "syslog-packets")]
+    syslog_packets: bool,
+
+    /// POST every result as JSON to this http:// URL: each --report-every report and the final results
+    #[arg(long = "webhook", value_name = "URL")]
+    webhook: Option<String>,
+
+    /// Print one line per target from TEMPLATE, e.g. '{target} {loss}% {avg}ms {status}', instead of the --format summary
+    #[arg(long = "template", value_parser = template::parse, conflicts_with_all = ["format", "mtr"])]
+    template: Option<template::Template>,
+
+    /// Decide success with an expression over the final metrics instead of the fixed thresholds
+    #[arg(long = "success-expr", value_parser = expr::parse)]
+    success_expr: Option<expr::Expr>,
+
+    /// Stop a target as soon as its result can no longer meet the thresholds
+    #[arg(long = "fail-fast")]
+    fail_fast: bool,
+
+    /// Compare the results against a JSON result saved earlier
+    #[arg(long = "baseline")]
+    baseline: Option<String>,
+
+    /// Save the JSON result to PATH for use with --baseline later
+    #[arg(long = "save-baseline")]
+    save_baseline: Option<String>,
+
+    /// Cap the aggregate send rate across all targets, in packets per second
+    #[arg(long = "max-pps", value_parser = parse_positive_f64)]
+    max_pps: Option<f64>,
+
+    /// Attach RTT exemplars to the histogram (switches --format prometheus to OpenMetrics)
+    #[arg(long = "exemplars")]
+    exemplars: bool,
+
+    /// Field-name casing of the JSON output
+    #[arg(long = "json-case", value_enum, default_value_t = JsonCase::Original)]
+    json_case: JsonCase,
+
+    /// Leave out null fields in the JSON and MessagePack output
+    #[arg(long = "omit-null")]
+    omit_null: bool,
+
+    /// Decimal places of the latencies in human output (default: as each line has always printed them)
+    #[arg(long = "precision", value_name = "DIGITS", value_parser = clap::value_parser!(u8).range(0..=6))]
+    precision: Option<u8>,
+
+    /// Format of peckr's own log output on stderr
+    #[arg(long = "log-format", value_enum, default_value_t = LogFormat::Text)]
+    log_format: LogFormat,
+
+    /// Listen on this Unix domain socket and stream the per-packet events to clients as NDJSON
+    #[arg(long = "event-socket", value_name = "PATH", conflicts_with = "replay")]
+    event_socket: Option<String>,
+
+    /// Append the per-packet events to this file as NDJSON
+    #[arg(long = "event-log", value_name = "PATH", conflicts_with = "replay")]
+    event_log: Option<String>,
+
+    /// Rotate --event-log before it would grow past BYTES
+    #[arg(long = "event-log-max-size", value_name = "BYTES", requires = "event_log")]
+    event_log_max_size: Option<u64>,
+
+    /// Rotate --event-log every DURATION (e.g. 1h, 1d is 24h)
+    #[arg(long = "event-log-rotate-every", value_name = "DURATION", value_parser = parse_duration, requires = "event_log")]
+    event_log_rotate_every: Option<Duration>,
+
+    /// Rotated --event-log files to keep, as PATH.1 (newest) to PATH.N
+    #[arg(long = "event-log-keep", value_name = "N", default_value_t = 5)]
+    event_log_keep: u32,
+
+    /// Output format for the final summary
+    #[arg(short = 'f', long = "format", value_enum, default_value_t = OutputFormat::Json)]
+    format: OutputFormat,
+
+    /// Write the final summary to PATH instead of stdout, leaving stdout to the live output
+    #[arg(long = "summary-out")]
+    summary_out: Option<String>,
+
+    /// Write the sent requests and matched replies to a pcap file
+    #[arg(long = "pcap")]
+    pcap: Option<String>,
+
+    /// Set the SO_MARK firewall mark on probe packets (Linux only, requires CAP_NET_ADMIN)
+    #[arg(long = "mark")]
+    mark: Option<u32>,
+
+    /// Send every probe through the VRF NAME, so routes come from its table (Linux only)
+    #[arg(long = "vrf", value_name = "NAME", conflicts_with = "replay")]
+    vrf: Option<String>,
+
+    /// Set the Don't Fragment bit and reject a --size too big for the outgoing route's MTU (Linux only)
+    #[arg(short = 'D', long = "dont-fragment", conflicts_with = "tcp")]
+    dont_fragment: bool,
 }
 
-#[derive(Serialize, Clone)]
-struct PingResult {
-    checkname: String,
-    servername: String,
-    resulttype: String,
-    success: bool,
-    error: Option<String>,
-    data: Option<PingData>,
+/// Unit of the plain-number `--interval` and `--timeout`.
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum TimeUnit {
+    Ms,
+    S,
 }
 
-#[derive(Serialize, Clone)]
-struct PingData {
-    latency: i64,
-    packetloss: f64,
-    packets_sent: u32,
-    packets_received: u32,
+impl TimeUnit {
+    fn millis(self, value: u64) -> u64 {
+        match self {
+            TimeUnit::Ms => value,
+            TimeUnit::S => value.saturating_mul(1000),
+        }
+    }
 }
 
-#[derive(Clone)]
-struct PingStats {
-    sent: u32,
-    received: u32,
-    total_rtt: Duration,
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum OutputFormat {
+    /// JSON summary (an array when multiple targets are given)
+    Json,
+    /// Human-readable statistics (a table when multiple targets are given)
+    Human,
+    /// Prometheus text exposition format
+    Prometheus,
+    /// fping's `host : xmt/rcv/%loss = ...` summary line per target
+    Fping,
+    /// Length-prefixed MessagePack records, one per target
+    Msgpack,
+    /// Flat `target`/`value`/`status`/`time` objects for Grafana JSON datasources
+    Grafana,
+    /// No summary on stdout, for when --statsd or --syslog carry the results
+    None,
 }
 
-impl PingStats {
-    fn new() -> Self {
-        Self {
-            sent: 0,
-            received: 0,
-            total_rtt: Duration::ZERO,
-        }
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum LogFormat {
+    Text,
+    /// One JSON object per log event, for shipping to a log pipeline
+    Json,
+}
+
+/// Health of a target, ordered from best to worst. The process exits with
+/// the worst status of all targets: 0, 1 or 2.
+#[derive(Serialize, Deserialize, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "lowercase")]
+enum Status {
+    /// Within the warning thresholds
+    #[default]
+    Ok,
+    /// Passing, but above a warning threshold
+    Degraded,
+    /// Failing the critical thresholds (`success: false`)
+    Critical,
+}
+
+impl Status {
+    fn exit_code(self) -> ExitCode {
+        ExitCode::from(self as u8)
     }
 
-    fn packet_loss(&self) -> f64 {
-        if self.sent == 0 {
-            return 100.0;
+    fn name(self) -> &'static str {
+        match self {
+            Status::Ok => "ok",
+            Status::Degraded => "degraded",
+            Status::Critical => "critical",
         }
-        ((self.sent - self.received) as f64 / self.sent as f64) * 100.0
     }
+}
 
-    fn avg_rtt(&self) -> Duration {
-        if self.received == 0 {
-            return Duration::ZERO;
+/// `--rich-exit-codes` bits; the process exits with those of every target
+/// ORed together.
+const EXIT_LOSS: u8 = 1;
+const EXIT_LATENCY: u8 = 2;
+const EXIT_JITTER: u8 = 4;
+const EXIT_DNS: u8 = 8;
+const EXIT_DEGRADED: u8 = 16;
+/// Critical for none of the reasons above, e.g. `--success-expr`.
+const EXIT_OTHER: u8 = 32;
+/// peckr could not run, or a target could not be set up.
+const EXIT_SETUP: u8 = 64;
+
+/// Set when `--rich-exit-codes` is given, for `main` to pick the encoding.
+static RICH_EXIT_CODES: AtomicBool = AtomicBool::new(false);
+/// The `--rich-exit-codes` bits of the results reported so far.
+static EXIT_BITS: AtomicU8 = AtomicU8::new(0);
+
+/// One `--max-latency`: on the average RTT, or on a percentile of them.
+#[derive(Serialize, Deserialize, Clone, Copy)]
+struct LatencyLimit {
+    /// None for the average.
+    percentile: Option<f64>,
+    max_ms: u64,
+}
+
+/// `MS` limits the average, `pNN:MS` the NNth percentile, e.g. `p95:200`
+/// or `p99.9:500`.
+fn parse_latency_limit(value: &str) -> Result<LatencyLimit, String> {
+    let (percentile, ms) = match value.split_once(':') {
+        Some((name, ms)) => {
+            let percentile = name
+                .strip_prefix('p')
+                .and_then(|number| number.parse::<f64>().ok())
+                .filter(|percentile| *percentile > 0.0 && *percentile <= 100.0)
+                .ok_or_else(|| format!("invalid percentile '{}' (use p50, p95, p99.9...)", name))?;
+            (Some(percentile), ms)
         }
-        self.total_rtt / self.received
+        None => (None, value),
+    };
+    let max_ms = ms.parse().map_err(|_| format!("invalid latency '{}' (milliseconds)", ms))?;
+    Ok(LatencyLimit { percentile, max_ms })
+}
+
+impl Args {
+    /// ICMP identifier of the `index`th target. Distinct per target, so
+    /// concurrent targets never answer each other's requests.
+    fn identifier(&self, index: usize) -> u16 {
+        self.identifier_base.unwrap_or(std::process::id() as u16).wrapping_add(index as u16)
     }
 
-    fn update_with_success(&mut self, rtt: Duration) {
-        self.sent += 1;
-        self.received += 1;
-        self.total_rtt += rtt;
+    /// Packets in the warmup phase, if there is one.
+    fn warmup_count(&self) -> Option<u32> {
+        let from_duration = || {
+            self.warmup_duration
+                .map(|duration| (duration.as_millis() / self.interval.max(1) as u128).clamp(1, u32::MAX as u128) as u32)
+        };
+        self.warmup_count.or_else(from_duration).filter(|&count| count > 0)
     }
 
-    fn update_with_failure(&mut self) {
-        self.sent += 1;
+    /// The `--max-latency` on the average RTT; None when only percentiles
+    /// are limited.
+    fn max_avg_latency(&self) -> Option<u64> {
+        self.max_latency.iter().filter(|limit| limit.percentile.is_none()).map(|limit| limit.max_ms).min()
     }
-}
 
-async fn resolve_host(host: &str) -> Result<IpAddr, Box<dyn std::error::Error>> {
-    if let Ok(ip) = host.parse::<IpAddr>() {
-        return Ok(ip);
+    /// The `--max-latency pNN:MS` limits, `--max-p95` included.
+    fn percentile_limits(&self) -> Vec<(f64, u64)> {
+        let limits = self.max_latency.iter().filter_map(|limit| limit.percentile.map(|p| (p, limit.max_ms)));
+        limits.chain(self.max_p95.map(|max_p95| (95.0, max_p95))).collect()
     }
 
-    let addrs = lookup_host(format!("{}:0", host)).await?;
-    addrs
-        .map(|socket_addr| socket_addr.ip())
-        .next()
-        .ok_or_else(|| "Could not resolve hostname".into())
+    /// What a single reply is too slow against: the limit on the average,
+    /// or else the tightest percentile limit.
+    fn reply_latency_limit(&self) -> u64 {
+        self.max_avg_latency()
+            .or_else(|| self.percentile_limits().iter().map(|(_, max_ms)| *max_ms).min())
+            .unwrap_or(u64::MAX)
+    }
 }
 
-async fn send_single_ping(
-    client: &Client,
-    ip_addr: IpAddr,
-    sequence: u32,
-    timeout_duration: Duration,
-) -> Result<Duration, Box<dyn std::error::Error>> {
-    let mut pinger = client.pinger(ip_addr, PingIdentifier(sequence as u16)).await;
-    
-    let result = timeout(
-        timeout_duration,
-        pinger.ping(PingSequence(sequence as u16), &[])
-    ).await;
+/// The boundaries the status was decided against.
+#[derive(Serialize, Deserialize, Clone, Default)]
+struct Thresholds {
+    max_loss: f64,
+    /// None when `--max-latency` only limits percentiles.
+    max_latency: Option<u64>,
+    /// The `--max-latency pNN:MS` limits, in milliseconds by percentile.
+    #[serde(default)]
+    max_latency_percentiles: Vec<PercentileThreshold>,
+    max_p95: Option<u64>,
+    max_jitter: Option<f64>,
+    #[serde(default)]
+    max_ttfb: Option<u64>,
+    #[serde(default)]
+    max_reorder: Option<f64>,
+    warn_loss: Option<f64>,
+    warn_latency: Option<u64>,
+}
 
-    match result {
-        Ok(Ok((_, rtt))) => Ok(rtt),
-        Ok(Err(e)) => Err(e.into()),
-        Err(_) => Err("Request timeout".into()),
-    }
+#[derive(Serialize, Deserialize, Clone, Copy)]
+struct PercentileThreshold {
+    percentile: f64,
+    max_ms: u64,
 }
 
-async fn print_ping_result(ip_addr: IpAddr, sequence: u32, ttl: u8, rtt: Duration) {
-    println!(
-        "64 bytes from {}: icmp_seq={} ttl={} time={:.2} ms",
-        ip_addr,
-        sequence,
-        ttl,
-        rtt.as_secs_f64() * 1000.0
-    );
+/// Why a result failed, for routing alerts by cause; `error` carries the
+/// details for humans.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+enum ErrorKind {
+    /// The target name did not resolve
+    Dns,
+    /// The socket or socket option needed more privileges
+    Permission,
+    /// No packet was answered
+    Timeout,
+    /// The network reported the target unreachable
+    Unreachable,
+    /// This host had no network: interface down or no route
+    #[serde(rename = "network_down")]
+    NetworkDown,
+    /// The arguments or a file they name are unusable
+    Config,
+    /// The target resolved to an address other than `--expect-ip`, or
+    /// `--reresolve-every` saw its addresses change
+    #[serde(rename = "dns_mismatch")]
+    DnsMismatch,
+    /// The target resolved to an address `--public-only` or
+    /// `--private-only` refuses; nothing was sent to it
+    #[serde(rename = "address_scope")]
+    AddressScope,
+    /// Ctrl+C stopped the run before it completed
+    Interrupted,
 }
 
-async fn print_statistics(target: &str, stats: &PingStats) {
-    println!("\n--- {} ping statistics ---", target);
-    println!(
-        "{} packets transmitted, {} received, {:.1}% packet loss, time {}ms",
-        stats.sent,
-        stats.received,
-        stats.packet_loss(),
-        stats.total_rtt.as_millis()
-    );
-    
-    if stats.received > 0 {
-        println!("rtt avg = {:.3} ms", stats.avg_rtt().as_millis());
-    }
+/// Which kind of ICMP socket the pings went through. Only a raw socket
+/// sees the ICMP errors that routers send back for a packet.
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+enum SocketMode {
+    /// Raw socket, with privileges
+    Raw,
+    /// Unprivileged ping socket, the fallback when a raw one is refused
+    Dgram,
 }
 
-fn create_result(config: &Args, stats: &PingStats) -> PingResult {
-    let packet_loss = stats.packet_loss();
-    let avg_rtt = stats.avg_rtt().as_millis() as i64;
-    
-    let success = packet_loss <= config.max_loss
-        && avg_rtt <= config.max_latency as i64
-        && avg_rtt != 0;
+impl SocketMode {
+    fn of(client: &EchoClient) -> Self {
+        match client.privileged() {
+            true => SocketMode::Raw,
+            false => SocketMode::Dgram,
+        }
+    }
 
-    PingResult {
-        checkname: "ping".to_string(),
-        servername: config.server_name.clone().unwrap_or_else(|| config.target.clone()),
-        resulttype: "site".to_string(),
-        success,
-        error: None,
-        data: Some(PingData {
-            latency: avg_rtt,
-            packetloss: packet_loss,
-            packets_sent: stats.sent,
-            packets_received: stats.received,
-        }),
+    fn name(self) -> &'static str {
+        match self {
+            SocketMode::Raw => "raw",
+            SocketMode::Dgram => "dgram",
+        }
     }
 }
 
-async fn monitor_ctrl_c() -> Result<(), tokio::io::Error> {
-    signal::ctrl_c().await
+/// What `--gate-on-first-reply` took before the measurement could start.
+#[derive(Serialize, Deserialize, Clone)]
+struct Gate {
+    /// Packets sent until the first reply, the reply included.
+    packets: u32,
+    /// From the first packet to the first reply; null when none came
+    /// within `--gate-timeout`.
+    waited_ms: Option<u64>,
 }
 
-#[tokio::main]
-async fn main() -> Result<(), Box<dyn std::error::Error>> {
-    tracing_subscriber::fmt::init();
-    let config = Args::parse();
+/// Part of a run reported on its own: the `--warmup-count` packets sent
+/// before the measurement, or one of its `--rounds`. Judged by the same
+/// thresholds as the whole.
+#[derive(Serialize, Deserialize, Clone)]
+struct Phase {
+    status: Status,
+    data: Option<PingData>,
+}
 
-    let ip_addr = match resolve_host(&config.target).await {
-        Ok(ip) => ip,
-        Err(e) => {
-            let result = PingResult {
-                checkname: "ping".to_string(),
-                servername: config.server_name.clone().unwrap_or_else(|| config.target.clone()),
-                resulttype: "site".to_string(),
-                success: false,
-                error: Some(format!("DNS resolution failed: {}", e)),
-                data: None,
-            };
-            println!("{}", serde_json::to_string_pretty(&result)?);
-            return Err(e);
+impl Phase {
+    fn of(result: &PingResult) -> Self {
+        Self {
+            status: result.status,
+            data: result.data.clone(),
         }
-    };
+    }
+}
 
-    if !config.quiet {
-        info!("PING {} ({}) {} bytes of data", config.target, ip_addr, 56);
-        if config.count == 0 {
-            info!("Running in endless mode. Press Ctrl+C to stop.");
+/// Set when `--target-fallback` stood in for the primary target; `data` is
+/// then the fallback's.
+#[derive(Serialize, Deserialize, Clone)]
+struct Failover {
+    /// The target that produced this result.
+    target: String,
+    /// Why the primary was given up on.
+    primary_error_kind: ErrorKind,
+    primary_error: String,
+}
+
+/// The settings a result was measured with, for `--embed-config`. `count`
+/// and `interval_ms` are the ones in effect after `--duration`; the
+/// thresholds are in `thresholds`.
+#[derive(Serialize, Deserialize, Clone)]
+struct RunConfig {
+    version: String,
+    command_line: Vec<String>,
+    targets: Vec<String>,
+    count: u32,
+    interval_ms: u64,
+    timeout_ms: u64,
+    size: usize,
+    ttl: u8,
+    /// The port of `--tcp` probes, or None for ICMP echoes.
+    tcp_port: Option<u16>,
+}
+
+impl RunConfig {
+    fn new(config: &Args) -> Self {
+        Self {
+            version: env!("CARGO_PKG_VERSION").to_string(),
+            command_line: std::env::args().collect(),
+            targets: config.targets.clone(),
+            count: config.count,
+            interval_ms: config.interval,
+            timeout_ms: config.timeout,
+            size: config.size,
+            ttl: config.ttl,
+            tcp_port: config.tcp,
         }
     }
+}
 
-    let client = Client::new(&Config::builder()
-        .kind(ICMP::V4)
-        .ttl(config.ttl as u32)
-        .build())?;
+/// What the target resolved to, set with `--expect-ip` or
+/// `--reresolve-every`.
+#[derive(Serialize, Deserialize, Clone)]
+struct Resolution {
+    /// `--expect-ip`, empty when not given.
+    expected: Vec<IpAddr>,
+    /// The addresses at the start of the run; the first was pinged.
+    observed: Vec<IpAddr>,
+    /// The addresses `--reresolve-every` found last, when they differ from
+    /// `observed`.
+    latest: Option<Vec<IpAddr>>,
+    /// How often a re-resolution differed from the one before.
+    changes: u32,
+}
 
-    let stats = Arc::new(Mutex::new(PingStats::new()));
-    let mut sequence = 0;
-    
-    let ctrl_c = tokio::spawn(monitor_ctrl_c());
-    
-    loop {
-        if config.count > 0 && sequence >= config.count {
-            break;
-        }
+/// Set on the target's result by `--compare-direct`.
+#[derive(Serialize, Deserialize, Clone)]
+struct Comparison {
+    /// The host pinged directly, whose result follows this one.
+    direct: String,
+    /// Average RTT through the target minus the direct average, in
+    /// milliseconds; None unless both answered.
+    relay_overhead_ms: Option<f64>,
+}
 
-        let ping_result = send_single_ping(
-            &client,
-            ip_addr,
-            sequence,
-            Duration::from_millis(config.timeout),
-        ).await;
-
-        let mut stats_guard = stats.lock().await;
-        match ping_result {
-            Ok(rtt) => {
-                stats_guard.update_with_success(rtt);
-                if !config.quiet {
-                    drop(stats_guard);
-                    print_ping_result(ip_addr, sequence, config.ttl, rtt).await;
-                }
-            }
-            Err(e) => {
-                stats_guard.update_with_failure();
-                if !config.quiet {
-                    error!("Ping failed for sequence {}: {}", sequence, e);
-                }
-            }
-        }
+/// A change of health during the run: from `at`, in Unix microseconds, the
+/// target was `status`.
+#[derive(Serialize, Deserialize, Clone, Copy)]
+struct Transition {
+    at: u64,
+    status: Status,
+}
 
-        sequence += 1;
-        
-        if ctrl_c.is_finished() {
-            break;
-        }
-        
-        tokio::time::sleep(Duration::from_millis(config.interval)).await;
-    }
+/// Transitions kept per target; beyond this the oldest are dropped, so an
+/// endless run keeps its most recent history.
+const MAX_TIMELINE: usize = 100;
 
-    if !config.quiet {
-        let stats_guard = stats.lock().await;
-        print_statistics(&config.target, &stats_guard).await;
-    }
+/// Set once Ctrl+C stops the run.
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+/// Packets sent to all targets so far, against `--max-packets`.
+static PACKETS_SENT: AtomicU32 = AtomicU32::new(0);
 
-    let final_stats = stats.lock().await;
-    let result = create_result(&config, &final_stats);
-    println!("{}", serde_json::to_string_pretty(&result)?);
+/// One threshold the result was judged against, and how it fared.
+#[derive(Serialize, Deserialize, Clone)]
+struct Objective {
+    name: String,
+    measured: f64,
+    threshold: f64,
+    passed: bool,
+}
 
-    Ok(())
+#[derive(Serialize, Deserialize, Clone)]
+struct PingResult {
+    checkname: String,
+    servername: String,
+    resulttype: String,
+    #[serde(default)]
+    prober_host: String,
+    #[serde(default)]
+    run_id: String,
+    success: bool,
+    #[serde(default)]
+    status: Status,
+    #[serde(default)]
+    thresholds: Thresholds,
+    #[serde(default)]
+    objectives: Vec<Objective>,
+    /// Health changes packet by packet, the first entry being the health
+    /// the run started in.
+    #[serde(default)]
+    timeline: Vec<Transition>,
+    error: Option<String>,
+    #[serde(default)]
+    error_kind: Option<ErrorKind>,
+    #[serde(default)]
+    gate: Option<Gate>,
+    #[serde(default)]
+    warmup: Option<Phase>,
+    /// Each round's own result under `--rounds`, the result being all of
+    /// them pooled.
+    #[serde(default)]
+    rounds: Option<Vec<Phase>>,
+    #[serde(default)]
+    failover: Option<Failover>,
+    #[serde(default)]
+    comparison: Option<Comparison>,
+    /// The `--dns-server` targets were resolved through, or None for the
+    /// system resolver.
+    #[serde(default)]
+    resolver: Option<SocketAddr>,
+    #[serde(default)]
+    resolution: Option<Resolution>,
+    #[serde(default)]
+    config: Option<RunConfig>,
+    data: Option<PingData>,
+    delta: Option<baseline::Delta>,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+struct PingData {
+    latency: i64,
+    #[serde(default)]
+    latency_p95: i64,
+    #[serde(default)]
+    rfc3550_jitter_ms: f64,
+    packetloss: f64,
+    packets_sent: u32,
+    packets_received: u32,
+    timestamp_mismatches: Option<u32>,
+    /// Replies whose payload was not the one sent, counted as received.
+    #[serde(default)]
+    corrupted_replies: u32,
+    #[serde(default)]
+    late_replies: Option<u32>,
+    #[serde(default)]
+    source_mismatches: Option<u32>,
+    #[serde(default)]
+    timeouts: u32,
+    #[serde(default)]
+    icmp_errors: u32,
+    #[serde(default)]
+    too_big: u32,
+    #[serde(default)]
+    send_errors: u32,
+    #[serde(default)]
+    network_down: u32,
+    #[serde(default)]
+    local_network_down: Vec<DownPeriodData>,
+    #[serde(default)]
+    path_mtu: Option<u16>,
+    /// Longest run of consecutive lost packets, and when its first packet
+    /// failed, in Unix microseconds.
+    #[serde(default)]
+    max_consecutive_loss: u32,
+    #[serde(default)]
+    max_consecutive_loss_at: Option<u64>,
+    /// Replies that arrived after one to a later request, and their share
+    /// of the replies received, from 0 to 1.
+    #[serde(default)]
+    reordered: u32,
+    #[serde(default)]
+    reorder_ratio: f64,
+    backoff_ms: Option<u64>,
+    source_address: Option<IpAddr>,
+    /// None for `--tcp`, and for a replayed run.
+    #[serde(default)]
+    socket_mode: Option<SocketMode>,
+    /// The TTL the ICMP socket reported after `--ttl` was set on it.
+    #[serde(default)]
+    effective_ttl: Option<u8>,
+    #[serde(default)]
+    clock_step_detected: bool,
+    #[serde(default)]
+    suspicious_timing: bool,
+    #[serde(default)]
+    bandwidth_bps: Option<f64>,
+    #[serde(default)]
+    per_source: Option<Vec<SourceData>>,
+    #[serde(default)]
+    by_size: Option<Vec<SizeData>>,
+    #[serde(default)]
+    remote_clock: Option<RemoteClockData>,
+    #[serde(default)]
+    hop_count: Option<u8>,
+    #[serde(default)]
+    hop_count_changes: Option<u32>,
+    #[serde(default)]
+    responders: Option<Vec<ResponderData>>,
+    /// Addresses that answered, counting at most `MAX_RESPONDERS`.
+    #[serde(default)]
+    distinct_responders: u32,
+    /// Null until two packets were sent, and for a replayed run.
+    #[serde(default)]
+    actual_interval_ms: Option<IntervalData>,
+    /// With `--http`.
+    #[serde(default)]
+    http: Option<HttpData>,
+    /// The retained RTTs in the order they were measured, with
+    /// `--include-samples`.
+    #[serde(default)]
+    samples_ms: Option<Vec<f64>>,
+}
+
+/// The target's clock as a `--timestamp-icmp` reply reported it, taken
+/// from the fastest reply since it bounds the offset most tightly.
+#[derive(Serialize, Deserialize, Clone, Copy)]
+struct RemoteClockData {
+    originate_ms: u32,
+    receive_ms: u32,
+    transmit_ms: u32,
+    /// Target clock minus ours; null when the target does not keep
+    /// standard time.
+    offset_ms: Option<f64>,
+}
+
+/// Gaps between consecutive sends as the run achieved them, next to the
+/// `--interval` asked for, in milliseconds.
+#[derive(Serialize, Deserialize, Clone)]
+struct IntervalData {
+    configured: u64,
+    min: f64,
+    avg: f64,
+    max: f64,
+}
+
+/// Where the time of the `--http` requests went, in milliseconds, averaged
+/// over the answered ones.
+#[derive(Serialize, Deserialize, Clone)]
+struct HttpData {
+    /// The lookup of the target at startup; null for an IP address.
+    dns_ms: Option<f64>,
+    /// Null until a request was answered.
+    connect_ms: Option<f64>,
+    ttfb_ms: Option<f64>,
+}
+
+/// A stretch of the run during which this host had no network, from the
+/// first to the last packet lost to it, in Unix microseconds.
+#[derive(Serialize, Deserialize, Clone)]
+struct DownPeriodData {
+    started_at: u64,
+    ended_at: u64,
+    packets: u32,
+}
+
+/// Loss and latency of the packets one `--source-rotate` address sent.
+#[derive(Serialize, Deserialize, Clone)]
+struct SourceData {
+    source: IpAddr,
+    latency: i64,
+    packetloss: f64,
+    packets_sent: u32,
+    packets_received: u32,
+}
+
+/// Loss and latency of the packets of one `--size-sweep` payload size.
+#[derive(Serialize, Deserialize, Clone)]
+struct SizeData {
+    size: usize,
+    latency: i64,
+    packetloss: f64,
+    packets_sent: u32,
+    packets_received: u32,
+}
+
+/// Replies from one source address, for `--anycast`.
+#[derive(Serialize, Deserialize, Clone)]
+struct ResponderData {
+    source: IpAddr,
+    replies: u32,
+    /// Percentage of all replies.
+    share: f64,
+    latency: i64,
+}
+
+/// Why a packet went unanswered.
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum FailureKind {
+    /// No answer within `--timeout`.
+    Timeout,
+    /// The network answered with an ICMP error (unreachable, time exceeded).
+    IcmpError,
+    /// A router needed to fragment the request but the DF bit forbade it.
+    TooBig,
+    /// The probe could not be sent, or the TCP connection failed locally
+    /// or was refused.
+    SendError,
+    /// This host had no usable network: the interface was down or there
+    /// was no route to the target.
+    NetworkDown,
+}
+
+impl FailureKind {
+    fn name(self) -> &'static str {
+        match self {
+            FailureKind::Timeout => "timeout",
+            FailureKind::IcmpError => "icmp_error",
+            FailureKind::TooBig => "too_big",
+            FailureKind::SendError => "send_error",
+            FailureKind::NetworkDown => "network_down",
+        }
+    }
+
+    fn from_name(name: &str) -> Option<Self> {
+        [
+            FailureKind::Timeout,
+            FailureKind::IcmpError,
+            FailureKind::TooBig,
+            FailureKind::SendError,
+            FailureKind::NetworkDown,
+        ]
+        .into_iter()
+            .find(|kind| kind.name() == name)
+    }
+}
+
+impl From<&PingError> for FailureKind {
+    fn from(e: &PingError) -> Self {
+        match e {
+            // A late reply is not a loss; it only lands here if not handled as one.
+            PingError::Timeout | PingError::Late { .. } => FailureKind::Timeout,
+            PingError::Icmp { .. } => FailureKind::IcmpError,
+            PingError::TooBig { .. } => FailureKind::TooBig,
+            PingError::Io(e) if matches!(e.kind(), std::io::ErrorKind::NetworkDown | std::io::ErrorKind::NetworkUnreachable) => {
+                FailureKind::NetworkDown
+            }
+            PingError::Io(_) | PingError::Duplicate => FailureKind::SendError,
+        }
+    }
+}
+
+/// Consecutive packets that failed because this host had no network.
+#[derive(Clone)]
+struct DownPeriod {
+    started_at: SystemTime,
+    ended_at: SystemTime,
+    packets: u32,
+    /// No packet has got through since.
+    open: bool,
+}
+
+impl DownPeriod {
+    fn data(&self) -> DownPeriodData {
+        let unix_us = |at: SystemTime| at.duration_since(UNIX_EPOCH).unwrap_or_default().as_micros() as u64;
+        DownPeriodData {
+            started_at: unix_us(self.started_at),
+            ended_at: unix_us(self.ended_at),
+            packets: self.packets,
+        }
+    }
+}
+
+/// Packets that share a `key`: a `--source-rotate` address for
+/// `--per-source-stats`, or a `--size-sweep` payload size.
+#[derive(Clone)]
+struct Tally<K> {
+    key: K,
+    sent: u32,
+    received: u32,
+    total_rtt: Duration,
+    measured: u32,
+}
+
+impl<K: Copy + PartialEq> Tally<K> {
+    /// The tally for `key` in `tallies`, added on first use.
+    fn of(tallies: &mut Vec<Tally<K>>, key: K) -> &mut Tally<K> {
+        let index = match tallies.iter().position(|tally| tally.key == key) {
+            Some(index) => index,
+            None => {
+                tallies.push(Tally {
+                    key,
+                    sent: 0,
+                    received: 0,
+                    total_rtt: Duration::ZERO,
+                    measured: 0,
+                });
+                tallies.len() - 1
+            }
+        };
+        &mut tallies[index]
+    }
+
+    /// A reply; `rtt` is None when the RTT is not trusted.
+    fn record_reply(&mut self, rtt: Option<Duration>) {
+        self.received += 1;
+        if let Some(rtt) = rtt {
+            self.total_rtt += rtt;
+            self.measured += 1;
+        }
+    }
+
+    fn latency(&self) -> i64 {
+        match self.measured {
+            0 => 0,
+            measured => (self.total_rtt / measured).as_millis() as i64,
+        }
+    }
+
+    fn packetloss(&self) -> f64 {
+        match self.sent {
+            0 => 100.0,
+            sent => (sent - self.received) as f64 / sent as f64 * 100.0,
+        }
+    }
+}
+
+impl Tally<IpAddr> {
+    fn source_data(&self) -> SourceData {
+        SourceData {
+            source: self.key,
+            latency: self.latency(),
+            packetloss: self.packetloss(),
+            packets_sent: self.sent,
+            packets_received: self.received,
+        }
+    }
+}
+
+impl Tally<usize> {
+    fn size_data(&self) -> SizeData {
+        SizeData {
+            size: self.key,
+            latency: self.latency(),
+            packetloss: self.packetloss(),
+            packets_sent: self.sent,
+            packets_received: self.received,
+        }
+    }
+}
+
+/// Replies from one source address.
+#[derive(Clone)]
+struct Responder {
+    source: IpAddr,
+    replies: u32,
+    total_rtt: Duration,
+}
+
+impl Responder {
+    fn data(&self, received: u32) -> ResponderData {
+        ResponderData {
+            source: self.source,
+            replies: self.replies,
+            share: self.replies as f64 / received.max(1) as f64 * 100.0,
+            latency: (self.total_rtt / self.replies.max(1)).as_millis() as i64,
+        }
+    }
+}
+
+/// Reply sources tracked per target.
+const MAX_RESPONDERS: usize = 256;
+
+/// Initial TTLs in common use: 64 (Linux, macOS, the BSDs), 128 (Windows)
+/// and 255 (most routers and network appliances).
+const INITIAL_TTLS: [u8; 3] = [64, 128, 255];
+
+/// Routers between us and the sender of a reply that arrived with `ttl`,
+/// assuming it started at the nearest common initial TTL at or above it.
+fn hop_count(ttl: u8) -> u8 {
+    let initial = INITIAL_TTLS.into_iter().find(|initial| ttl <= *initial).unwrap_or(u8::MAX);
+    initial - ttl
+}
+
+/// A received reply kept for percentiles and exemplars.
+#[derive(Clone)]
+struct Sample {
+    rtt: Duration,
+    seq: u32,
+    at: SystemTime,
+}
+
+/// Keep every `every`-th sample, counting from the first.
+fn thin(samples: &mut Vec<Sample>, every: usize) {
+    let mut position = 0usize;
+    samples.retain(|_| {
+        position += 1;
+        position.is_multiple_of(every)
+    });
+}
+
+#[derive(Clone)]
+struct PingStats {
+    sent: u32,
+    received: u32,
+    total_rtt: Duration,
+    /// Replies with a trusted RTT, all of them in `total_rtt`.
+    measured: u32,
+    /// RTTs kept for percentiles and exemplars: every `sample_stride`-th
+    /// measured reply, at most `max_samples` of them.
+    samples: Vec<Sample>,
+    sample_stride: u32,
+    max_samples: usize,
+    /// Measured replies per `prometheus::RTT_BUCKETS` bucket, the last one
+    /// above the largest bound. Exact however far `samples` is decimated.
+    rtt_buckets: [u32; prometheus::RTT_BUCKETS.len() + 1],
+    /// Fastest and slowest measured reply.
+    rtt_range: Option<(Duration, Duration)>,
+    timestamp_mismatches: u32,
+    corrupted_replies: u32,
+    /// Highest sequence answered so far, and the replies that came after
+    /// one to a later request.
+    highest_seq: Option<u32>,
+    reordered: u32,
+    /// Replies within `--late-window` after their timeout.
+    late_replies: u32,
+    /// Replies from outside `--expect-source`.
+    source_mismatches: u32,
+    /// Losses by cause; together with the source mismatches they add up to
+    /// `sent - received`.
+    timeouts: u32,
+    icmp_errors: u32,
+    too_big: u32,
+    send_errors: u32,
+    network_down: u32,
+    /// Runs of consecutive packets lost to `FailureKind::NetworkDown`.
+    local_network_down: Vec<DownPeriod>,
+    /// Smallest next-hop MTU reported by a fragmentation-needed error.
+    path_mtu: Option<u16>,
+    consecutive_failures: u32,
+    /// When the current run of lost packets began, and the longest run so
+    /// far with when it began.
+    loss_streak_started: Option<SystemTime>,
+    max_loss_streak: Option<(u32, SystemTime)>,
+    /// Interval currently in effect under `--down-backoff`.
+    backoff: Duration,
+    /// Local address the kernel chose for this target.
+    source_address: Option<IpAddr>,
+    socket_mode: Option<SocketMode>,
+    effective_ttl: Option<u8>,
+    /// How long resolving the target took, for `--http`.
+    lookup: Option<Duration>,
+    /// Handshake and time-to-first-byte totals of the answered `--http`
+    /// requests, and how many there were.
+    http_phases: Option<(Duration, Duration, u32)>,
+    /// RTT of the previous reply, for the jitter estimate.
+    last_rtt: Option<Duration>,
+    /// RFC 3550 interarrival jitter, in milliseconds.
+    jitter: f64,
+    clock_step_detected: bool,
+    /// Replies from a non-loopback target faster than `PLAUSIBLE_RTT_FLOOR`.
+    implausible_rtts: u32,
+    /// First and last reply arrival, for the `--bandwidth` estimate.
+    arrivals: Option<(Instant, Instant)>,
+    last_send: Option<Instant>,
+    /// Shortest, longest and total gap between sends, and how many.
+    send_gaps: Option<(Duration, Duration, Duration, u32)>,
+    /// Per `--source-rotate` address, in the order first used.
+    per_source: Vec<Tally<IpAddr>>,
+    /// Per `--size-sweep` payload size, in the order first used.
+    by_size: Vec<Tally<usize>>,
+    /// `--timestamp-icmp` clock fields of the fastest reply, with its RTT.
+    remote_clock: Option<(Duration, RemoteClockData)>,
+    /// Hops to the target as inferred from the latest reply TTL, and how
+    /// often that changed from one reply to the next.
+    hop_count: Option<u8>,
+    hop_count_changes: u32,
+    /// Who answered the measured replies, in order of first reply, up to
+    /// `MAX_RESPONDERS` of them.
+    responders: Vec<Responder>,
+    timeline: Vec<Transition>,
+    /// What the target resolved to at the start, and last.
+    resolved: Vec<IpAddr>,
+    latest_resolved: Vec<IpAddr>,
+    resolution_changes: u32,
+    /// Packets in a row whose health differs from the current one: that
+    /// health, since when, and how many.
+    health_streak: Option<(Status, SystemTime, u32)>,
+}
+
+impl PingStats {
+    fn new() -> Self {
+        Self {
+            sent: 0,
+            received: 0,
+            total_rtt: Duration::ZERO,
+            measured: 0,
+            samples: Vec::new(),
+            sample_stride: 1,
+            max_samples: usize::MAX,
+            rtt_buckets: [0; prometheus::RTT_BUCKETS.len() + 1],
+            rtt_range: None,
+            timestamp_mismatches: 0,
+            corrupted_replies: 0,
+            highest_seq: None,
+            reordered: 0,
+            late_replies: 0,
+            source_mismatches: 0,
+            timeouts: 0,
+            icmp_errors: 0,
+            too_big: 0,
+            send_errors: 0,
+            network_down: 0,
+            local_network_down: Vec::new(),
+            path_mtu: None,
+            consecutive_failures: 0,
+            loss_streak_started: None,
+            max_loss_streak: None,
+            backoff: Duration::ZERO,
+            source_address: None,
+            socket_mode: None,
+            effective_ttl: None,
+            lookup: None,
+            http_phases: None,
+            last_rtt: None,
+            jitter: 0.0,
+            clock_step_detected: false,
+            implausible_rtts: 0,
+            arrivals: None,
+            last_send: None,
+            send_gaps: None,
+            per_source: Vec::new(),
+            by_size: Vec::new(),
+            timeline: Vec::new(),
+            resolved: Vec::new(),
+            latest_resolved: Vec::new(),
+            resolution_changes: 0,
+            health_streak: None,
+            remote_clock: None,
+            hop_count: None,
+            hop_count_changes: 0,
+            responders: Vec::new(),
+        }
+    }
+
+    fn record_reply_ttl(&mut self, ttl: u8) {
+        let hops = hop_count(ttl);
+        if self.hop_count.is_some_and(|previous| previous != hops) {
+            self.hop_count_changes += 1;
+        }
+        self.hop_count = Some(hops);
+    }
+
+    fn record_responder(&mut self, source: IpAddr, rtt: Duration) {
+        let full = self.responders.len() >= MAX_RESPONDERS;
+        match self.responders.iter_mut().find(|responder| responder.source == source) {
+            Some(responder) => {
+                responder.replies += 1;
+                responder.total_rtt += rtt;
+            }
+            // A target answering from more addresses than this is not one
+            // load-balanced service; the rest go uncounted.
+            None if full => {}
+            None => self.responders.push(Responder { source, replies: 1, total_rtt: rtt }),
+        }
+    }
+
+    /// The responders with the most replies first.
+    fn responder_data(&self) -> Vec<ResponderData> {
+        let mut responders: Vec<ResponderData> = self.responders.iter().map(|r| r.data(self.received)).collect();
+        responders.sort_by_key(|responder| std::cmp::Reverse(responder.replies));
+        responders
+    }
+
+    fn record_remote_clock(&mut self, rtt: Duration, clock: RemoteClockData) {
+        if self.remote_clock.is_none_or(|(best, _)| rtt < best) {
+            self.remote_clock = Some((rtt, clock));
+        }
+    }
+
+    /// Count a packet against its source when it goes out, so packets still
+    /// in flight when the run is cut short count as lost there too.
+    fn record_source_sent(&mut self, source: IpAddr) {
+        Tally::of(&mut self.per_source, source).sent += 1;
+    }
+
+    /// A reply to a packet sent from `source`; `rtt` is None when the RTT
+    /// is not trusted.
+    fn record_source_reply(&mut self, source: IpAddr, rtt: Option<Duration>) {
+        Tally::of(&mut self.per_source, source).record_reply(rtt);
+    }
+
+    /// Count a packet against its payload size when it goes out.
+    fn record_size_sent(&mut self, size: usize) {
+        Tally::of(&mut self.by_size, size).sent += 1;
+    }
+
+    fn record_size_reply(&mut self, size: usize, rtt: Option<Duration>) {
+        Tally::of(&mut self.by_size, size).record_reply(rtt);
+    }
+
+    /// More than a tenth of the measured replies came back implausibly fast.
+    fn suspicious_timing(&self) -> bool {
+        self.implausible_rtts > 0 && self.implausible_rtts * 10 > self.measured
+    }
+
+    fn record_send(&mut self, at: Instant) {
+        let Some(last) = self.last_send.replace(at) else {
+            return;
+        };
+        let gap = at.duration_since(last);
+        self.send_gaps = Some(match self.send_gaps {
+            Some((min, max, total, count)) => (min.min(gap), max.max(gap), total + gap, count + 1),
+            None => (gap, gap, gap, 1),
+        });
+    }
+
+    fn interval_data(&self, configured: u64) -> Option<IntervalData> {
+        let ms = |gap: Duration| gap.as_micros() as f64 / 1000.0;
+        self.send_gaps.map(|(min, max, total, count)| IntervalData {
+            configured,
+            min: ms(min),
+            avg: ms(total / count),
+            max: ms(max),
+        })
+    }
+
+    fn record_http(&mut self, connect: Duration, ttfb: Duration) {
+        let (total_connect, total_ttfb, count) = self.http_phases.unwrap_or_default();
+        self.http_phases = Some((total_connect + connect, total_ttfb + ttfb, count + 1));
+    }
+
+    fn http_data(&self) -> HttpData {
+        let ms = |phase: Duration| phase.as_micros() as f64 / 1000.0;
+        HttpData {
+            dns_ms: self.lookup.map(ms),
+            connect_ms: self.http_phases.map(|(connect, _, count)| ms(connect / count)),
+            ttfb_ms: self.http_phases.map(|(_, ttfb, count)| ms(ttfb / count)),
+        }
+    }
+
+    fn record_arrival(&mut self, at: Instant) {
+        self.arrivals = Some(match self.arrivals {
+            Some((first, last)) => (first.min(at), last.max(at)),
+            None => (at, at),
+        });
+    }
+
+    /// Bits per second the replies arrived at, counting the IP and ICMP
+    /// headers of every packet: the later replies of a back-to-back burst
+    /// queue behind the earlier ones at the narrowest link, so their spread
+    /// is the time that link needs to carry them.
+    fn bandwidth_bps(&self, size: usize) -> Option<f64> {
+        let (first, last) = self.arrivals?;
+        let spread = last.duration_since(first).as_secs_f64();
+        let bits = ((self.measured as usize).checked_sub(1)? * (size + icmp::ICMP_HEADER_LEN + IPV4_HEADER_LEN) * 8) as f64;
+        (spread > 0.0).then(|| bits / spread)
+    }
+
+    fn packet_loss(&self) -> f64 {
+        if self.sent == 0 {
+            return 100.0;
+        }
+        ((self.sent - self.received) as f64 / self.sent as f64) * 100.0
+    }
+
+    /// Average over the measured replies; replies received during a clock
+    /// step are counted but not measured.
+    fn avg_rtt(&self) -> Duration {
+        if self.measured == 0 {
+            return Duration::ZERO;
+        }
+        self.total_rtt / self.measured
+    }
+
+    /// Nearest-rank percentile of the retained RTTs, `p` in 0..=100.
+    fn percentile(&self, p: f64) -> Duration {
+        if self.samples.is_empty() {
+            return Duration::ZERO;
+        }
+        let mut sorted: Vec<Duration> = self.samples.iter().map(|s| s.rtt).collect();
+        sorted.sort_unstable();
+        let rank = ((p / 100.0) * sorted.len() as f64).ceil() as usize;
+        sorted[rank.clamp(1, sorted.len()) - 1]
+    }
+
+    fn update_with_success(&mut self, rtt: Duration, seq: u32) {
+        self.sent += 1;
+        self.received += 1;
+        self.total_rtt += rtt;
+        self.measured += 1;
+        let bucket = prometheus::RTT_BUCKETS
+            .iter()
+            .position(|le| rtt.as_secs_f64() <= *le)
+            .unwrap_or(prometheus::RTT_BUCKETS.len());
+        self.rtt_buckets[bucket] += 1;
+        self.rtt_range = Some(match self.rtt_range {
+            Some((min, max)) => (min.min(rtt), max.max(rtt)),
+            None => (rtt, rtt),
+        });
+        if self.measured.is_multiple_of(self.sample_stride) {
+            self.samples.push(Sample {
+                rtt,
+                seq,
+                at: SystemTime::now(),
+            });
+            if self.samples.len() >= self.max_samples {
+                self.decimate();
+            }
+        }
+        self.end_loss_streak();
+        match self.highest_seq {
+            Some(highest) if seq < highest => self.reordered += 1,
+            _ => self.highest_seq = Some(seq),
+        }
+
+        // RFC 3550 section 6.4.1: J += (|D(i-1,i)| - J) / 16, where D is the
+        // change in transit time between consecutive replies.
+        if let Some(last_rtt) = self.last_rtt {
+            let d = rtt.abs_diff(last_rtt).as_secs_f64() * 1000.0;
+            self.jitter += (d - self.jitter) / 16.0;
+        }
+        self.last_rtt = Some(rtt);
+    }
+
+    /// Halve the retained samples, keeping every second one, and retain
+    /// only every second reply from now on. The samples kept are always the
+    /// replies whose position among the measured ones is a multiple of
+    /// `sample_stride`, so they stay evenly spread over the whole run.
+    fn decimate(&mut self) {
+        thin(&mut self.samples, 2);
+        self.sample_stride *= 2;
+    }
+
+    /// Pool a later `--rounds` round into these stats: the packet counts,
+    /// loss causes, RTTs and samples add up. Breakdowns such as per-source,
+    /// per-size and responders stay those of the first round.
+    fn absorb(&mut self, round: &PingStats) {
+        self.sent += round.sent;
+        self.received += round.received;
+        self.total_rtt += round.total_rtt;
+        // Weighted by the replies each estimate rests on.
+        if self.measured + round.measured > 0 {
+            self.jitter = (self.jitter * self.measured as f64 + round.jitter * round.measured as f64)
+                / (self.measured + round.measured) as f64;
+        }
+        self.measured += round.measured;
+        // Both sides thinned to the coarser stride first, or the less
+        // decimated one would weigh more than its share of the replies.
+        let stride = self.sample_stride.max(round.sample_stride);
+        thin(&mut self.samples, (stride / self.sample_stride) as usize);
+        self.sample_stride = stride;
+        let mut round_samples = round.samples.clone();
+        thin(&mut round_samples, (stride / round.sample_stride) as usize);
+        self.samples.extend(round_samples);
+        while self.samples.len() >= self.max_samples {
+            self.decimate();
+        }
+        for (bucket, count) in self.rtt_buckets.iter_mut().zip(round.rtt_buckets) {
+            *bucket += count;
+        }
+        self.rtt_range = match (self.rtt_range, round.rtt_range) {
+            (Some((min, max)), Some((round_min, round_max))) => Some((min.min(round_min), max.max(round_max))),
+            (range, round_range) => range.or(round_range),
+        };
+        self.timestamp_mismatches += round.timestamp_mismatches;
+        self.corrupted_replies += round.corrupted_replies;
+        self.reordered += round.reordered;
+        self.highest_seq = round.highest_seq;
+        self.late_replies += round.late_replies;
+        self.source_mismatches += round.source_mismatches;
+        self.timeouts += round.timeouts;
+        self.icmp_errors += round.icmp_errors;
+        self.too_big += round.too_big;
+        self.send_errors += round.send_errors;
+        self.network_down += round.network_down;
+        self.local_network_down.extend(round.local_network_down.iter().cloned());
+        self.path_mtu = self.path_mtu.into_iter().chain(round.path_mtu).min();
+        self.http_phases = match (self.http_phases, round.http_phases) {
+            (Some((connect, ttfb, count)), Some((round_connect, round_ttfb, round_count))) => {
+                Some((connect + round_connect, ttfb + round_ttfb, count + round_count))
+            }
+            (phases, round_phases) => phases.or(round_phases),
+        };
+        self.send_gaps = match (self.send_gaps, round.send_gaps) {
+            (Some((min, max, total, count)), Some((round_min, round_max, round_total, round_count))) => {
+                Some((min.min(round_min), max.max(round_max), total + round_total, count + round_count))
+            }
+            (gaps, round_gaps) => gaps.or(round_gaps),
+        };
+        self.consecutive_failures = round.consecutive_failures;
+        self.loss_streak_started = round.loss_streak_started;
+        // Streaks do not span rounds, the pause between them breaks them.
+        self.max_loss_streak = match (self.max_loss_streak, round.max_loss_streak) {
+            (Some(streak), Some(round_streak)) if round_streak.0 > streak.0 => Some(round_streak),
+            (streak, round_streak) => streak.or(round_streak),
+        };
+        self.clock_step_detected |= round.clock_step_detected;
+        self.implausible_rtts += round.implausible_rtts;
+        for transition in &round.timeline {
+            if self.timeline.last().map(|last| last.status) != Some(transition.status) {
+                if self.timeline.len() == MAX_TIMELINE {
+                    self.timeline.remove(0);
+                }
+                self.timeline.push(*transition);
+            }
+        }
+        self.latest_resolved = round.latest_resolved.clone();
+        self.resolution_changes += round.resolution_changes;
+    }
+
+    /// A reply that came after its timeout, within `--late-window`. The
+    /// packet is not lost, but its RTT stays out of the latency figures,
+    /// which describe the replies that came in time.
+    fn update_with_late(&mut self) {
+        self.sent += 1;
+        self.received += 1;
+        self.late_replies += 1;
+        self.end_loss_streak();
+    }
+
+    /// A reply that arrived, but whose RTT cannot be trusted.
+    fn update_with_unmeasured(&mut self) {
+        self.sent += 1;
+        self.received += 1;
+        self.end_loss_streak();
+        self.clock_step_detected = true;
+    }
+
+    fn update_with_failure(&mut self) {
+        self.sent += 1;
+        self.consecutive_failures += 1;
+        let started = *self.loss_streak_started.get_or_insert_with(SystemTime::now);
+        if self.max_loss_streak.is_none_or(|(longest, _)| self.consecutive_failures > longest) {
+            self.max_loss_streak = Some((self.consecutive_failures, started));
+        }
+    }
+
+    fn end_loss_streak(&mut self) {
+        self.consecutive_failures = 0;
+        self.loss_streak_started = None;
+    }
+
+    /// Share of the replies that arrived out of order, from 0 to 1.
+    fn reorder_ratio(&self) -> f64 {
+        match self.received {
+            0 => 0.0,
+            received => self.reordered as f64 / received as f64,
+        }
+    }
+
+    fn record_path_mtu(&mut self, mtu: u16) {
+        self.path_mtu = Some(self.path_mtu.map_or(mtu, |known| known.min(mtu)));
+    }
+
+    fn update_with_error(&mut self, kind: FailureKind) {
+        self.update_with_failure();
+        match kind {
+            FailureKind::Timeout => self.timeouts += 1,
+            FailureKind::IcmpError => self.icmp_errors += 1,
+            FailureKind::TooBig => self.too_big += 1,
+            FailureKind::SendError => self.send_errors += 1,
+            FailureKind::NetworkDown => self.network_down += 1,
+        }
+    }
+
+    /// Extend, open or close the current `local_network_down` period for a
+    /// packet that completed at `at`. True when it opened or closed one.
+    fn track_local_network(&mut self, down: bool, at: SystemTime) -> bool {
+        match (self.local_network_down.last_mut(), down) {
+            (Some(period), true) if period.open => {
+                period.ended_at = period.ended_at.max(at);
+                period.packets += 1;
+                false
+            }
+            (Some(period), false) if period.open => {
+                period.open = false;
+                true
+            }
+            (_, true) => {
+                self.local_network_down.push(DownPeriod {
+                    started_at: at,
+                    ended_at: at,
+                    packets: 1,
+                    open: true,
+                });
+                true
+            }
+            (_, false) => false,
+        }
+    }
+
+    /// A `--reresolve-every` lookup. True when the addresses differ from
+    /// the last ones, in any order.
+    fn record_resolution(&mut self, mut addrs: Vec<IpAddr>) -> bool {
+        addrs.sort();
+        addrs.dedup();
+        let mut last = self.latest_resolved.clone();
+        last.sort();
+        last.dedup();
+        if addrs == last {
+            return false;
+        }
+        self.resolution_changes += 1;
+        self.latest_resolved = addrs;
+        true
+    }
+
+    /// Count a packet of health `status`, completed at `at`, toward the
+    /// timeline. The health changes only after `confirm` packets in a row
+    /// agree, taking effect from the first of them, so brief flapping
+    /// leaves no trace.
+    fn record_health(&mut self, status: Status, at: SystemTime, confirm: u32) {
+        if self.timeline.last().map(|transition| transition.status) == Some(status) {
+            self.health_streak = None;
+            return;
+        }
+        let (_, since, packets) = match &mut self.health_streak {
+            Some(streak) if streak.0 == status => streak,
+            streak => streak.insert((status, at, 0)),
+        };
+        *packets += 1;
+        if *packets < confirm {
+            return;
+        }
+        let at = since.duration_since(UNIX_EPOCH).unwrap_or_default().as_micros() as u64;
+        self.health_streak = None;
+        if self.timeline.len() == MAX_TIMELINE {
+            self.timeline.remove(0);
+        }
+        self.timeline.push(Transition { at, status });
+    }
+}
+
+/// Token bucket shared by every target loop, so the total send rate stays
+/// under `--max-pps` however the per-target schedules line up. The bucket
+/// holds a single token to keep sends paced rather than bursty.
+struct RateLimiter {
+    rate: f64,
+    state: Mutex<(f64, Instant)>,
+}
+
+impl RateLimiter {
+    fn new(rate: f64) -> Self {
+        Self {
+            rate,
+            state: Mutex::new((1.0, Instant::now())),
+        }
+    }
+
+    async fn acquire(&self) {
+        loop {
+            let wait = {
+                let mut state = self.state.lock().await;
+                let (tokens, last) = &mut *state;
+                let now = Instant::now();
+                *tokens = (*tokens + now.duration_since(*last).as_secs_f64() * self.rate).min(1.0);
+                *last = now;
+                if *tokens >= 1.0 {
+                    *tokens -= 1.0;
+                    return;
+                }
+                Duration::from_secs_f64((1.0 - *tokens) / self.rate)
+            };
+            tokio::time::sleep(wait).await;
+        }
+    }
+}
+
+/// Bound on packets sent but not yet answered or timed out (`--window`).
+/// Each in-flight packet holds a permit until its task finishes.
+#[derive(Clone)]
+struct Window(Arc<Semaphore>);
+
+impl Window {
+    fn new(size: usize) -> Self {
+        Self(Arc::new(Semaphore::new(size)))
+    }
+
+    async fn reserve(&self) -> OwnedSemaphorePermit {
+        self.0.clone().acquire_owned().await.expect("window semaphore is never closed")
+    }
+}
+
+fn parse_positive_f64(value: &str) -> Result<f64, String> {
+    match value.parse::<f64>() {
+        Ok(v) if v > 0.0 && v.is_finite() => Ok(v),
+        Ok(_) => Err("must be a positive number".to_string()),
+        Err(e) => Err(e.to_string()),
+    }
+}
+
+/// A duration with an optional `ms`, `s`, `m` or `h` suffix; bare numbers
+/// are seconds.
+fn parse_duration(value: &str) -> Result<Duration, String> {
+    let split = value.find(|c: char| !c.is_ascii_digit() && c != '.').unwrap_or(value.len());
+    let (number, unit) = value.split_at(split);
+    let number: f64 = number.parse().map_err(|_| format!("invalid duration '{}'", value))?;
+    let seconds = match unit {
+        "ms" => number / 1000.0,
+        "" | "s" => number,
+        "m" => number * 60.0,
+        "h" => number * 3600.0,
+        _ => return Err(format!("unknown unit '{}' in duration (use ms, s, m or h)", unit)),
+    };
+    Duration::try_from_secs_f64(seconds)
+        .ok()
+        .filter(|d| !d.is_zero())
+        .ok_or_else(|| "duration must be positive".to_string())
+}
+
+/// Every address `host` resolves to, in resolver order; the first is pinged.
+/// `--dns-server` replaces the system resolver.
+async fn resolve_host(config: &Args, host: &str) -> Result<Vec<IpAddr>, Box<dyn std::error::Error>> {
+    if let Ok(ip) = host.parse::<IpAddr>() {
+        return Ok(vec![ip]);
+    }
+    if config.no_dns {
+        return Err(format!("--no-dns set but target {} is not an IP address", host).into());
+    }
+    if let Some(server) = config.dns_server {
+        return Ok(dns::resolve(host, server, config.vrf.as_deref(), Duration::from_millis(config.timeout)).await?);
+    }
+
+    let addrs: Vec<IpAddr> = lookup_host(format!("{}:0", host)).await?.map(|socket_addr| socket_addr.ip()).collect();
+    if addrs.is_empty() {
+        return Err("Could not resolve hostname".into());
+    }
+    Ok(addrs)
+}
+
+/// What kind of address `ip` is, as `--public-only` and `--private-only`
+/// judge it. An IPv4-mapped IPv6 address is judged as the IPv4 one.
+fn address_scope(ip: IpAddr) -> &'static str {
+    let ip = match ip {
+        IpAddr::V6(v6) => v6.to_ipv4_mapped().map_or(ip, IpAddr::V4),
+        ip => ip,
+    };
+    match ip {
+        _ if ip.is_loopback() => "loopback",
+        _ if ip.is_multicast() => "multicast",
+        _ if ip.is_unspecified() => "unspecified",
+        IpAddr::V4(v4) if v4.is_link_local() => "link-local",
+        IpAddr::V4(v4) if v4.is_private() => "private",
+        // 100.64.0.0/10, carrier-grade NAT (RFC 6598).
+        IpAddr::V4(v4) if v4.octets()[0] == 100 && v4.octets()[1] & 0xc0 == 64 => "shared",
+        IpAddr::V4(v4) if v4.is_broadcast() => "broadcast",
+        IpAddr::V6(v6) if v6.is_unicast_link_local() => "link-local",
+        IpAddr::V6(v6) if v6.is_unique_local() => "private",
+        _ => "public",
+    }
+}
+
+/// Refuse the addresses of a target that `--public-only` or
+/// `--private-only` rules out, naming the first such one.
+fn check_address_scope(config: &Args, addrs: &[IpAddr]) -> Result<(), String> {
+    let refused = |ip: &&IpAddr| match address_scope(**ip) {
+        "public" => config.private_only,
+        "private" | "shared" | "loopback" | "link-local" => config.public_only,
+        _ => config.public_only || config.private_only,
+    };
+    match addrs.iter().find(refused) {
+        Some(ip) => Err(format!(
+            "Resolved to {} ({}), refused by {}",
+            ip,
+            address_scope(*ip),
+            if config.public_only { "--public-only" } else { "--private-only" }
+        )),
+        None => Ok(()),
+    }
+}
+
+/// Where results go besides stdout.
+#[derive(Clone, Default)]
+struct Sinks {
+    statsd: Option<Arc<statsd::StatsdSink>>,
+    syslog: Option<Arc<syslog::Syslog>>,
+    webhook: Option<Arc<webhook::Webhook>>,
+}
+
+/// How packets reach a target.
+#[derive(Clone)]
+enum Probe {
+    Icmp(EchoClient),
+    /// TCP handshake to this port (`--tcp`).
+    Tcp(u16),
+    /// Echo requests handed to another transport, such as a scripted one
+    /// in the tests.
+    #[cfg_attr(not(test), allow(dead_code))]
+    Transport(Arc<dyn PingTransport>),
+}
+
+type PingFuture<'a> = Pin<Box<dyn Future<Output = Result<Echo, PingError>> + Send + 'a>>;
+
+/// Sends one echo request and waits for the answer, the part of the ping
+/// loop that touches the network. Runs use the sockets of `Probe::Icmp` and
+/// `Probe::Tcp`; a test can put its own behind `Probe::Transport` to drive
+/// the loop through scripted replies, timeouts and errors.
+trait PingTransport: Send + Sync {
+    fn ping<'a>(&'a self, ip_addr: IpAddr, ident: u16, sequence: u16, payload: &'a [u8], timeout: Duration) -> PingFuture<'a>;
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn send_single_ping(
+    probe: &Probe,
+    config: &Args,
+    target: &str,
+    ip_addr: IpAddr,
+    source: Option<IpAddr>,
+    ident: u16,
+    sequence: u32,
+    payload: &[u8],
+    timeout_duration: Duration,
+) -> Result<Echo, PingError> {
+    match probe {
+        Probe::Icmp(client) if config.timestamp_icmp => {
+            client.timestamp(ip_addr, ident, sequence as u16, timeout_duration).await
+        }
+        Probe::Icmp(client) => {
+            client
+                .ping(ip_addr, ident, sequence as u16, payload, timeout_duration)
+                .await
+        }
+        Probe::Tcp(port) => {
+            let dest = std::net::SocketAddr::new(ip_addr, *port);
+            let (rtt, handshake) = match &config.http {
+                Some(path) => {
+                    let request = http_request(target, *port, path);
+                    let timing = tcp::http(dest, source, config.source_port, config.mark, config.vrf.as_deref(), timeout_duration, request.as_bytes()).await?;
+                    (timing.connect + timing.ttfb, Some(timing.connect))
+                }
+                None => (tcp::connect(dest, source, config.source_port, config.mark, config.vrf.as_deref(), timeout_duration).await?, None),
+            };
+            Ok(Echo {
+                rtt,
+                handshake,
+                reply: Reply {
+                    received_at: Instant::now(),
+                    source: ip_addr,
+                    payload: Vec::new(),
+                    ttl: None,
+                },
+            })
+        }
+        Probe::Transport(transport) => transport.ping(ip_addr, ident, sequence as u16, payload, timeout_duration).await,
+    }
+}
+
+/// The `--http` request for `path` on `target`, asking the server to close
+/// the connection once it has answered.
+fn http_request(target: &str, port: u16, path: &str) -> String {
+    let host = target::parse(target).host;
+    let host = match (host.contains(':'), port) {
+        (true, 80) => format!("[{}]", host),
+        (true, port) => format!("[{}]:{}", host, port),
+        (false, 80) => host.to_string(),
+        (false, port) => format!("{}:{}", host, port),
+    };
+    format!(
+        "GET {} HTTP/1.1\r\nHost: {}\r\nUser-Agent: peckr/{}\r\nAccept: */*\r\nConnection: close\r\n\r\n",
+        path,
+        host,
+        env!("CARGO_PKG_VERSION")
+    )
+}
+
+/// Check a reply's embedded timestamp against the request it was matched to.
+/// A reply carrying another sequence's payload (a late or duplicated reply)
+/// or an RTT that disagrees with the measured one is a mismatch.
+fn timestamp_matches(echo: &Echo, sequence: u32) -> bool {
+    match icmp::parse_timestamp(&echo.reply.payload) {
+        Some((embedded_sequence, sent_at)) => {
+            let embedded_rtt = echo.reply.received_at.saturating_duration_since(sent_at);
+            embedded_sequence == sequence && embedded_rtt.abs_diff(echo.rtt) <= TIMESTAMP_TOLERANCE
+        }
+        None => false,
+    }
+}
+
+/// Size of the IPv4 header (without options) in front of every ICMP message.
+const IPV4_HEADER_LEN: usize = 20;
+
+/// Inline markers for a reply that breached a threshold on its own: `[HIGH]`
+/// when the RTT exceeds `--max-latency`, `[JITTER]` when it differs from the
+/// previous reply's by more than `--max-jitter`. They go on the per-packet
+/// lines whatever `--format` the results use, in color when stdout is a
+/// terminal and `NO_COLOR` is unset.
+fn threshold_markers(config: &Args, rtt: Duration, previous: Option<Duration>) -> String {
+    use std::io::IsTerminal;
+
+    let ms = |rtt: Duration| rtt.as_secs_f64() * 1000.0;
+    let high = ms(rtt) > config.reply_latency_limit() as f64;
+    let jitter = matches!((config.max_jitter, previous), (Some(max), Some(previous)) if ms(rtt.abs_diff(previous)) > max);
+    let color = std::io::stdout().is_terminal() && std::env::var_os("NO_COLOR").is_none();
+    let mark = |label: &str, code: &str| match color {
+        true => format!(" \x1b[{}m[{}]\x1b[0m", code, label),
+        false => format!(" [{}]", label),
+    };
+    let mut markers = String::new();
+    if high {
+        markers += &mark("HIGH", "1;31");
+    }
+    if jitter {
+        markers += &mark("JITTER", "1;33");
+    }
+    markers
+}
+
+/// Decimal places for a latency in human output: `--precision`, or the
+/// line's own `default`.
+fn precision(config: &Args, default: usize) -> usize {
+    config.precision.map_or(default, usize::from)
+}
+
+/// `bytes` is the ICMP length of the reply, header included, as ping(8) prints it.
+async fn print_ping_result(
+    ip_addr: IpAddr,
+    bytes: usize,
+    sequence: u32,
+    ttl: u8,
+    rtt: Duration,
+    precision: usize,
+    markers: &str,
+) {
+    println!(
+        "{} bytes from {}: icmp_seq={} ttl={} time={:.*} ms{}",
+        bytes,
+        ip_addr,
+        // As on the wire, like ping(8).
+        sequence as u16,
+        ttl,
+        precision,
+        rtt.as_secs_f64() * 1000.0,
+        markers
+    );
+}
+
+async fn print_statistics(config: &Args, target: &str, stats: &PingStats) {
+    println!("\n--- {} ping statistics ---", target);
+    println!(
+        "{} packets transmitted, {} received, {:.1}% packet loss, time {}ms",
+        stats.sent,
+        stats.received,
+        stats.packet_loss(),
+        stats.total_rtt.as_millis()
+    );
+    
+    match (stats.received, config.precision) {
+        (0, _) => {}
+        (_, Some(precision)) => println!("rtt avg = {:.*} ms", precision as usize, stats.avg_rtt().as_secs_f64() * 1000.0),
+        // Whole milliseconds, as it has always been printed.
+        (_, None) => println!("rtt avg = {:.3} ms", stats.avg_rtt().as_millis()),
+    }
+    if stats.corrupted_replies > 0 {
+        println!("WARNING: {} replies came back with a different payload, the path is corrupting data", stats.corrupted_replies);
+    }
+    if let Some(offset) = stats.remote_clock.and_then(|(_, clock)| clock.offset_ms) {
+        println!("remote clock offset = {:+.*} ms", precision(config, 1), offset);
+    }
+}
+
+/// First instant at or after `not_before` that is a whole multiple of
+/// `interval` since the Unix epoch. Every slot is derived from the wall
+/// clock rather than from the previous sleep, so drift never accumulates.
+fn next_aligned(not_before: SystemTime, interval: Duration) -> SystemTime {
+    let since_epoch = not_before.duration_since(UNIX_EPOCH).unwrap_or_default().as_nanos();
+    let interval = interval.as_nanos().max(1);
+    let slot = since_epoch.div_ceil(interval) * interval;
+    UNIX_EPOCH + Duration::from_nanos(slot as u64)
+}
+
+async fn sleep_until_wall(at: SystemTime) {
+    if let Ok(wait) = at.duration_since(SystemTime::now()) {
+        tokio::time::sleep(wait).await;
+    }
+}
+
+/// Largest echo payload that fits in a single IPv4 datagram.
+const MAX_PAYLOAD: usize = 65507 - icmp::ICMP_HEADER_LEN;
+
+/// `--bandwidth` payload unless `--size` is given: the largest packet that
+/// fits a 1500-byte Ethernet MTU unfragmented.
+const BANDWIDTH_SIZE: usize = 1500 - IPV4_HEADER_LEN - icmp::ICMP_HEADER_LEN;
+
+/// Smaller packets spread too little for the arrival gaps to mean anything.
+const MIN_BANDWIDTH_SIZE: usize = 512;
+
+/// Derive the schedule from `--duration`. With an explicit `--count` the
+/// interval becomes duration / count; otherwise the count becomes
+/// duration / interval. Setting all three over-constrains the run.
+fn apply_duration(config: &mut Args, count_given: bool, interval_given: bool) -> Result<(), String> {
+    let Some(duration) = config.duration else {
+        return Ok(());
+    };
+    let millis = duration.as_millis();
+    match (count_given, interval_given) {
+        (true, true) => Err("--duration, --count and --interval together over-constrain the run; drop one".to_string()),
+        (true, false) if config.count == 0 => Err("--duration needs a non-zero --count".to_string()),
+        (true, false) => {
+            config.interval = (millis / config.count as u128) as u64;
+            Ok(())
+        }
+        (false, _) if config.interval == 0 => Err("--duration needs a non-zero --interval".to_string()),
+        (false, _) => {
+            config.count = (millis / config.interval as u128).clamp(1, u32::MAX as u128) as u32;
+            Ok(())
+        }
+    }
+}
+
+/// Under `--no-dns`, refuse before anything is sent any name that would
+/// need a lookup.
+fn check_no_dns(config: &Args) -> Result<(), String> {
+    let targets = config.targets.iter().chain(&config.target_fallback).chain(&config.compare_direct);
+    for target in targets {
+        if target::parse(target).host.parse::<IpAddr>().is_err() {
+            return Err(format!("--no-dns set but target {} is not an IP address", target));
+        }
+    }
+    if let Some(url) = config.targets_from.as_deref().filter(|url| target::parse(url).host.parse::<IpAddr>().is_err()) {
+        return Err(format!("--no-dns set but the --targets-from host of {} is not an IP address", url));
+    }
+    if let Some(statsd) = config.statsd.as_deref().filter(|statsd| statsd.parse::<SocketAddr>().is_err()) {
+        return Err(format!("--no-dns set but --statsd {} is not an IP:PORT address", statsd));
+    }
+    if let Some(webhook) = config.webhook.as_deref().filter(|url| target::parse(url).host.parse::<IpAddr>().is_err()) {
+        return Err(format!("--no-dns set but the --webhook host in {} is not an IP address", webhook));
+    }
+    Ok(())
+}
+
+/// Reject option combinations that would run but produce misleading
+/// results, such as every packet silently expiring at the first hop.
+fn validate_args(config: &Args) -> Result<(), String> {
+    use std::io::IsTerminal;
+
+    if config.no_dns {
+        check_no_dns(config)?;
+    }
+    if config.ttl == 0 {
+        return Err("--ttl 0 would expire every packet at the first hop".to_string());
+    }
+    if config.size > MAX_PAYLOAD {
+        return Err(format!("--size {} exceeds the maximum IPv4 payload of {} bytes", config.size, MAX_PAYLOAD));
+    }
+    if config.timestamp && config.size < TIMESTAMP_LEN && config.size_sweep.is_empty() {
+        return Err(format!("--timestamp needs --size of at least {} bytes", TIMESTAMP_LEN));
+    }
+    for &size in &config.size_sweep {
+        if size > MAX_PAYLOAD {
+            return Err(format!("--size-sweep {} exceeds the maximum IPv4 payload of {} bytes", size, MAX_PAYLOAD));
+        }
+        if config.timestamp && size < TIMESTAMP_LEN {
+            return Err(format!("--timestamp needs every --size-sweep size to be at least {} bytes", TIMESTAMP_LEN));
+        }
+    }
+    if !(0.0..=100.0).contains(&config.max_loss) {
+        return Err("--max-loss must be a percentage between 0 and 100".to_string());
+    }
+    if config.timeout == 0 {
+        return Err("--timeout 0 would count every packet as lost".to_string());
+    }
+    if config.adaptive_timeout.is_some() && config.min_timeout > config.timeout {
+        return Err("--min-timeout must not exceed --timeout".to_string());
+    }
+    if config.align && config.interval == 0 {
+        return Err("--align needs a non-zero --interval".to_string());
+    }
+    if config.down_after == 0 {
+        return Err("--down-after must be at least 1".to_string());
+    }
+    if config.down_backoff && config.max_backoff < config.interval {
+        return Err("--max-backoff must not be shorter than --interval".to_string());
+    }
+    if let Some(warn_loss) = config.warn_loss {
+        if !(0.0..=config.max_loss).contains(&warn_loss) {
+            return Err("--warn-loss must be between 0 and --max-loss".to_string());
+        }
+    }
+    if config.warn_latency.zip(config.max_avg_latency()).is_some_and(|(warn, max)| warn > max) {
+        return Err("--warn-latency must not exceed --max-latency".to_string());
+    }
+    if config.tcp.is_some() {
+        if config.mtr || config.pcap.is_some() {
+            return Err("--mtr and --pcap trace ICMP and cannot be combined with --tcp".to_string());
+        }
+        if config.size > 0 || config.timestamp {
+            return Err("--size and --timestamp shape ICMP payloads and do not apply to --tcp".to_string());
+        }
+    }
+    if config.tui && !std::io::stderr().is_terminal() {
+        return Err("--tui draws on stderr, which is not a terminal".to_string());
+    }
+    if config.source_port.is_some() && config.tcp.is_none() {
+        return Err("--source-port requires --tcp".to_string());
+    }
+    if config.target_fallback.is_some() && config.targets.len() != 1 {
+        return Err("--target-fallback backs up a single target".to_string());
+    }
+    if !config.source_rotate.is_empty() {
+        if config.mtr || config.replay.is_some() {
+            return Err("--source-rotate cannot be combined with --mtr or --replay".to_string());
+        }
+        if config.tcp.is_none() && config.source_rotate.iter().any(IpAddr::is_ipv6) {
+            return Err("--source-rotate addresses must be IPv4 for ICMP probes".to_string());
+        }
+    }
+    if config.syslog_packets && !config.syslog {
+        return Err("--syslog-packets requires --syslog".to_string());
+    }
+    if config.max_jitter.is_some_and(|max_jitter| !(0.0..).contains(&max_jitter)) {
+        return Err("--max-jitter must not be negative".to_string());
+    }
+    if config.max_reorder.is_some_and(|max_reorder| !(0.0..=100.0).contains(&max_reorder)) {
+        return Err("--max-reorder must be a percentage between 0 and 100".to_string());
+    }
+    let limits = [config.max_jitter, config.max_ttfb.map(|max_ttfb| max_ttfb as f64), config.max_reorder];
+    if config.success_expr.is_some() && (!config.percentile_limits().is_empty() || limits.iter().any(Option::is_some)) {
+        return Err(
+            "--max-p95, --max-latency pNN:MS, --max-jitter, --max-ttfb and --max-reorder do not apply with --success-expr".to_string(),
+        );
+    }
+    if config.http.is_some() && config.targets.iter().any(|target| target.to_ascii_lowercase().starts_with("https://")) {
+        return Err("--http speaks plain HTTP only; TLS (https://) targets are not supported".to_string());
+    }
+    if config.http.as_ref().is_some_and(|path| !path.starts_with('/') || path.contains(char::is_whitespace)) {
+        return Err("--http PATH must start with '/' and contain no whitespace".to_string());
+    }
+    if config.fail_fast && config.success_expr.is_some() {
+        return Err("--fail-fast works with --max-loss/--max-latency, not --success-expr".to_string());
+    }
+    if config.event_log_keep == 0 {
+        return Err("--event-log-keep must be at least 1".to_string());
+    }
+    if config.rounds == 0 {
+        return Err("--rounds must be at least 1".to_string());
+    }
+    if config.warmup_duration.is_some() && config.interval == 0 {
+        return Err("--warmup-duration needs a non-zero --interval".to_string());
+    }
+    if config.max_packets == Some(0) {
+        return Err("--max-packets must be at least 1".to_string());
+    }
+    if config.fail_fast && config.count == 0 {
+        return Err("--fail-fast needs a --count to decide early".to_string());
+    }
+    if config.replay.is_some() && (config.mtr || config.await_healthy || config.wait_down) {
+        return Err("--replay re-summarizes a recorded run and cannot be combined with --mtr, --await-healthy or --wait-down".to_string());
+    }
+    if config.timestamp_icmp && (config.tcp.is_some() || config.mtr || config.bandwidth) {
+        return Err("--timestamp-icmp cannot be combined with --tcp, --mtr or --bandwidth".to_string());
+    }
+    if config.timestamp_icmp && (config.size > 0 || config.timestamp) {
+        return Err("--timestamp-icmp requests carry only clock fields; --size and --timestamp do not apply".to_string());
+    }
+    if config.bandwidth {
+        if config.tcp.is_some() || config.mtr {
+            return Err("--bandwidth times ICMP bursts and cannot be combined with --tcp or --mtr".to_string());
+        }
+        if config.interval != 0 || config.align {
+            return Err("--bandwidth sends a back-to-back burst and needs --interval 0 without --align".to_string());
+        }
+        if config.size < MIN_BANDWIDTH_SIZE {
+            return Err(format!("--bandwidth needs --size of at least {} bytes", MIN_BANDWIDTH_SIZE));
+        }
+        if config.count < 2 {
+            return Err("--bandwidth needs a --count of at least 2".to_string());
+        }
+        if config.window < config.count as usize {
+            return Err("--bandwidth sends the whole --count at once and needs a --window of at least --count".to_string());
+        }
+    }
+    if config.wait_down && (config.await_healthy || config.mtr) {
+        return Err("--wait-down cannot be combined with --await-healthy or --mtr".to_string());
+    }
+    if config.await_healthy && (config.count == 0 || config.mtr) {
+        return Err("--await-healthy needs a non-zero --count as its window and cannot be combined with --mtr".to_string());
+    }
+    if config.until_received == Some(0) {
+        return Err("--until-received must be at least 1".to_string());
+    }
+    if config.max_samples < 2 {
+        return Err("--max-samples must be at least 2".to_string());
+    }
+    if config.window == 0 {
+        return Err("--window must be at least 1".to_string());
+    }
+    if config.report_every == Some(0) {
+        return Err("--report-every must be at least 1 second".to_string());
+    }
+    if config.summary_out.is_some()
+        && matches!(config.format, OutputFormat::Human | OutputFormat::None)
+    {
+        return Err("--summary-out writes a json, prometheus, fping, msgpack or grafana summary".to_string());
+    }
+    if config.exemplars && config.format != OutputFormat::Prometheus {
+        return Err("--exemplars requires --format prometheus".to_string());
+    }
+    if config.mtr {
+        if config.targets.len() > 1 {
+            return Err("--mtr traces a single target".to_string());
+        }
+        if config.max_hops == 0 {
+            return Err("--max-hops must be at least 1".to_string());
+        }
+        if !matches!(config.format, OutputFormat::Json | OutputFormat::Human | OutputFormat::None) {
+            return Err("--mtr supports --format json or human".to_string());
+        }
+        if config.baseline.is_some() || config.save_baseline.is_some() {
+            return Err("--baseline and --save-baseline do not apply to --mtr".to_string());
+        }
+    }
+    Ok(())
+}
+
+/// Interval to wait before the next send. With `--down-backoff`, once the
+/// target has been down for `--down-after` losses the interval doubles on
+/// every further loss, up to `--max-backoff`, and snaps back on a reply.
+fn next_interval(config: &Args, consecutive_failures: u32) -> Duration {
+    let interval = Duration::from_millis(config.interval);
+    if !config.down_backoff || consecutive_failures < config.down_after {
+        return interval;
+    }
+    let doublings = (consecutive_failures - config.down_after + 1).min(20);
+    (interval.max(Duration::from_millis(1)) * 2u32.pow(doublings))
+        .min(Duration::from_millis(config.max_backoff))
+        .max(interval)
+}
+
+/// Timeout for the next packet. With `--adaptive-timeout` it follows the
+/// target's average RTT, so fast targets fail quickly and slow ones get more
+/// patience; until the first reply there is nothing to scale, so it is the
+/// full `--timeout`.
+fn packet_timeout(config: &Args, stats: &PingStats) -> Duration {
+    let timeout = Duration::from_millis(config.timeout);
+    match config.adaptive_timeout {
+        Some(factor) if stats.measured > 0 => stats
+            .avg_rtt()
+            .mul_f64(factor)
+            .clamp(Duration::from_millis(config.min_timeout), timeout),
+        _ => timeout,
+    }
+}
+
+/// Whether `create_result` is certain to report failure whatever the rest of
+/// the run brings. Loss can only be pinned down against a final count: even
+/// if every packet not yet answered gets a reply, the losses so far already
+/// exceed `--max-loss`. Latency fails for certain when the RTT total so far
+/// would average above `--max-latency` even if every remaining packet
+/// replied instantly.
+fn certain_failure(config: &Args, stats: &PingStats) -> bool {
+    if config.count == 0 {
+        return false;
+    }
+    let lost = stats.sent - stats.received;
+    if lost as f64 / config.count as f64 * 100.0 > config.max_loss {
+        return true;
+    }
+    let most_replies = stats.received + config.count.saturating_sub(stats.sent);
+    let Some(max_latency) = config.max_avg_latency() else {
+        return false;
+    };
+    most_replies > 0 && (stats.total_rtt / most_replies).as_millis() > max_latency as u128
+}
+
+/// Health of a single packet for the timeline: critical when lost or slower
+/// than `--max-latency`, degraded when slower than `--warn-latency`.
+fn packet_health(config: &Args, rtt: Option<Duration>) -> Status {
+    match rtt.map(|rtt| rtt.as_millis()) {
+        None => Status::Critical,
+        Some(ms) if ms > config.reply_latency_limit() as u128 => Status::Critical,
+        Some(ms) if config.warn_latency.is_some_and(|warn_latency| ms > warn_latency as u128) => Status::Degraded,
+        Some(_) => Status::Ok,
+    }
+}
+
+fn server_name(config: &Args, target: &str) -> String {
+    match &config.server_name {
+        // The direct host of --compare-direct keeps its own name.
+        Some(name) if config.targets.len() == 1 || (config.compare_direct.is_some() && target == config.targets[0]) => {
+            name.clone()
+        }
+        _ => target.to_string(),
+    }
+}
+
+fn metric_value(stats: &PingStats, metric: expr::Metric) -> f64 {
+    let ms = |rtt: Duration| rtt.as_secs_f64() * 1000.0;
+    match metric {
+        expr::Metric::Loss => stats.packet_loss(),
+        expr::Metric::Avg => ms(stats.avg_rtt()),
+        expr::Metric::P50 => ms(stats.percentile(50.0)),
+        expr::Metric::P95 => ms(stats.percentile(95.0)),
+        expr::Metric::P99 => ms(stats.percentile(99.0)),
+        expr::Metric::Sent => stats.sent as f64,
+        expr::Metric::Received => stats.received as f64,
+    }
+}
+
+/// The thresholds that decide `success` without `--success-expr`: loss and
+/// average latency always, p95, jitter, time to first byte and reordering
+/// when set.
+fn objectives(config: &Args, stats: &PingStats) -> Vec<Objective> {
+    let objective = |name: &str, measured: f64, threshold: f64| Objective {
+        name: name.to_string(),
+        measured,
+        threshold,
+        passed: measured <= threshold,
+    };
+    let mut objectives = vec![objective("loss", stats.packet_loss(), config.max_loss)];
+    if let Some(max_latency) = config.max_avg_latency() {
+        let latency = objective("latency", stats.avg_rtt().as_millis() as f64, max_latency as f64);
+        // An average that rounds down to 0 ms has always failed.
+        objectives.push(Objective { passed: latency.passed && latency.measured != 0.0, ..latency });
+    }
+    for (percentile, max_ms) in config.percentile_limits() {
+        let measured = stats.percentile(percentile).as_millis() as f64;
+        objectives.push(objective(&format!("latency_p{}", percentile), measured, max_ms as f64));
+    }
+    if let Some(max_jitter) = config.max_jitter {
+        objectives.push(objective("jitter", stats.jitter, max_jitter));
+    }
+    if let Some(max_ttfb) = config.max_ttfb {
+        objectives.push(objective("ttfb", stats.http_data().ttfb_ms.unwrap_or_default(), max_ttfb as f64));
+    }
+    if let Some(max_reorder) = config.max_reorder {
+        objectives.push(objective("reorder", stats.reorder_ratio() * 100.0, max_reorder));
+    }
+    objectives
+}
+
+fn create_result(config: &Args, target: &str, stats: &PingStats) -> PingResult {
+    let packet_loss = stats.packet_loss();
+    let avg_rtt = stats.avg_rtt().as_millis() as i64;
+    
+    let objectives = match &config.success_expr {
+        Some(_) => Vec::new(),
+        None => objectives(config, stats),
+    };
+    let success = match &config.success_expr {
+        Some(expr) => expr.eval(&|metric| metric_value(stats, metric)),
+        None => objectives.iter().all(|objective| objective.passed),
+    };
+    let degraded = config.warn_loss.is_some_and(|warn_loss| packet_loss > warn_loss)
+        || config.warn_latency.is_some_and(|warn_latency| avg_rtt > warn_latency as i64);
+    let (error_kind, error) = match (resolution_failure(config, stats), success) {
+        (Some(failure), _) => Some(failure),
+        (None, true) => None,
+        (None, false) => measured_failure(stats),
+    }
+    .unzip();
+    let success = success && error_kind != Some(ErrorKind::DnsMismatch);
+    let status = match (success, degraded) {
+        (false, _) => Status::Critical,
+        (true, true) => Status::Degraded,
+        (true, false) => Status::Ok,
+    };
+
+    PingResult {
+        checkname: "ping".to_string(),
+        servername: server_name(config, target),
+        resulttype: "site".to_string(),
+        prober_host: config.prober_host.clone().unwrap_or_default(),
+        run_id: config.run_id.clone(),
+        success,
+        status,
+        thresholds: Thresholds {
+            max_loss: config.max_loss,
+            max_latency: config.max_avg_latency(),
+            max_latency_percentiles: config
+                .max_latency
+                .iter()
+                .filter_map(|limit| limit.percentile.map(|percentile| PercentileThreshold { percentile, max_ms: limit.max_ms }))
+                .collect(),
+            max_p95: config.max_p95,
+            max_jitter: config.max_jitter,
+            max_ttfb: config.max_ttfb,
+            max_reorder: config.max_reorder,
+            warn_loss: config.warn_loss,
+            warn_latency: config.warn_latency,
+        },
+        objectives,
+        timeline: stats.timeline.clone(),
+        error,
+        error_kind,
+        gate: None,
+        warmup: None,
+        rounds: None,
+        failover: None,
+        comparison: None,
+        resolver: config.dns_server,
+        resolution: (!config.expect_ip.is_empty() || config.reresolve_every.is_some()).then(|| Resolution {
+            expected: config.expect_ip.clone(),
+            observed: stats.resolved.clone(),
+            latest: (stats.resolution_changes > 0).then(|| stats.latest_resolved.clone()),
+            changes: stats.resolution_changes,
+        }),
+        config: config.embed_config.then(|| RunConfig::new(config)),
+        data: Some(PingData {
+            latency: avg_rtt,
+            latency_p95: stats.percentile(95.0).as_millis() as i64,
+            rfc3550_jitter_ms: stats.jitter,
+            packetloss: packet_loss,
+            packets_sent: stats.sent,
+            packets_received: stats.received,
+            timestamp_mismatches: config.timestamp.then_some(stats.timestamp_mismatches),
+            corrupted_replies: stats.corrupted_replies,
+            late_replies: (config.late_window > 0).then_some(stats.late_replies),
+            source_mismatches: (!config.expect_source.is_empty()).then_some(stats.source_mismatches),
+            timeouts: stats.timeouts,
+            icmp_errors: stats.icmp_errors,
+            too_big: stats.too_big,
+            send_errors: stats.send_errors,
+            network_down: stats.network_down,
+            local_network_down: stats.local_network_down.iter().map(DownPeriod::data).collect(),
+            path_mtu: stats.path_mtu,
+            max_consecutive_loss: stats.max_loss_streak.map_or(0, |(longest, _)| longest),
+            max_consecutive_loss_at: stats
+                .max_loss_streak
+                .map(|(_, at)| at.duration_since(UNIX_EPOCH).unwrap_or_default().as_micros() as u64),
+            reordered: stats.reordered,
+            reorder_ratio: stats.reorder_ratio(),
+            backoff_ms: config.down_backoff.then_some(stats.backoff.as_millis() as u64),
+            source_address: stats.source_address,
+            socket_mode: stats.socket_mode,
+            effective_ttl: stats.effective_ttl,
+            clock_step_detected: stats.clock_step_detected,
+            suspicious_timing: stats.suspicious_timing(),
+            bandwidth_bps: config.bandwidth.then(|| stats.bandwidth_bps(config.size)).flatten(),
+            per_source: config
+                .per_source_stats
+                .then(|| stats.per_source.iter().map(Tally::source_data).collect()),
+            by_size: (!config.size_sweep.is_empty())
+                .then(|| stats.by_size.iter().map(Tally::size_data).collect()),
+            remote_clock: stats.remote_clock.map(|(_, clock)| clock),
+            hop_count: stats.hop_count,
+            // Under --anycast a change means another PoP answered, not a new route.
+            // Under --broadcast the hop count is that of whoever answered first.
+            hop_count_changes: stats
+                .hop_count
+                .filter(|_| !config.anycast && !config.broadcast)
+                .map(|_| stats.hop_count_changes),
+            responders: (config.anycast || config.broadcast || config.responders).then(|| stats.responder_data()),
+            distinct_responders: stats.responders.len() as u32,
+            actual_interval_ms: stats.interval_data(config.interval),
+            http: config.http.is_some().then(|| stats.http_data()),
+            samples_ms: config
+                .include_samples
+                .then(|| stats.samples.iter().map(|sample| sample.rtt.as_micros() as f64 / 1000.0).collect()),
+        }),
+        delta: None,
+    }
+}
+
+impl PingResult {
+    /// Whether the target failed before it could be measured, so `data`
+    /// holds no measurement.
+    fn setup_failed(&self) -> bool {
+        matches!(
+            self.error_kind,
+            Some(ErrorKind::Dns | ErrorKind::AddressScope | ErrorKind::Permission | ErrorKind::Config)
+        )
+    }
+
+    /// What this result adds to the `--rich-exit-codes` exit code.
+    fn exit_bits(&self) -> u8 {
+        if self.setup_failed() {
+            return EXIT_SETUP;
+        }
+        let mut bits = 0;
+        for objective in self.objectives.iter().filter(|objective| !objective.passed) {
+            bits |= match objective.name.as_str() {
+                "loss" => EXIT_LOSS,
+                "jitter" => EXIT_JITTER,
+                // No bit of its own: a critical result then reads as "other".
+                "reorder" => 0,
+                _ => EXIT_LATENCY,
+            };
+        }
+        let changed = self.resolution.as_ref().is_some_and(|resolution| resolution.changes > 0);
+        if changed || self.error_kind == Some(ErrorKind::DnsMismatch) {
+            bits |= EXIT_DNS;
+        }
+        match self.status {
+            Status::Degraded => bits | EXIT_DEGRADED,
+            Status::Critical if bits == 0 => EXIT_OTHER,
+            _ => bits,
+        }
+    }
+}
+
+/// A failure of `--expect-ip` or `--fail-on-reresolve-change`, whatever the
+/// pings measured.
+fn resolution_failure(config: &Args, stats: &PingStats) -> Option<(ErrorKind, String)> {
+    let list = |addrs: &[IpAddr]| addrs.iter().map(IpAddr::to_string).collect::<Vec<_>>().join(", ");
+    if !config.expect_ip.is_empty() && stats.resolved.iter().any(|ip| !config.expect_ip.contains(ip)) {
+        return Some((
+            ErrorKind::DnsMismatch,
+            format!("Resolved to {}, expected {}", list(&stats.resolved), list(&config.expect_ip)),
+        ));
+    }
+    if config.fail_on_reresolve_change && stats.resolution_changes > 0 {
+        return Some((
+            ErrorKind::DnsMismatch,
+            format!(
+                "Resolution changed during the run from {} to {}",
+                list(&stats.resolved),
+                list(&stats.latest_resolved)
+            ),
+        ));
+    }
+    None
+}
+
+/// Why a measured run failed, when it is more than missed thresholds: an
+/// interrupted run, or one that got no reply at all. A run that missed its
+/// thresholds otherwise has no error; its `objectives` say which.
+fn measured_failure(stats: &PingStats) -> Option<(ErrorKind, String)> {
+    if INTERRUPTED.load(Ordering::Relaxed) {
+        return Some((ErrorKind::Interrupted, "Interrupted before the run completed".to_string()));
+    }
+    match (stats.sent, stats.received) {
+        (0, _) | (_, 1..) => None,
+        (sent, 0) if stats.network_down > stats.timeouts + stats.icmp_errors + stats.too_big => Some((
+            ErrorKind::NetworkDown,
+            format!("Local network down: {} of {} packets could not be sent", stats.network_down, sent),
+        )),
+        (sent, 0) if stats.icmp_errors + stats.too_big > stats.timeouts => Some((
+            ErrorKind::Unreachable,
+            format!("Target unreachable: {} of {} packets answered with ICMP errors", stats.icmp_errors + stats.too_big, sent),
+        )),
+        (sent, 0) => Some((ErrorKind::Timeout, format!("No reply to any of {} packets", sent))),
+    }
+}
+
+/// A failed result still carries a full `data` object, that of a target
+/// that sent nothing, so every result has the same shape.
+fn failure_result(config: &Args, target: &str, kind: ErrorKind, error: String) -> PingResult {
+    PingResult {
+        success: false,
+        status: Status::Critical,
+        error: Some(error),
+        error_kind: Some(kind),
+        ..create_result(config, target, &PingStats::new())
+    }
+}
+
+/// A failure before any packet was sent applies to every target alike.
+fn setup_failure(config: &Args, kind: ErrorKind, error: &str) -> Vec<(PingResult, PingStats)> {
+    config
+        .targets
+        .iter()
+        .map(|target| (failure_result(config, target, kind, error.to_string()), PingStats::new()))
+        .collect()
+}
+
+/// Print the per-target summary table used by `--format human` with multiple
+/// targets. Rows are sorted worst first: by loss, then by average latency.
+fn print_summary_table(config: &Args, reports: &[(PingResult, PingStats)]) {
+    let precision = precision(config, 2);
+    let mut rows: Vec<&(PingResult, PingStats)> = reports.iter().collect();
+    rows.sort_by(|(_, a), (_, b)| {
+        b.packet_loss()
+            .total_cmp(&a.packet_loss())
+            .then_with(|| b.avg_rtt().cmp(&a.avg_rtt()))
+    });
+
+    let width = rows
+        .iter()
+        .map(|(result, _)| result.servername.len())
+        .chain(std::iter::once("TARGET".len()))
+        .max()
+        .unwrap_or(0);
+
+    println!(
+        "{:<width$}  {:>6}  {:>6}  {:>7}  {:>10}  {:>10}  STATUS",
+        "TARGET", "SENT", "RECV", "LOSS%", "AVG(ms)", "P95(ms)",
+    );
+    for (result, stats) in rows {
+        println!(
+            "{:<width$}  {:>6}  {:>6}  {:>7.1}  {:>10.*}  {:>10.*}  {}",
+            result.servername,
+            stats.sent,
+            stats.received,
+            stats.packet_loss(),
+            precision,
+            stats.avg_rtt().as_secs_f64() * 1000.0,
+            precision,
+            stats.percentile(95.0).as_secs_f64() * 1000.0,
+            match result.status {
+                Status::Ok => "ok",
+                Status::Degraded => "DEGRADED",
+                Status::Critical => "FAIL",
+            },
+        );
+    }
+}
+
+fn multi_target_table(config: &Args) -> bool {
+    config.format == OutputFormat::Human && (config.targets.len() > 1 || config.targets_refresh.is_some())
+}
+
+/// The text a `--template` placeholder is replaced with.
+fn template_value(result: &PingResult, stats: &PingStats, field: template::Field) -> String {
+    match field {
+        template::Field::Target => result.servername.clone(),
+        template::Field::Status => result.status.name().to_string(),
+        template::Field::Jitter => format!("{:.2}", stats.jitter),
+        template::Field::Error => result.error.clone().unwrap_or_default(),
+        template::Field::Metric(metric @ (expr::Metric::Sent | expr::Metric::Received)) => metric_value(stats, metric).to_string(),
+        template::Field::Metric(expr::Metric::Loss) => format!("{:.1}", stats.packet_loss()),
+        template::Field::Metric(metric) => format!("{:.2}", metric_value(stats, metric)),
+    }
+}
+
+fn print_results(config: &Args, reports: &[(PingResult, PingStats)]) -> Result<(), Box<dyn std::error::Error>> {
+    let results: Vec<&PingResult> = reports.iter().map(|(result, _)| result).collect();
+    let summary = match (&config.template, config.format) {
+        (Some(template), _) => reports
+            .iter()
+            .map(|(result, stats)| template.render(&|field| template_value(result, stats, field)) + "\n")
+            .collect::<String>()
+            .into_bytes(),
+        (None, OutputFormat::Json) if results.len() == 1 => (casing::to_json(results[0], config.json_case, config.omit_null, true)? + "\n").into_bytes(),
+        (None, OutputFormat::Json) => (casing::to_json(&results, config.json_case, config.omit_null, true)? + "\n").into_bytes(),
+        (None, OutputFormat::Prometheus) => prometheus::render(reports, config.exemplars).into_bytes(),
+        (None, OutputFormat::Fping) => fping::render(reports).into_bytes(),
+        (None, OutputFormat::Grafana) => (grafana::render(reports, config.json_case, true)? + "\n").into_bytes(),
+        (None, OutputFormat::Msgpack) => msgpack::records(&results, config.json_case, config.omit_null)?,
+        (None, OutputFormat::None) => return Ok(()),
+        (None, OutputFormat::Human) => {
+            for result in results {
+                if let Some(error) = &result.error {
+                    println!("{}: {}", result.servername, error);
+                }
+            }
+            return Ok(());
+        }
+    };
+    match &config.summary_out {
+        Some(path) => std::fs::write(path, summary)
+            .map_err(|e| format!("Failed to write --summary-out {}: {}", path, e).into()),
+        None => {
+            use std::io::Write;
+
+            let mut stdout = std::io::stdout().lock();
+            stdout.write_all(&summary)?;
+            stdout.flush()?;
+            Ok(())
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn set_socket_mark(client: &EchoClient, mark: u32) -> Result<(), (ErrorKind, String)> {
+    use std::os::fd::BorrowedFd;
+
+    let fd = client.socket().get_native_sock();
+    // SAFETY: the descriptor is owned by `client`, which outlives this borrow.
+    let fd = unsafe { BorrowedFd::borrow_raw(fd) };
+    socket2::SockRef::from(&fd).set_mark(mark).map_err(|e| match e.kind() {
+        std::io::ErrorKind::PermissionDenied => {
+            (ErrorKind::Permission, "Setting SO_MARK requires CAP_NET_ADMIN".to_string())
+        }
+        _ => (ErrorKind::Config, format!("Failed to set SO_MARK: {}", e)),
+    })
+}
+
+#[cfg(not(target_os = "linux"))]
+fn set_socket_mark(_client: &EchoClient, _mark: u32) -> Result<(), (ErrorKind, String)> {
+    Err((ErrorKind::Config, "--mark is only supported on Linux".to_string()))
+}
+
+/// Lacking privileges is a permission error; anything else that stops setup
+/// is a problem with what the arguments asked for.
+fn setup_error_kind(kind: std::io::ErrorKind) -> ErrorKind {
+    match kind {
+        std::io::ErrorKind::PermissionDenied => ErrorKind::Permission,
+        _ => ErrorKind::Config,
+    }
+}
+
+fn family(ip: IpAddr) -> &'static str {
+    match ip {
+        IpAddr::V4(_) => "IPv4",
+        IpAddr::V6(_) => "IPv6",
+    }
+}
+
+/// What `-v` prints before the first packet: each socket actually in use
+/// (`log_diagnostics`) and the timing after defaults and overrides are
+/// applied (`log_timing`).
+fn log_diagnostics(config: &Args, source: Option<IpAddr>, probe: &Probe) {
+    match probe {
+        Probe::Icmp(client) => {
+            let option = |value: std::io::Result<u32>| value.map_or_else(|e| format!("unknown ({})", e), |v| v.to_string());
+            let bound = match client.socket().local_addr() {
+                Ok(local) if !local.ip().is_unspecified() => format!("bound to {}", local.ip()),
+                _ => "not bound".to_string(),
+            };
+            let mode = SocketMode::of(client);
+            info!(
+                socket_mode = mode.name(),
+                effective_ttl = client.socket_ttl().ok(),
+                "ICMP socket: IPv4 {}, {}, TTL {}, ToS {}",
+                match mode {
+                    SocketMode::Raw => "raw",
+                    SocketMode::Dgram => "dgram (unprivileged ping socket)",
+                },
+                bound,
+                option(client.socket_ttl()),
+                option(client.socket_tos())
+            );
+        }
+        Probe::Tcp(port) => info!(
+            "TCP connect probes to port {}, one socket per packet, from {} port {}",
+            port,
+            source.map_or_else(|| "the routed address".to_string(), |ip| ip.to_string()),
+            config.source_port.map_or_else(|| "ephemeral".to_string(), |port| port.to_string())
+        ),
+        Probe::Transport(_) => info!("Echo requests through a custom transport"),
+    }
+}
+
+fn log_timing(config: &Args) {
+    info!(
+        "Timing: count {}, interval {}ms, timeout {}ms{}, window {}, drain {}ms",
+        match config.count {
+            0 => "endless".to_string(),
+            count => count.to_string(),
+        },
+        config.interval,
+        config.timeout,
+        config
+            .adaptive_timeout
+            .map(|factor| format!(" (adaptive x{}, at least {}ms)", factor, config.min_timeout))
+            .unwrap_or_default(),
+        if config.source_port.is_some() { 1 } else { config.window },
+        config.drain.unwrap_or(config.timeout + config.late_window)
+    );
+}
+
+/// The local address the kernel uses to reach `dest`. The ICMP socket is
+/// normally unbound, so unless it was bound explicitly ask the routing table.
+fn source_address(probe: &Probe, dest: IpAddr, mark: Option<u32>, vrf: Option<&str>) -> Option<IpAddr> {
+    if let Probe::Icmp(client) = probe {
+        if let Ok(local) = client.socket().local_addr() {
+            if !local.ip().is_unspecified() {
+                return Some(local.ip());
+            }
+        }
+    }
+
+    route_source(dest, mark, vrf)
+}
+
+/// A throwaway UDP socket carrying the same firewall mark and bound to the
+/// same VRF, connected to `dest` so the kernel has picked its route.
+fn route_socket(dest: IpAddr, mark: Option<u32>, vrf: Option<&str>) -> Option<socket2::Socket> {
+    let domain = match dest {
+        IpAddr::V4(_) => socket2::Domain::IPV4,
+        IpAddr::V6(_) => socket2::Domain::IPV6,
+    };
+    let probe = socket2::Socket::new(domain, socket2::Type::DGRAM, None).ok()?;
+    #[cfg(target_os = "linux")]
+    if let Some(mark) = mark {
+        probe.set_mark(mark).ok()?;
+    }
+    #[cfg(target_os = "linux")]
+    if let Some(vrf) = vrf {
+        probe.bind_device(Some(vrf.as_bytes())).ok()?;
+    }
+    #[cfg(not(target_os = "linux"))]
+    let _ = (mark, vrf);
+    probe.connect(&std::net::SocketAddr::new(dest, 9).into()).ok()?;
+    Some(probe)
+}
+
+/// The local address the routing table picks for `dest`.
+fn route_source(dest: IpAddr, mark: Option<u32>, vrf: Option<&str>) -> Option<IpAddr> {
+    route_socket(dest, mark, vrf)?.local_addr().ok()?.as_socket().map(|addr| addr.ip())
+}
+
+/// MTU assumed for `--dont-fragment` when the route's cannot be read.
+const DEFAULT_MTU: usize = 1500;
+
+/// The MTU of the route to `dest`: the outgoing interface's, or less when
+/// the kernel already learned a smaller path MTU for `dest`.
+#[cfg(target_os = "linux")]
+fn route_mtu(dest: IpAddr, mark: Option<u32>, vrf: Option<&str>) -> Option<usize> {
+    use std::os::fd::AsRawFd;
+
+    let probe = route_socket(dest, mark, vrf)?;
+    let mut mtu: libc::c_int = 0;
+    let mut len = std::mem::size_of::<libc::c_int>() as libc::socklen_t;
+    // SAFETY: `mtu` and `len` outlive the call and `len` is the size of `mtu`.
+    let ret = unsafe {
+        libc::getsockopt(
+            probe.as_raw_fd(),
+            libc::IPPROTO_IP,
+            libc::IP_MTU,
+            &mut mtu as *mut libc::c_int as *mut libc::c_void,
+            &mut len,
+        )
+    };
+    (ret == 0 && mtu > 0).then_some(mtu as usize)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn route_mtu(_dest: IpAddr, _mark: Option<u32>, _vrf: Option<&str>) -> Option<usize> {
+    None
+}
+
+/// Payload bytes of packet number `sequence`: the next `--size-sweep` size
+/// in turn, or `--size`.
+fn payload_size(config: &Args, sequence: u32) -> usize {
+    match config.size_sweep.as_slice() {
+        [] => config.size,
+        sizes => sizes[sequence as usize % sizes.len()],
+    }
+}
+
+/// The largest payload the run sends.
+fn max_payload_size(config: &Args) -> usize {
+    config.size_sweep.iter().copied().max().unwrap_or(config.size)
+}
+
+/// With `--dont-fragment` a request must fit the route's MTU whole; say so
+/// before sending rather than losing every packet.
+fn check_packet_fits(config: &Args, target: &str, ip_addr: IpAddr) -> Result<(), String> {
+    let mtu = route_mtu(ip_addr, config.mark, config.vrf.as_deref()).unwrap_or_else(|| {
+        warn!(target = %target, "Could not read the MTU toward {}, assuming {}", ip_addr, DEFAULT_MTU);
+        DEFAULT_MTU
+    });
+    let size = max_payload_size(config);
+    let packet = IPV4_HEADER_LEN + icmp::ICMP_HEADER_LEN + size;
+    if packet > mtu {
+        return Err(format!(
+            "--{} {} makes {}-byte packets, more than the {}-byte MTU toward {} with --dont-fragment (at most {})",
+            if config.size_sweep.is_empty() { "size" } else { "size-sweep" },
+            size,
+            packet,
+            mtu,
+            target,
+            mtu.saturating_sub(IPV4_HEADER_LEN + icmp::ICMP_HEADER_LEN)
+        ));
+    }
+    if config.verbose > 0 {
+        info!(target = %target, "MTU toward {} is {}, {}-byte packets fit", ip_addr, mtu, packet);
+    }
+    Ok(())
+}
+
+/// Logs go to stderr so they never mix with the summary on stdout. Per-reply
+/// events are logged at debug level; set `RUST_LOG=debug` to see them.
+/// With `hold` set, logs are kept in memory until `release_logs`. With
+/// `events` set, the packet events also go to the `--event-socket` clients.
+fn init_logging(config: &Args) {
+    let (hold, tui) = (config.only_on_failure, config.tui);
+    let filter = EnvFilter::builder()
+        .with_default_directive(LevelFilter::INFO.into())
+        .from_env_lossy();
+    let writer = match (hold, tui) {
+        (true, _) => BoxMakeWriter::new(|| HeldLogs),
+        (_, true) => BoxMakeWriter::new(|| tui::Events),
+        _ => BoxMakeWriter::new(std::io::stderr),
+    };
+    let logs = tracing_subscriber::fmt::layer().with_writer(writer);
+    // Escapes would be cut through when a line is fitted to the dashboard.
+    let logs = match tui {
+        true => logs.with_ansi(false),
+        false => logs,
+    };
+    let logs = match config.log_format {
+        LogFormat::Text => logs.boxed(),
+        LogFormat::Json => logs.json().boxed(),
+    };
+    // The packet events are the ones carrying a `schema` version.
+    let packet_events = || filter_fn(|meta| meta.fields().field("schema").is_some());
+    let events = config.event_socket.is_some().then(|| {
+        tracing_subscriber::fmt::layer()
+            .json()
+            .with_writer(|| event_socket::Clients)
+            .with_filter(packet_events())
+    });
+    let event_log = config.event_log.is_some().then(|| {
+        tracing_subscriber::fmt::layer()
+            .json()
+            .with_writer(|| event_log::EventLog)
+            .with_filter(packet_events())
+    });
+    tracing_subscriber::registry()
+        .with(logs.with_filter(filter))
+        .with(events)
+        .with(event_log)
+        .init();
+}
+
+/// Logs held back under `--only-on-failure` until the outcome is known.
+static HELD_LOGS: std::sync::Mutex<Vec<u8>> = std::sync::Mutex::new(Vec::new());
+
+struct HeldLogs;
+
+impl std::io::Write for HeldLogs {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        HELD_LOGS.lock().unwrap().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Write out the logs held back so far; later ones go to stderr directly.
+fn release_logs() {
+    use std::io::Write;
+
+    let held = std::mem::take(&mut *HELD_LOGS.lock().unwrap());
+    let _ = std::io::stderr().write_all(&held);
+}
+
+/// Print the running summary for one target mid-run (`--report-every`).
+/// JSON reports are written one per line so they can be consumed as a stream.
+async fn print_report(config: &Args, target: &str, stats: &PingStats) -> Result<(), serde_json::Error> {
+    let report = (create_result(config, target, stats), stats.clone());
+    match config.format {
+        OutputFormat::Json => println!("{}", casing::to_json(&report.0, config.json_case, config.omit_null, false)?),
+        OutputFormat::Human => print_statistics(config, &report.0.servername, stats).await,
+        OutputFormat::Prometheus => print!("{}", prometheus::render(&[report], config.exemplars)),
+        OutputFormat::Fping => print!("{}", fping::render(&[report])),
+        OutputFormat::Grafana => println!("{}", grafana::render(&[report], config.json_case, false)?),
+        OutputFormat::Msgpack => {
+            use std::io::Write;
+
+            let _ = std::io::stdout().lock().write_all(&msgpack::records(&[&report.0], config.json_case, config.omit_null)?);
+        }
+        OutputFormat::None => {}
+    }
+    Ok(())
+}
+
+fn open_sinks(config: &Args) -> Result<Sinks, String> {
+    Ok(Sinks {
+        statsd: config.statsd.as_deref().map(statsd::StatsdSink::connect).transpose()?.map(Arc::new),
+        syslog: config.syslog.then(syslog::Syslog::connect).transpose()?.map(Arc::new),
+        webhook: config.webhook.as_deref().map(webhook::Webhook::connect).transpose()?.map(Arc::new),
+    })
+}
+
+fn syslog_summary(result: &PingResult, stats: &PingStats) -> String {
+    let status = result.status.name();
+    match &result.error {
+        Some(error) if result.setup_failed() => format!("{}: {}: {}", result.servername, status, error),
+        _ => format!(
+            "{}: {}: {} sent, {} received, {:.1}% loss, avg {:.2} ms, p95 {:.2} ms",
+            result.servername,
+            status,
+            stats.sent,
+            stats.received,
+            stats.packet_loss(),
+            stats.avg_rtt().as_secs_f64() * 1000.0,
+            stats.percentile(95.0).as_secs_f64() * 1000.0,
+        ),
+    }
+}
+
+async fn monitor_ctrl_c() -> Result<(), tokio::io::Error> {
+    signal::ctrl_c().await
+}
+
+fn expected_source(config: &Args, source: IpAddr) -> bool {
+    config.expect_source.is_empty() || config.expect_source.iter().any(|cidr| cidr.contains(source))
+}
+
+/// Microseconds from the start of the run to `at`, and the Unix time of
+/// `at` in microseconds as seen from the wall clock at `anchor`. Deriving
+/// both from the monotonic clock keeps one packet's times consistent.
+fn event_times(config: &Args, anchor: (Instant, SystemTime), at: Instant) -> (u64, u64) {
+    let wall = match at.checked_duration_since(anchor.0) {
+        Some(after) => anchor.1 + after,
+        None => anchor.1 - anchor.0.duration_since(at),
+    };
+    (
+        at.saturating_duration_since(config.started_at).as_micros() as u64,
+        wall.duration_since(UNIX_EPOCH).unwrap_or_default().as_micros() as u64,
+    )
+}
+
+/// Whether the wall clock moved differently from the monotonic clock since
+/// `since`, meaning NTP or an operator stepped it. RTTs are measured on the
+/// monotonic clock, but a step usually comes with a VM pause or migration
+/// that makes the packet in flight meaningless too.
+fn clock_stepped(since: (Instant, SystemTime)) -> bool {
+    let monotonic = since.0.elapsed();
+    let divergence = match SystemTime::now().duration_since(since.1) {
+        Ok(wall) => wall.abs_diff(monotonic),
+        // The wall clock went backwards.
+        Err(e) => e.duration() + monotonic,
+    };
+    divergence > CLOCK_STEP_THRESHOLD
+}
+
+/// Send one packet and fold its outcome into `stats`. Each packet runs as
+/// its own task so the send loop keeps its schedule while replies are
+/// outstanding.
+#[allow(clippy::too_many_arguments)]
+async fn send_packet(
+    (source, probe): (Option<IpAddr>, Probe),
+    config: Arc<Args>,
+    target: String,
+    ip_addr: IpAddr,
+    ident: u16,
+    sequence: u32,
+    size: usize,
+    timeout: Duration,
+    stats: Arc<Mutex<PingStats>>,
+    syslog: Option<Arc<syslog::Syslog>>,
+) {
+    let payload = icmp::build_payload(size, sequence, config.timestamp);
+    if config.verbose > 1 {
+        match &probe {
+            Probe::Icmp(client) => info!(
+                "Sending echo request: {} bytes to {}, TTL {}, timeout {}ms",
+                payload.len() + icmp::ICMP_HEADER_LEN,
+                ip_addr,
+                client.socket_ttl().map_or_else(|_| "unknown".to_string(), |ttl| ttl.to_string()),
+                timeout.as_millis()
+            ),
+            Probe::Tcp(port) => info!(
+                "Connecting to {}:{}, timeout {}ms",
+                ip_addr,
+                port,
+                timeout.as_millis()
+            ),
+            Probe::Transport(_) => info!("Sending echo request to {}, timeout {}ms", ip_addr, timeout.as_millis()),
+        }
+    }
+    let started = (Instant::now(), SystemTime::now());
+    // Under --broadcast the first reply counts for the packet; the others
+    // only tell who else answered.
+    let (ping_result, further) = match (&probe, config.broadcast) {
+        (Probe::Icmp(client), true) => {
+            match client.ping_all(ip_addr, ident, sequence as u16, &payload, timeout).await {
+                Ok(mut echoes) => {
+                    let further = echoes.split_off(1);
+                    (Ok(echoes.remove(0)), further)
+                }
+                Err(e) => (Err(e), Vec::new()),
+            }
+        }
+        _ => (send_single_ping(&probe, &config, &target, ip_addr, source, ident, sequence, &payload, timeout).await, Vec::new()),
+    };
+
+    let clock_stepped = clock_stepped(started);
+
+    let mut stats_guard = stats.lock().await;
+    let previous_rtt = stats_guard.last_rtt;
+    if let (true, Some(source), Ok(echo)) = (config.per_source_stats, source, &ping_result) {
+        if expected_source(&config, echo.reply.source) {
+            stats_guard.record_source_reply(source, (!clock_stepped).then_some(echo.rtt));
+        }
+    }
+    if let (false, Ok(echo)) = (config.size_sweep.is_empty(), &ping_result) {
+        if expected_source(&config, echo.reply.source) {
+            stats_guard.record_size_reply(size, (!clock_stepped).then_some(echo.rtt));
+        }
+    }
+    match &ping_result {
+        Ok(echo) if !expected_source(&config, echo.reply.source) => {
+            stats_guard.update_with_failure();
+            stats_guard.source_mismatches += 1;
+            if !config.quiet {
+                warn!(
+                    target = %target,
+                    seq = sequence,
+                    source = %echo.reply.source,
+                    schema = replay::SCHEMA,
+                    "Reply from outside --expect-source"
+                );
+            }
+        }
+        Ok(_) if clock_stepped => {
+            stats_guard.update_with_unmeasured();
+            warn!(
+                target = %target,
+                seq = sequence,
+                schema = replay::SCHEMA,
+                "System clock stepped during the packet, RTT not counted"
+            );
+        }
+        Ok(echo) => {
+            let rtt = echo.rtt;
+            stats_guard.update_with_success(rtt, sequence);
+            stats_guard.record_responder(echo.reply.source, rtt);
+            if let Some(handshake) = echo.handshake {
+                stats_guard.record_http(handshake, rtt - handshake);
+            }
+            if config.bandwidth {
+                stats_guard.record_arrival(echo.reply.received_at);
+            }
+            if let Some(ttl) = echo.reply.ttl {
+                stats_guard.record_reply_ttl(ttl);
+            }
+            if let (true, Some(clock)) = (config.timestamp_icmp, icmp::parse_remote_clock(&echo.reply.payload)) {
+                let received = SystemTime::now() - echo.reply.received_at.elapsed();
+                stats_guard.record_remote_clock(rtt, RemoteClockData {
+                    originate_ms: clock.originate,
+                    receive_ms: clock.receive,
+                    transmit_ms: clock.transmit,
+                    offset_ms: clock.offset_ms(received),
+                });
+            }
+            if rtt < PLAUSIBLE_RTT_FLOOR && !ip_addr.is_loopback() {
+                stats_guard.implausible_rtts += 1;
+            }
+            let (sent_mono_us, sent_at) = event_times(&config, started, echo.reply.received_at - rtt);
+            let (received_mono_us, received_at) = event_times(&config, started, echo.reply.received_at);
+            debug!(
+                target = %target,
+                seq = sequence,
+                rtt_us = rtt.as_micros() as u64,
+                ttl = echo.reply.ttl,
+                source = %echo.reply.source,
+                success = true,
+                schema = replay::SCHEMA,
+                sent_at,
+                received_at,
+                sent_mono_us,
+                received_mono_us,
+                "Echo reply"
+            );
+            // An echo reply carries the request's payload back unchanged.
+            if matches!(probe, Probe::Icmp(_)) && !config.timestamp_icmp && echo.reply.payload != payload {
+                stats_guard.corrupted_replies += 1;
+                if !config.quiet {
+                    warn!(
+                        target = %target,
+                        seq = sequence,
+                        source = %echo.reply.source,
+                        schema = replay::SCHEMA,
+                        "Reply payload differs from the request"
+                    );
+                }
+            }
+            if config.timestamp && !timestamp_matches(echo, sequence) {
+                stats_guard.timestamp_mismatches += 1;
+                if !config.quiet {
+                    warn!(target = %target, seq = sequence, "Timestamp mismatch");
+                }
+            }
+            for echo in &further {
+                stats_guard.record_responder(echo.reply.source, echo.rtt);
+                debug!(
+                    target = %target,
+                    seq = sequence,
+                    rtt_us = echo.rtt.as_micros() as u64,
+                    source = %echo.reply.source,
+                    schema = replay::SCHEMA,
+                    "Further reply"
+                );
+            }
+        }
+        Err(PingError::Late { source, rtt }) => {
+            stats_guard.update_with_late();
+            if !config.quiet {
+                warn!(
+                    target = %target,
+                    seq = sequence,
+                    rtt_us = rtt.as_micros() as u64,
+                    source = %source,
+                    schema = replay::SCHEMA,
+                    "Late reply"
+                );
+            }
+        }
+        Err(e) => {
+            let kind = FailureKind::from(e);
+            stats_guard.update_with_error(kind);
+            if let Some(mtu) = e.next_hop_mtu() {
+                stats_guard.record_path_mtu(mtu);
+            }
+            if !config.quiet {
+                let (sent_mono_us, sent_at) = event_times(&config, started, started.0);
+                error!(
+                    target = %target,
+                    seq = sequence,
+                    success = false,
+                    error = %e,
+                    failure = kind.name(),
+                    mtu = e.next_hop_mtu(),
+                    schema = replay::SCHEMA,
+                    sent_at,
+                    sent_mono_us,
+                    "Ping failed"
+                );
+            }
+        }
+    }
+    let network_down = match &ping_result {
+        Err(e) => Some(e).filter(|e| FailureKind::from(*e) == FailureKind::NetworkDown),
+        Ok(_) => None,
+    };
+    let health = match &ping_result {
+        Ok(echo) if !expected_source(&config, echo.reply.source) => Status::Critical,
+        // A reply is a reply even when the clock hides how fast it was.
+        Ok(_) if clock_stepped => packet_health(&config, Some(Duration::ZERO)),
+        Ok(echo) => packet_health(&config, Some(echo.rtt)),
+        Err(PingError::Late { rtt, .. }) => packet_health(&config, Some(*rtt)),
+        Err(_) => packet_health(&config, None),
+    };
+    stats_guard.record_health(health, started.1, config.down_after);
+    if stats_guard.track_local_network(network_down.is_some(), SystemTime::now()) && !config.quiet {
+        match network_down {
+            Some(e) => warn!(target = %target, "Local network down ({}), losses are not the path's", e),
+            None => info!(target = %target, "Local network is back"),
+        }
+    }
+    drop(stats_guard);
+
+    if let Some(syslog) = &syslog {
+        match &ping_result {
+            Ok(echo) => syslog.send(
+                syslog::Severity::Info,
+                &format!("{}: seq={} time={:.2} ms", target, sequence, echo.rtt.as_secs_f64() * 1000.0),
+            ),
+            Err(e) => syslog.send(syslog::Severity::Warning, &format!("{}: seq={} {}", target, sequence, e)),
+        }
+    }
+
+    if let (Ok(echo), false) = (&ping_result, config.quiet || config.tui) {
+        let markers = threshold_markers(&config, echo.rtt, previous_rtt);
+        match probe {
+            Probe::Icmp(_) | Probe::Transport(_) => {
+                let bytes = icmp::ICMP_HEADER_LEN + echo.reply.payload.len();
+                // The reply's own TTL, like ping(8), where the socket shows it.
+                let ttl = echo.reply.ttl.unwrap_or(config.ttl);
+                print_ping_result(echo.reply.source, bytes, sequence, ttl, echo.rtt, precision(&config, 2), &markers).await;
+                for echo in &further {
+                    let bytes = icmp::ICMP_HEADER_LEN + echo.reply.payload.len();
+                    let ttl = echo.reply.ttl.unwrap_or(config.ttl);
+                    print_ping_result(echo.reply.source, bytes, sequence, ttl, echo.rtt, precision(&config, 2), " (DUP!)")
+                        .await;
+                }
+            }
+            Probe::Tcp(port) => match echo.handshake {
+                Some(handshake) => println!(
+                    "Response from {}:{}: seq={} connect={:.*} ms time={:.*} ms{}",
+                    ip_addr,
+                    port,
+                    sequence,
+                    precision(&config, 2),
+                    handshake.as_secs_f64() * 1000.0,
+                    precision(&config, 2),
+                    echo.rtt.as_secs_f64() * 1000.0,
+                    markers
+                ),
+                None => println!(
+                    "Connected to {}:{}: seq={} time={:.*} ms{}",
+                    ip_addr,
+                    port,
+                    sequence,
+                    precision(&config, 2),
+                    echo.rtt.as_secs_f64() * 1000.0,
+                    markers
+                ),
+            },
+        }
+    }
+}
+
+/// Give packets still in flight when the send loop stops up to `window` to
+/// be answered. Whatever is still outstanding after that counts as lost.
+async fn drain(in_flight: &mut JoinSet<()>, stats: &Mutex<PingStats>, window: Duration) {
+    let _ = tokio::time::timeout(window, async {
+        while in_flight.join_next().await.is_some() {}
+    })
+    .await;
+
+    in_flight.abort_all();
+    while let Some(joined) = in_flight.join_next().await {
+        if joined.is_err() {
+            stats.lock().await.update_with_error(FailureKind::Timeout);
+        }
+    }
+}
+
+/// With `--until-received`, wait until another packet can no longer push
+/// the replies past `wanted`: replies so far plus packets still in flight
+/// must fall short of it. Returns false once `wanted` replies are in.
+async fn room_for_reply(in_flight: &mut JoinSet<()>, stats: &Mutex<PingStats>, sent: u32, wanted: u32) -> bool {
+    loop {
+        let (received, answered) = {
+            let stats = stats.lock().await;
+            (stats.received, stats.sent)
+        };
+        if received >= wanted {
+            return false;
+        }
+        if received + (sent - answered) < wanted {
+            return true;
+        }
+        in_flight.join_next().await;
+    }
+}
+
+/// Wait until the next packet is due, `delay` after the last one, or until
+/// `stop` is set, so Ctrl+C does not sit out a long `--down-backoff`. A
+/// zero delay (`--interval 0`) returns at once without yielding to the
+/// timer, so packets go out back to back as fast as the window allows.
+async fn wait_for_next_send(config: &Args, slot: &mut SystemTime, delay: Duration, stop: &AtomicBool) {
+    let due = async {
+        if config.align {
+            // A slot already missed, say behind the rate limiter, is skipped
+            // rather than sent late.
+            *slot = next_aligned((*slot + delay).max(SystemTime::now()), Duration::from_millis(config.interval));
+            sleep_until_wall(*slot).await;
+        } else if !delay.is_zero() {
+            tokio::time::sleep(delay).await;
+        }
+    };
+    tokio::select! {
+        () = due => {}
+        () = stopped(stop) => {}
+    }
+}
+
+/// Resolves once `stop` is set, checking every 100ms.
+async fn stopped(stop: &AtomicBool) {
+    while !stop.load(Ordering::Relaxed) {
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+}
+
+/// `probes` holds one probe per `--source-rotate` address, each packet
+/// taking the next, or a single probe for the default source.
+#[allow(clippy::too_many_arguments)]
+async fn ping_target(
+    probes: Vec<(Option<IpAddr>, Probe)>,
+    config: Arc<Args>,
+    target: String,
+    addrs: Vec<IpAddr>,
+    ident: u16,
+    stop: Arc<AtomicBool>,
+    limiter: Option<Arc<RateLimiter>>,
+    sinks: Sinks,
+) -> PingStats {
+    let ip_addr = addrs[0];
+    if let (false, Probe::Tcp(port)) = (config.quiet, &probes[0].1) {
+        match &config.http {
+            Some(path) => info!("TCP PING {} ({}) port {}, GET {}", target, ip_addr, port, path),
+            None => info!("TCP PING {} ({}) port {}", target, ip_addr, port),
+        }
+    } else if !config.quiet && !config.size_sweep.is_empty() {
+        let sizes: Vec<String> = config.size_sweep.iter().map(usize::to_string).collect();
+        info!("PING {} ({}) {} bytes of data in turn", target, ip_addr, sizes.join("/"));
+    } else if !config.quiet {
+        info!(
+            "PING {} ({}) {}({}) bytes of data",
+            target,
+            ip_addr,
+            config.size,
+            config.size + icmp::ICMP_HEADER_LEN + IPV4_HEADER_LEN
+        );
+    }
+
+    let stats = Arc::new(Mutex::new(PingStats {
+        max_samples: config.max_samples,
+        socket_mode: match &probes[0].1 {
+            Probe::Icmp(client) => Some(SocketMode::of(client)),
+            Probe::Tcp(_) | Probe::Transport(_) => None,
+        },
+        effective_ttl: match &probes[0].1 {
+            Probe::Icmp(client) => client.socket_ttl().ok().map(|ttl| ttl as u8),
+            Probe::Tcp(_) | Probe::Transport(_) => None,
+        },
+        latest_resolved: addrs.clone(),
+        resolved: addrs,
+        ..PingStats::new()
+    }));
+    if config.tui {
+        tui::watch(&target, stats.clone());
+    }
+    let started = Instant::now();
+    let mut sequence = 0;
+    let interval = Duration::from_millis(config.interval);
+    // Send slot on the --align grid for the current packet.
+    let mut slot = SystemTime::now();
+    // Packets sent but not yet answered or timed out.
+    let mut in_flight = JoinSet::new();
+    // Probes from a fixed source port to one target share a 4-tuple, so
+    // only one of them can be in flight at a time.
+    let window = Window::new(if config.source_port.is_some() { 1 } else { config.window });
+
+    // A rotating source has no single address to report.
+    if probes.len() == 1 {
+        let source = source_address(&probes[0].1, ip_addr, config.mark, config.vrf.as_deref());
+        if config.verbose > 0 {
+            info!(target = %target, "Source address {}", source.map_or_else(|| "unknown".to_string(), |ip| ip.to_string()));
+        }
+        stats.lock().await.source_address = source;
+    }
+
+    let reresolver = config.reresolve_every.map(|period| {
+        let (config, target, stats) = (config.clone(), target.clone(), stats.clone());
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval_at(tokio::time::Instant::now() + period, period);
+            loop {
+                ticker.tick().await;
+                // The error is not Send, and the match awaits the stats lock.
+                let lookup = resolve_host(&config, target::parse(&target).host).await.map_err(|e| e.to_string());
+                match lookup {
+                    Ok(addrs) => {
+                        let list: Vec<String> = addrs.iter().map(IpAddr::to_string).collect();
+                        if stats.lock().await.record_resolution(addrs) {
+                            warn!(target = %target, "Resolution changed, now {}; still pinging {}", list.join(", "), ip_addr);
+                        }
+                    }
+                    Err(e) => warn!(target = %target, "Re-resolution failed: {}", e),
+                }
+            }
+        })
+    });
+
+    let reporter = config.report_every.map(|secs| {
+        let (config, target, stats) = (config.clone(), target.clone(), stats.clone());
+        tokio::spawn(async move {
+            let period = Duration::from_secs(secs.max(1));
+            let mut ticker = tokio::time::interval_at(tokio::time::Instant::now() + period, period);
+            loop {
+                ticker.tick().await;
+                let snapshot = stats.lock().await.clone();
+                if let Err(e) = print_report(&config, &target, &snapshot).await {
+                    error!("Failed to print report: {}", e);
+                }
+                let result = create_result(&config, &target, &snapshot);
+                if let Some(statsd) = &sinks.statsd {
+                    statsd.send(&result, &snapshot);
+                }
+                if let Some(webhook) = &sinks.webhook {
+                    match casing::to_json(&result, config.json_case, config.omit_null, false) {
+                        Ok(body) => webhook.send(body),
+                        Err(e) => error!("Failed to serialize report: {}", e),
+                    }
+                }
+            }
+        })
+    });
+    
+    loop {
+        if config.count > 0 && sequence >= config.count {
+            break;
+        }
+        if config.align && sequence == 0 {
+            slot = next_aligned(SystemTime::now(), interval);
+            sleep_until_wall(slot).await;
+        }
+
+        if let Some(wanted) = config.until_received {
+            if !room_for_reply(&mut in_flight, &stats, sequence, wanted).await {
+                break;
+            }
+        }
+
+        if let Some(limiter) = &limiter {
+            limiter.acquire().await;
+        }
+
+        let (source, probe) = &probes[sequence as usize % probes.len()];
+        let size = payload_size(&config, sequence);
+        let (delay, timeout) = {
+            let mut stats_guard = stats.lock().await;
+            if config.wait_down && stats_guard.consecutive_failures >= config.down_after {
+                info!(target = %target, sent = sequence, "Target is down");
+                break;
+            }
+            if config.fail_fast && certain_failure(&config, &stats_guard) {
+                info!(target = %target, sent = sequence, "Thresholds can no longer be met, stopping early");
+                break;
+            }
+            if let Some(max_packets) = config.max_packets {
+                // Taken once this packet is sure to go out, and before the
+                // send, so the targets cannot overshoot it together.
+                if PACKETS_SENT.fetch_add(1, Ordering::Relaxed) >= max_packets {
+                    if !stop.swap(true, Ordering::Relaxed) {
+                        warn!(max_packets, "Reached --max-packets, stopping");
+                    }
+                    break;
+                }
+            }
+            if let (true, Some(source)) = (config.per_source_stats, source) {
+                stats_guard.record_source_sent(*source);
+            }
+            if !config.size_sweep.is_empty() {
+                stats_guard.record_size_sent(size);
+            }
+            let delay = next_interval(&config, stats_guard.consecutive_failures);
+            stats_guard.backoff = delay;
+            (delay, packet_timeout(&config, &stats_guard))
+        };
+
+        let permit = window.reserve().await;
+        stats.lock().await.record_send(Instant::now());
+        // The ICMP sequence field is 16 bits and wraps with the packet number.
+        let seq = (config.seq_start as u32).wrapping_add(sequence);
+        let packet = send_packet(
+            (*source, probe.clone()),
+            config.clone(),
+            target.clone(),
+            ip_addr,
+            ident,
+            seq,
+            size,
+            timeout,
+            stats.clone(),
+            sinks.syslog.clone().filter(|_| config.syslog_packets),
+        );
+        let span = info_span!("packet", run_id = %config.run_id, target = %target, seq);
+        in_flight.spawn(async move {
+            packet.await;
+            drop(permit);
+        }.instrument(span));
+        while in_flight.try_join_next().is_some() {}
+
+        sequence += 1;
+        
+        if stop.load(Ordering::Relaxed) {
+            break;
+        }
+        
+        wait_for_next_send(&config, &mut slot, delay, &stop).await;
+    }
+
+    let mut drain_window = Duration::from_millis(config.drain.unwrap_or(config.timeout + config.late_window));
+    if let (Some(duration), true) = (config.duration, config.deadline_includes_drain) {
+        // Replies still out when the duration is up count as lost.
+        drain_window = drain_window.min(duration.saturating_sub(started.elapsed()));
+    }
+    drain(&mut in_flight, &stats, drain_window).await;
+
+    if let Some(reporter) = reporter {
+        reporter.abort();
+    }
+    if let Some(reresolver) = reresolver {
+        reresolver.abort();
+    }
+
+    let final_stats = stats.lock().await;
+    if config.timestamp_icmp && final_stats.sent > 0 && final_stats.received == 0 {
+        warn!(target = %target, "No ICMP Timestamp replies; many hosts filter them, try a plain echo ping");
+    }
+    if final_stats.suspicious_timing() {
+        warn!(
+            target = %target,
+            implausible = final_stats.implausible_rtts,
+            "RTTs below {}µs, latency figures may be measurement artifacts",
+            PLAUSIBLE_RTT_FLOOR.as_micros()
+        );
+    }
+    final_stats.clone()
+}
+
+/// Start pinging one resolved target, the `index`th of the run.
+#[allow(clippy::too_many_arguments)]
+fn spawn_target(
+    probes: &[(Option<IpAddr>, Probe)],
+    config: &Arc<Args>,
+    target: &str,
+    addrs: &[IpAddr],
+    index: usize,
+    stop: &Arc<AtomicBool>,
+    limiter: &Option<Arc<RateLimiter>>,
+    sinks: &Sinks,
+) -> JoinHandle<PingStats> {
+    // A port in the target overrides --tcp's for that target.
+    let port = target::parse(target).port;
+    let probes = probes
+        .iter()
+        .map(|(source, probe)| match (probe, port) {
+            (Probe::Tcp(_), Some(port)) => (*source, Probe::Tcp(port)),
+            _ => (*source, probe.clone()),
+        })
+        .collect();
+    tokio::spawn(ping_target(
+        probes,
+        config.clone(),
+        target.to_string(),
+        addrs.to_vec(),
+        config.identifier(index),
+        stop.clone(),
+        limiter.clone(),
+        sinks.clone(),
+    ))
+}
+
+/// Ping every resolved target once for the configured run and collect the
+/// results, with DNS failures reported in place.
+async fn measure(
+    probes: &[(Option<IpAddr>, Probe)],
+    config: &Arc<Args>,
+    resolved: &[Resolved],
+    stop: &Arc<AtomicBool>,
+    limiter: &Option<Arc<RateLimiter>>,
+    sinks: &Sinks,
+) -> Result<Vec<(PingResult, PingStats)>, tokio::task::JoinError> {
+    let handles: Vec<_> = resolved
+        .iter()
+        .enumerate()
+        .map(|(index, (target, addrs, _, _))| {
+            (!addrs.is_empty()).then(|| spawn_target(probes, config, target, addrs, index, stop, limiter, sinks))
+        })
+        .collect();
+
+    let mut reports = Vec::with_capacity(resolved.len());
+    for ((target, _, failure, lookup), handle) in resolved.iter().zip(handles) {
+        match (handle, failure) {
+            (Some(handle), _) => {
+                let mut stats = handle.await?;
+                stats.lookup = *lookup;
+                reports.push((create_result(config, target, &stats), stats));
+            }
+            (None, failure) => {
+                let (kind, error) = failure.clone().unwrap_or((ErrorKind::Dns, String::new()));
+                reports.push((failure_result(config, target, kind, error), PingStats::new()));
+            }
+        }
+    }
+    Ok(reports)
+}
+
+/// Ping every target until its first reply, for `--gate-on-first-reply`,
+/// and say per target how long that took. A target still silent at
+/// `--gate-timeout` is measured anyway.
+async fn pass_gate(
+    probes: &[(Option<IpAddr>, Probe)],
+    config: &Arc<Args>,
+    resolved: &[Resolved],
+    stop: &Arc<AtomicBool>,
+    limiter: &Option<Arc<RateLimiter>>,
+) -> Result<Vec<(String, Gate)>, tokio::task::JoinError> {
+    let gate_config = Arc::new(Args {
+        count: 0,
+        until_received: Some(1),
+        fail_fast: false,
+        report_every: None,
+        ..(**config).clone()
+    });
+    // Ends the gate at its timeout, or with the run on Ctrl+C.
+    let gate_stop = Arc::new(AtomicBool::new(false));
+    let deadline = {
+        let (gate_stop, stop, timeout) = (gate_stop.clone(), stop.clone(), config.gate_timeout);
+        tokio::spawn(async move {
+            let end = Instant::now() + timeout;
+            while !stop.load(Ordering::Relaxed) && Instant::now() < end {
+                tokio::time::sleep(end.saturating_duration_since(Instant::now()).min(Duration::from_millis(100))).await;
+            }
+            gate_stop.store(true, Ordering::Relaxed);
+        })
+    };
+    if !config.quiet {
+        info!("Waiting for a first reply before measuring");
+    }
+    let started = SystemTime::now();
+    let gated = measure(probes, &gate_config, resolved, &gate_stop, limiter, &Sinks::default()).await?;
+    deadline.abort();
+
+    let gates = gated.into_iter().filter(|(result, _)| !result.setup_failed()).map(|(result, stats)| {
+        let waited = stats.samples.first().map(|sample| sample.at.duration_since(started).unwrap_or_default());
+        if waited.is_none() && !stop.load(Ordering::Relaxed) {
+            warn!(target = %result.servername, "No reply within --gate-timeout, measuring anyway");
+        }
+        let gate = Gate {
+            packets: stats.sent,
+            waited_ms: waited.map(|waited| waited.as_millis() as u64),
+        };
+        (result.servername, gate)
+    });
+    Ok(gates.collect())
+}
+
+/// `measure` once per `--rounds` round, `--round-gap` apart, and the rounds
+/// pooled per target, each result listing its rounds. Ctrl+C ends the
+/// rounds early; those measured still count.
+async fn measure_rounds(
+    probes: &[(Option<IpAddr>, Probe)],
+    config: &Arc<Args>,
+    resolved: &[Resolved],
+    stop: &Arc<AtomicBool>,
+    limiter: &Option<Arc<RateLimiter>>,
+    sinks: &Sinks,
+) -> Result<Vec<(PingResult, PingStats)>, tokio::task::JoinError> {
+    if config.rounds <= 1 {
+        return measure(probes, config, resolved, stop, limiter, sinks).await;
+    }
+    let mut rounds = Vec::new();
+    for round in 1..=config.rounds {
+        if round > 1 {
+            let resume = Instant::now() + config.round_gap.unwrap_or_default();
+            while !stop.load(Ordering::Relaxed) && Instant::now() < resume {
+                tokio::time::sleep(resume.saturating_duration_since(Instant::now()).min(Duration::from_millis(100))).await;
+            }
+            if stop.load(Ordering::Relaxed) {
+                break;
+            }
+        }
+        if !config.quiet {
+            info!("Measuring round {} of {}", round, config.rounds);
+        }
+        rounds.push(measure(probes, config, resolved, stop, limiter, sinks).await?);
+        if stop.load(Ordering::Relaxed) {
+            break;
+        }
+    }
+
+    let pooled = (0..resolved.len()).map(|index| {
+        let (first, first_stats) = &rounds[0][index];
+        let phases = Some(rounds.iter().map(|round| Phase::of(&round[index].0)).collect());
+        if first.setup_failed() {
+            return (PingResult { rounds: phases, ..first.clone() }, first_stats.clone());
+        }
+        let mut stats = first_stats.clone();
+        for round in &rounds[1..] {
+            stats.absorb(&round[index].1);
+        }
+        let result = PingResult { rounds: phases, ..create_result(config, &resolved[index].0, &stats) };
+        (result, stats)
+    });
+    Ok(pooled.collect())
+}
+
+/// A target of a `--targets-refresh` run: one run per stretch it was listed,
+/// the flag that stops the current one, and why it last failed to resolve
+/// or was refused.
+struct Discovered {
+    target: String,
+    runs: Vec<JoinHandle<PingStats>>,
+    stop: Arc<AtomicBool>,
+    failure: Option<(ErrorKind, String)>,
+    lookup: Option<Duration>,
+}
+
+impl Discovered {
+    fn running(&self) -> bool {
+        self.runs.last().is_some_and(|run| !run.is_finished())
+    }
+}
+
+/// `measure` for `--targets-refresh`: the `--targets-from` list is fetched
+/// again every `every`, targets that appear on it are resolved and pinged,
+/// and those that leave it are stopped. A failed fetch keeps the last list,
+/// and the targets in `fixed`, given on the command line, stay whatever it
+/// says. Every target listed at some point is reported with what it
+/// measured while listed; one listed again later pools its stretches, as
+/// `--rounds` pools rounds. In endless mode only Ctrl+C ends the run, even
+/// with nothing listed; otherwise it ends when the running targets are done.
+#[allow(clippy::too_many_arguments)]
+async fn measure_discovered(
+    probes: &[(Option<IpAddr>, Probe)],
+    config: &Arc<Args>,
+    resolved: &[Resolved],
+    stop: &Arc<AtomicBool>,
+    limiter: &Option<Arc<RateLimiter>>,
+    sinks: &Sinks,
+    fixed: &[String],
+    every: Duration,
+) -> Result<Vec<(PingResult, PingStats)>, tokio::task::JoinError> {
+    let url = config.targets_from.clone().unwrap_or_default();
+    let mut targets: Vec<Discovered> = Vec::with_capacity(resolved.len());
+    for (index, (target, addrs, failure, lookup)) in resolved.iter().enumerate() {
+        let target_stop = Arc::new(AtomicBool::new(false));
+        let runs = match addrs.is_empty() {
+            true => Vec::new(),
+            false => vec![spawn_target(probes, config, target, addrs, index, &target_stop, limiter, sinks)],
+        };
+        targets.push(Discovered {
+            target: target.clone(),
+            runs,
+            stop: target_stop,
+            failure: failure.clone(),
+            lookup: *lookup,
+        });
+    }
+
+    let mut fetching: Option<JoinHandle<Result<Vec<String>, String>>> = None;
+    let mut next_fetch = Instant::now() + every;
+    while !stop.load(Ordering::Relaxed) && (config.count == 0 || targets.iter().any(Discovered::running)) {
+        if fetching.is_none() && Instant::now() >= next_fetch {
+            next_fetch = Instant::now() + every;
+            let url = url.clone();
+            fetching = Some(tokio::spawn(async move { discovery::fetch(&url).await }));
+        }
+        let listed = match fetching.take_if(|fetch| fetch.is_finished()) {
+            Some(fetch) => fetch.await?,
+            None => {
+                tokio::time::sleep(Duration::from_millis(100)).await;
+                continue;
+            }
+        };
+        let listed: Vec<String> = match listed {
+            Ok(listed) => fixed.iter().cloned().chain(listed.into_iter().filter(|target| !fixed.contains(target))).collect(),
+            Err(e) => {
+                warn!("{}, keeping the last list", e);
+                continue;
+            }
+        };
+
+        for entry in targets.iter_mut().filter(|entry| !listed.contains(&entry.target)) {
+            if !entry.stop.swap(true, Ordering::Relaxed) && entry.running() && !config.quiet {
+                info!(target = %entry.target, "No longer listed by --targets-from, stopping");
+            }
+        }
+        for target in listed {
+            let existing = targets.iter().position(|entry| entry.target == target);
+            // A target listed again restarts once its last run has drained,
+            // so the two never share an ICMP identifier.
+            let start = existing.is_none_or(|index| {
+                let entry = &targets[index];
+                entry.runs.is_empty() || (entry.stop.load(Ordering::Relaxed) && !entry.running())
+            });
+            if !start {
+                continue;
+            }
+            // Not again for one whose lookup is being retried.
+            if !config.quiet && existing.is_none_or(|index| !targets[index].runs.is_empty()) {
+                info!(target = %target, "Listed by --targets-from, starting");
+            }
+            let index = existing.unwrap_or_else(|| {
+                targets.push(Discovered {
+                    target: target.clone(),
+                    runs: Vec::new(),
+                    stop: Arc::new(AtomicBool::new(false)),
+                    failure: None,
+                    lookup: None,
+                });
+                targets.len() - 1
+            });
+            let entry = &mut targets[index];
+            match resolve_target(config, &target).await {
+                Ok((_, addrs, _, lookup)) => {
+                    entry.stop = Arc::new(AtomicBool::new(false));
+                    entry.failure = None;
+                    entry.lookup = entry.lookup.or(lookup);
+                    entry.runs.push(spawn_target(probes, config, &target, &addrs, index, &entry.stop, limiter, sinks));
+                }
+                Err((kind, e)) => {
+                    warn!(target = %target, "{}, retrying at the next refresh", e);
+                    entry.failure = Some((kind, e));
+                }
+            }
+        }
+    }
+    if let Some(fetch) = fetching {
+        fetch.abort();
+    }
+    for entry in &targets {
+        entry.stop.store(true, Ordering::Relaxed);
+    }
+
+    let mut reports = Vec::with_capacity(targets.len());
+    for entry in targets {
+        let mut pooled: Option<PingStats> = None;
+        for run in entry.runs {
+            let run = run.await?;
+            match &mut pooled {
+                Some(stats) => stats.absorb(&run),
+                None => pooled = Some(run),
+            }
+        }
+        match pooled {
+            Some(mut stats) => {
+                stats.lookup = entry.lookup;
+                reports.push((create_result(config, &entry.target, &stats), stats));
+            }
+            None => {
+                let (kind, error) = entry.failure.unwrap_or((ErrorKind::Dns, String::new()));
+                reports.push((failure_result(config, &entry.target, kind, error), PingStats::new()));
+            }
+        }
+    }
+    Ok(reports)
+}
+
+/// Record on the relay target's result how much slower it is than the
+/// direct host, the second report.
+fn compare_direct(mut reports: Vec<(PingResult, PingStats)>) -> Vec<(PingResult, PingStats)> {
+    if let [(relay, relay_stats), (direct, direct_stats)] = reports.as_mut_slice() {
+        let avg_ms = |stats: &PingStats| (stats.received > 0).then(|| stats.avg_rtt().as_micros() as f64 / 1000.0);
+        relay.comparison = Some(Comparison {
+            direct: direct.servername.clone(),
+            relay_overhead_ms: avg_ms(relay_stats).zip(avg_ms(direct_stats)).map(|(relay, direct)| relay - direct),
+        });
+    }
+    reports
+}
+
+/// What `--self-test` pings.
+const SELF_TEST_TARGET: &str = "127.0.0.1";
+
+/// One `--self-test` check: its name and what was found, Err if it failed.
+type Check = (&'static str, Result<String, String>);
+
+/// Judge a `--self-test` run: the socket came up, loopback answered, the
+/// RTTs are plausible and the result survives a JSON round trip.
+fn self_test_checks(probe: &Probe, reports: &[(PingResult, PingStats)]) -> Vec<Check> {
+    let mut checks: Vec<Check> = Vec::new();
+    if let Probe::Icmp(client) = probe {
+        let kind = if client.privileged() { "raw ICMP socket" } else { "unprivileged ICMP ping socket" };
+        checks.push(("socket", Ok(kind.to_string())));
+    }
+    let Some((result, stats)) = reports.first() else {
+        return checks;
+    };
+    checks.push((
+        "replies",
+        match stats.received {
+            0 => Err(format!(
+                "no reply to {} packets from {}: {}",
+                stats.sent,
+                SELF_TEST_TARGET,
+                result.error.as_deref().unwrap_or("no error recorded")
+            )),
+            received => Ok(format!("{} of {} from {}", received, stats.sent, SELF_TEST_TARGET)),
+        },
+    ));
+    let ms = |rtt: Duration| rtt.as_secs_f64() * 1000.0;
+    checks.push((
+        "timing",
+        match stats.rtt_range {
+            _ if stats.clock_step_detected => Err("the system clock stepped during the run".to_string()),
+            Some((min, max)) if min.is_zero() || max >= Duration::from_secs(1) => {
+                Err(format!("implausible loopback RTTs, {:.3} to {:.3} ms", ms(min), ms(max)))
+            }
+            Some((min, max)) => Ok(format!("RTTs {:.3} to {:.3} ms", ms(min), ms(max))),
+            None => Err("no RTT measured".to_string()),
+        },
+    ));
+    let round_trip = serde_json::to_string(result)
+        .and_then(|json| serde_json::from_str::<PingResult>(&json))
+        .map_err(|e| e.to_string());
+    checks.push((
+        "json",
+        match round_trip {
+            Ok(parsed) if parsed.data.as_ref().map(|data| data.packets_received) == Some(stats.received) => {
+                Ok("the result serializes and reads back".to_string())
+            }
+            Ok(_) => Err("the result read back differs".to_string()),
+            Err(e) => Err(e),
+        },
+    ));
+    checks
+}
+
+/// Print the `--self-test` verdict, one line per check, and turn it into
+/// the exit status.
+fn print_self_test(checks: &[Check]) -> Status {
+    for (name, check) in checks {
+        match check {
+            Ok(detail) => println!("self-test {}: ok ({})", name, detail),
+            Err(detail) => println!("self-test {}: FAIL ({})", name, detail),
+        }
+    }
+    // The ICMP client speaks IPv4 only, so ::1 cannot be pinged.
+    println!("self-test ipv6: skipped (ICMP probing is IPv4 only)");
+    match checks.iter().all(|(_, check)| check.is_ok()) {
+        true => {
+            println!("self-test: PASS");
+            Status::Ok
+        }
+        false => {
+            println!("self-test: FAIL");
+            Status::Critical
+        }
+    }
+}
+
+/// Whether `--target-fallback` should take over from this result.
+fn primary_unusable(result: &PingResult) -> bool {
+    matches!(result.error_kind, Some(ErrorKind::Dns | ErrorKind::Timeout | ErrorKind::Unreachable))
+}
+
+/// Measure `fallback` in place of the failed `primary`. The result keeps the
+/// primary's name, so it reads as the same check, and records the failover.
+/// None when the fallback does not resolve either, or resolves to an
+/// address `--public-only` or `--private-only` refuses.
+async fn fail_over(
+    probes: &[(Option<IpAddr>, Probe)],
+    config: &Arc<Args>,
+    fallback: &str,
+    primary: &PingResult,
+    stop: &Arc<AtomicBool>,
+    limiter: &Option<Arc<RateLimiter>>,
+    sinks: &Sinks,
+) -> Result<Option<Vec<(PingResult, PingStats)>>, Box<dyn std::error::Error>> {
+    let Some(primary_error_kind) = primary.error_kind else {
+        return Ok(None);
+    };
+    let host = target::parse(fallback).host;
+    let started = Instant::now();
+    let addrs = match resolve_host(config, host).await {
+        Ok(addrs) => addrs,
+        Err(e) => {
+            warn!(target = %fallback, "Fallback target did not resolve either: {}", e);
+            return Ok(None);
+        }
+    };
+    if let Err(e) = check_address_scope(config, &addrs) {
+        warn!(target = %fallback, "Fallback target unusable too: {}", e);
+        return Ok(None);
+    }
+    let lookup = host.parse::<IpAddr>().is_err().then(|| started.elapsed());
+    if !config.quiet {
+        warn!(
+            target = %primary.servername,
+            fallback = %fallback,
+            "Primary target unusable ({}), failing over",
+            primary.error.as_deref().unwrap_or_default()
+        );
+    }
+    let mut reports = measure(probes, config, &[(fallback.to_string(), addrs, None, lookup)], stop, limiter, sinks).await?;
+    for (result, _) in &mut reports {
+        result.servername = primary.servername.clone();
+        result.failover = Some(Failover {
+            target: fallback.to_string(),
+            primary_error_kind,
+            primary_error: primary.error.clone().unwrap_or_default(),
+        });
+    }
+    Ok(Some(reports))
+}
+
+/// Deliver the final results: to the sinks, compared against the baseline,
+/// printed, and saved with `--save-baseline`. Returns the worst status.
+async fn report(
+    config: &Args,
+    mut reports: Vec<(PingResult, PingStats)>,
+    baseline: &[PingResult],
+    sinks: &Sinks,
+) -> Result<Status, Box<dyn std::error::Error>> {
+    if let Some(statsd) = &sinks.statsd {
+        for (result, stats) in &reports {
+            if !result.setup_failed() {
+                statsd.send(result, stats);
+            }
+        }
+    }
+    if let Some(syslog) = &sinks.syslog {
+        for (result, stats) in &reports {
+            syslog.send(result.status.into(), &syslog_summary(result, stats));
+        }
+    }
+
+    for (result, _) in &mut reports {
+        if let Some(previous) = baseline.iter().find(|b| b.servername == result.servername) {
+            result.delta = baseline::compare(result, previous);
+        }
+    }
+    if let Some(webhook) = &sinks.webhook {
+        for (result, _) in &reports {
+            webhook.send(casing::to_json(result, config.json_case, config.omit_null, false)?);
+        }
+        webhook.finish().await;
+    }
+
+    let status = reports.iter().map(|(result, _)| result.status).max().unwrap_or_default();
+    for (result, _) in &reports {
+        EXIT_BITS.fetch_or(result.exit_bits(), Ordering::Relaxed);
+    }
+    let silent = config.only_on_failure && status == Status::Ok;
+    let human = config.format == OutputFormat::Human;
+    if silent {
+        // Silence means OK; the exit status says the same.
+    } else if multi_target_table(config) {
+        print_summary_table(config, &reports);
+    } else if !config.quiet || human {
+        for (result, stats) in &reports {
+            if !result.setup_failed() {
+                print_statistics(config, &result.servername, stats).await;
+            }
+        }
+    }
+
+    if human && !silent {
+        for (result, _) in &reports {
+            if let Some(Comparison { direct, relay_overhead_ms: Some(overhead) }) = &result.comparison {
+                println!(
+                    "relay overhead = {:+.*} ms ({} vs {} direct)",
+                    precision(config, 3),
+                    overhead,
+                    result.servername,
+                    direct
+                );
+            }
+        }
+    }
+
+    if !silent {
+        print_results(config, &reports)?;
+    }
+
+    if let Some(path) = &config.save_baseline {
+        let results: Vec<&PingResult> = reports.iter().map(|(result, _)| result).collect();
+        baseline::save(path, &results)?;
+    }
+
+    Ok(status)
+}
+
+/// A target as given, the addresses it resolved to, the first of which is
+/// pinged, or why it is not pinged, and how long the lookup took (None for
+/// an IP address).
+type Resolved = (String, Vec<IpAddr>, Option<(ErrorKind, String)>, Option<Duration>);
+
+/// Resolve one target, warning about a port ICMP cannot use, and check
+/// what it resolved to against `--public-only` and `--private-only`.
+async fn resolve_target(config: &Args, target: &str) -> Result<Resolved, (ErrorKind, String)> {
+    let parsed = target::parse(target);
+    if let (Some(port), None, false) = (parsed.port, config.tcp, config.quiet) {
+        warn!(target = %target, "Ignoring port {}: ICMP pings the host only", port);
+    }
+    let started = Instant::now();
+    let addrs = resolve_host(config, parsed.host)
+        .await
+        .map_err(|e| (ErrorKind::Dns, format!("DNS resolution failed: {}", e)))?;
+    check_address_scope(config, &addrs).map_err(|e| (ErrorKind::AddressScope, e))?;
+    let lookup = parsed.host.parse::<IpAddr>().is_err().then(|| started.elapsed());
+    if config.verbose > 0 {
+        let all: Vec<String> = addrs.iter().map(IpAddr::to_string).collect();
+        let resolver = config.dns_server.map_or("the system resolver".to_string(), |server| server.to_string());
+        info!(
+            target = %target,
+            "Resolved via {} to {}; using {} ({})",
+            resolver,
+            all.join(", "),
+            addrs[0],
+            family(addrs[0])
+        );
+    }
+    Ok((target.to_string(), addrs, None, lookup))
+}
+
+/// Everything before the first packet that may block: resolving the
+/// targets and opening the sockets. Failures are printed as results here.
+async fn set_up(config: &Args) -> Result<(Vec<Resolved>, Vec<(Option<IpAddr>, Probe)>), Box<dyn std::error::Error>> {
+    if let Some(vrf) = &config.vrf {
+        if let Err(e) = vrf::check(vrf) {
+            print_results(config, &setup_failure(config, ErrorKind::Config, &e))?;
+            return Err(e.into());
+        }
+    }
+
+    let mut resolved = Vec::with_capacity(config.targets.len());
+    for target in &config.targets {
+        match resolve_target(config, target).await {
+            Ok(target) => resolved.push(target),
+            Err((kind, e)) if config.targets.len() == 1 && config.target_fallback.is_none() && config.targets_refresh.is_none() => {
+                print_results(config, &[(failure_result(config, target, kind, e.clone()), PingStats::new())])?;
+                return Err(e.into());
+            }
+            Err(failure) => resolved.push((target.clone(), Vec::new(), Some(failure), None)),
+        }
+    }
+
+    if !config.quiet && config.count == 0 && config.until_received.is_none() && !config.wait_down {
+        match config.max_packets {
+            Some(max_packets) => info!("Running in endless mode, up to {} packets. Press Ctrl+C to stop.", max_packets),
+            None => info!("Running in endless mode. Press Ctrl+C to stop."),
+        }
+    }
+
+    if let Some(path) = &config.event_log {
+        if let Err(e) = event_log::open(path, config.event_log_max_size, config.event_log_rotate_every, config.event_log_keep) {
+            let kind = setup_error_kind(e.kind());
+            let e = format!("Failed to open --event-log {}: {}", path, e);
+            print_results(config, &setup_failure(config, kind, &e))?;
+            return Err(e.into());
+        }
+    }
+
+    if let Some(path) = &config.event_socket {
+        if let Err(e) = event_socket::listen(path) {
+            let kind = setup_error_kind(e.kind());
+            let e = format!("Failed to listen on --event-socket {}: {}", path, e);
+            print_results(config, &setup_failure(config, kind, &e))?;
+            return Err(e.into());
+        }
+    }
+
+    let capture = match config.pcap.as_deref().map(|path| pcap::Capture::create(path, config.mark, config.vrf.clone())).transpose() {
+        Ok(capture) => capture.map(Arc::new),
+        Err(e) => {
+            let kind = setup_error_kind(e.kind());
+            let e = format!("Failed to create pcap file: {}", e);
+            print_results(config, &setup_failure(config, kind, &e))?;
+            return Err(e.into());
+        }
+    };
+
+    // One source for each probe; None leaves the choice to the kernel.
+    let sources: Vec<Option<IpAddr>> = match config.source_rotate.as_slice() {
+        [] => vec![None],
+        rotate => rotate.iter().copied().map(Some).collect(),
+    };
+    let probes: Vec<(Option<IpAddr>, Probe)> = match config.tcp {
+        Some(port) => {
+            let source_port_check = config.source_port.zip(resolved.iter().find_map(|(_, addrs, _, _)| addrs.first().copied()));
+            if let Some((source_port, ip_addr)) = source_port_check {
+                if let Err((kind, e)) = tcp::check_source_port(ip_addr, source_port) {
+                    let kind = setup_error_kind(kind);
+                    print_results(config, &setup_failure(config, kind, &e))?;
+                    return Err(e.into());
+                }
+            }
+            sources.into_iter().map(|source| (source, Probe::Tcp(port))).collect()
+        }
+        None => {
+            let mut probes = Vec::with_capacity(sources.len());
+            for source in sources {
+                let mut builder = Config::builder().kind(ICMP::V4).ttl(config.ttl as u32);
+                if let Some(source) = source {
+                    builder = builder.bind(std::net::SocketAddr::new(source, 0));
+                }
+                if let Some(vrf) = &config.vrf {
+                    builder = builder.interface(vrf);
+                }
+                let client = match EchoClient::new(&builder.build(), capture.clone(), !config.expect_source.is_empty() || config.anycast || config.responders) {
+                    Ok(client) => client.with_late_window(Duration::from_millis(config.late_window)),
+                    Err(e) => {
+                        let kind = setup_error_kind(e.kind());
+                        let e = match source {
+                            Some(source) => format!("Failed to bind ICMP socket to {}: {}", source, e),
+                            None => format!("Failed to create ICMP socket: {}", e),
+                        };
+                        print_results(config, &setup_failure(config, kind, &e))?;
+                        if config.self_test {
+                            println!("self-test socket: FAIL ({}; needs root, CAP_NET_RAW or a net.ipv4.ping_group_range that includes this group)", e);
+                            println!("self-test: FAIL");
+                        }
+                        return Err(e.into());
+                    }
+                };
+
+                match client.socket_ttl() {
+                    Ok(ttl) if ttl != config.ttl as u32 => {
+                        warn!("The ICMP socket applied TTL {} instead of --ttl {}", ttl, config.ttl);
+                    }
+                    _ => {}
+                }
+
+                if (config.bandwidth || config.timestamp_icmp) && !client.privileged() {
+                    let flag = if config.bandwidth { "--bandwidth" } else { "--timestamp-icmp" };
+                    let e = format!("{} needs a raw ICMP socket (root or CAP_NET_RAW)", flag);
+                    print_results(config, &setup_failure(config, ErrorKind::Permission, &e))?;
+                    return Err(e.into());
+                }
+
+                if let Some(mark) = config.mark {
+                    if let Err((kind, e)) = set_socket_mark(&client, mark) {
+                        print_results(config, &setup_failure(config, kind, &e))?;
+                        return Err(e.into());
+                    }
+                }
+                if config.dont_fragment {
+                    if let Err(e) = client.set_dont_fragment() {
+                        let e = format!("Failed to set the Don't Fragment bit: {}", e);
+                        print_results(config, &setup_failure(config, ErrorKind::Config, &e))?;
+                        return Err(e.into());
+                    }
+                }
+                if config.broadcast {
+                    if let Err(e) = client.enable_one_to_many() {
+                        let kind = setup_error_kind(e.kind());
+                        let e = format!("Failed to enable broadcast and multicast on the ICMP socket: {}", e);
+                        print_results(config, &setup_failure(config, kind, &e))?;
+                        return Err(e.into());
+                    }
+                }
+                probes.push((source, Probe::Icmp(client)));
+            }
+            probes
+        }
+    };
+    Ok((resolved, probes))
+}
+
+/// The exit code for the worst status of the run, or for a run that could
+/// not start when None.
+fn exit_code(status: Option<Status>) -> ExitCode {
+    if !RICH_EXIT_CODES.load(Ordering::Relaxed) {
+        return status.unwrap_or(Status::Critical).exit_code();
+    }
+    let bits = EXIT_BITS.load(Ordering::Relaxed);
+    match status {
+        None => ExitCode::from(bits | EXIT_SETUP),
+        // --await-healthy can fail a run whose last results passed.
+        Some(Status::Critical) if bits & !EXIT_DEGRADED == 0 => ExitCode::from(bits | EXIT_OTHER),
+        Some(_) => ExitCode::from(bits),
+    }
+}
+
+fn main() -> ExitCode {
+    let runtime = match tokio::runtime::Runtime::new() {
+        Ok(runtime) => runtime,
+        Err(e) => {
+            eprintln!("Error: failed to start the runtime: {}", e);
+            return Status::Critical.exit_code();
+        }
+    };
+    let code = match runtime.block_on(run()) {
+        Ok(Status::Ok) => exit_code(Some(Status::Ok)),
+        Ok(status) => {
+            release_logs();
+            exit_code(Some(status))
+        }
+        Err(e) => {
+            release_logs();
+            eprintln!("Error: {:?}", e);
+            exit_code(None)
+        }
+    };
+    // A lookup still wedged past --startup-timeout must not hold up the
+    // exit, as dropping the runtime would until it returns.
+    runtime.shutdown_background();
+    code
+}
+
+async fn run() -> Result<Status, Box<dyn std::error::Error>> {
+    let matches = Args::command().get_matches();
+    let mut config = Args::from_arg_matches(&matches).unwrap_or_else(|e| e.exit());
+    RICH_EXIT_CODES.store(config.rich_exit_codes, Ordering::Relaxed);
+    let given = |id: &str| matches.value_source(id) == Some(ValueSource::CommandLine);
+    // Everything past this point works in milliseconds.
+    config.interval = config.interval_unit.millis(config.interval);
+    config.timeout = config.timeout_unit.millis(config.timeout);
+    if given("min_timeout") {
+        config.min_timeout = config.timeout_unit.millis(config.min_timeout);
+    }
+    let schedule = apply_duration(&mut config, given("count"), given("interval"));
+    if config.wait_down && !given("count") {
+        config.count = 0;
+    }
+    if config.only_on_failure {
+        config.quiet = true;
+    }
+    if config.tui && !given("format") {
+        config.format = OutputFormat::Json;
+    }
+    if config.bandwidth {
+        if !given("interval") {
+            config.interval = 0;
+        }
+        if !given("size") {
+            config.size = BANDWIDTH_SIZE;
+        }
+        if !given("window") {
+            config.window = config.window.max(config.count as usize);
+        }
+    }
+    config
+        .prober_host
+        .get_or_insert_with(|| gethostname::gethostname().to_string_lossy().into_owned());
+    if config.self_test {
+        config.targets = vec![SELF_TEST_TARGET.to_string()];
+        if !given("count") {
+            config.count = 3;
+        }
+    }
+    let compare = match (&config.compare_direct, config.targets.len()) {
+        (Some(direct), 1) => {
+            config.targets.push(direct.clone());
+            Ok(())
+        }
+        (Some(_), _) => Err("--compare-direct compares a single relay target".to_string()),
+        (None, _) => Ok(()),
+    };
+    let replayed = config.replay.as_deref().map(|path| replay::load(&config, path)).transpose()?;
+    if let Some(replayed) = &replayed {
+        config.targets = replayed.iter().map(|(target, _)| target.clone()).collect();
+    }
+    let given_targets = config.targets.clone();
+    let discovered = match config.targets_from.clone() {
+        Some(url) => discovery::fetch(&url).await.and_then(|listed| {
+            if listed.is_empty() && config.targets.is_empty() {
+                return Err(format!("--targets-from {} listed no targets", url));
+            }
+            for target in listed {
+                if !config.targets.contains(&target) {
+                    config.targets.push(target);
+                }
+            }
+            Ok(())
+        }),
+        None => Ok(()),
+    };
+    let config = Arc::new(config);
+    init_logging(&config);
+
+    if let Err(e) = schedule.and(compare).and(discovered).and_then(|()| validate_args(&config)) {
+        print_results(&config, &setup_failure(&config, ErrorKind::Config, &e))?;
+        return Err(e.into());
+    }
+
+    let baseline = match config.baseline.as_deref().map(baseline::load).transpose() {
+        Ok(baseline) => baseline.unwrap_or_default(),
+        Err(e) => {
+            print_results(&config, &setup_failure(&config, ErrorKind::Config, &e))?;
+            return Err(e.into());
+        }
+    };
+
+    if let Some(replayed) = replayed {
+        let reports = replayed
+            .into_iter()
+            .map(|(target, stats)| (create_result(&config, &target, &stats), stats))
+            .collect();
+        let sinks = open_sinks(&config)?;
+        return report(&config, reports, &baseline, &sinks).await;
+    }
+
+    let startup = tokio::time::timeout(config.startup_timeout, set_up(&config)).await;
+    let (resolved, probes) = match startup {
+        Ok(setup) => setup?,
+        Err(_) => {
+            let e = format!(
+                "Resolution and socket setup did not finish within --startup-timeout ({}ms)",
+                config.startup_timeout.as_millis()
+            );
+            print_results(&config, &setup_failure(&config, ErrorKind::Timeout, &e))?;
+            return Err(e.into());
+        }
+    };
+
+    let probe = &probes[0].1;
+
+    if config.dont_fragment {
+        for (target, addrs, _, _) in &resolved {
+            if let Err(e) = addrs.first().map_or(Ok(()), |ip_addr| check_packet_fits(&config, target, *ip_addr)) {
+                print_results(&config, &setup_failure(&config, ErrorKind::Config, &e))?;
+                return Err(e.into());
+            }
+        }
+    }
+
+    if config.verbose > 0 {
+        for (source, probe) in &probes {
+            log_diagnostics(&config, *source, probe);
+        }
+        log_timing(&config);
+    }
+
+    let stop = Arc::new(AtomicBool::new(false));
+    let ctrl_c_stop = stop.clone();
+    tokio::spawn(async move {
+        if monitor_ctrl_c().await.is_ok() {
+            INTERRUPTED.store(true, Ordering::Relaxed);
+            ctrl_c_stop.store(true, Ordering::Relaxed);
+        }
+    });
+
+    if let (true, Probe::Icmp(client)) = (config.mtr, probe) {
+        if let (target, Some(ip_addr)) = (&resolved[0].0, resolved[0].1.first()) {
+            let hops = mtr::run(client, &config, target, *ip_addr, &stop).await;
+            match config.format {
+                OutputFormat::Human if config.quiet => mtr::print_table(target, &hops),
+                OutputFormat::Human | OutputFormat::None => {}
+                _ => {
+                    let reports: Vec<mtr::HopReport> = hops.iter().map(|hop| hop.report()).collect();
+                    println!("{}", casing::to_json(&reports, config.json_case, config.omit_null, true)?);
+                }
+            }
+            return Ok(mtr::status(&config, &hops, *ip_addr));
+        }
+        return Ok(Status::Critical);
+    }
+
+    let sinks = match open_sinks(&config) {
+        Ok(sinks) => sinks,
+        Err(e) => {
+            print_results(&config, &setup_failure(&config, ErrorKind::Config, &e))?;
+            return Err(e.into());
+        }
+    };
+
+    let deadline = match (config.await_healthy, config.wait_down) {
+        (true, _) => Some(config.await_timeout),
+        (_, true) => Some(config.wait_timeout),
+        _ => None,
+    };
+    if let Some(deadline) = deadline {
+        let deadline_stop = stop.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(deadline).await;
+            deadline_stop.store(true, Ordering::Relaxed);
+        });
+    }
+
+    let limiter = config.max_pps.map(|pps| Arc::new(RateLimiter::new(pps)));
+    let dashboard = config.tui.then(|| tui::Dashboard::open(config.clone(), stop.clone()));
+    let gates = match config.gate_on_first_reply {
+        true => pass_gate(&probes, &config, &resolved, &stop, &limiter).await?,
+        false => Vec::new(),
+    };
+    let warmup = match config.warmup_count() {
+        Some(count) => {
+            // The warmup sends all its packets, and reports only at the end.
+            let warmup_config = Arc::new(Args {
+                count,
+                until_received: None,
+                fail_fast: false,
+                report_every: None,
+                ..(*config).clone()
+            });
+            if !config.quiet {
+                info!(count, "Warming up before the measurement");
+            }
+            Some(measure(&probes, &warmup_config, &resolved, &stop, &limiter, &Sinks::default()).await?)
+        }
+        None => None,
+    };
+    let mut window = 1;
+    let (reports, healthy) = loop {
+        let reports = match config.targets_refresh {
+            Some(every) => {
+                measure_discovered(&probes, &config, &resolved, &stop, &limiter, &sinks, &given_targets, every).await?
+            }
+            None => measure_rounds(&probes, &config, &resolved, &stop, &limiter, &sinks).await?,
+        };
+        // A window cut short by Ctrl+C or --await-timeout does not count.
+        let healthy = !stop.load(Ordering::Relaxed)
+            && reports.iter().all(|(result, _)| result.status == Status::Ok);
+        if !config.await_healthy || healthy || stop.load(Ordering::Relaxed) {
+            break (reports, healthy);
+        }
+        if !config.quiet {
+            info!(window, "Not healthy yet, measuring another window");
+        }
+        window += 1;
+    };
+
+    let reports = match (&config.target_fallback, reports.as_slice()) {
+        (Some(fallback), [(primary, _)]) if primary_unusable(primary) && !INTERRUPTED.load(Ordering::Relaxed) => {
+            fail_over(&probes, &config, fallback, primary, &stop, &limiter, &sinks).await?.unwrap_or(reports)
+        }
+        _ => reports,
+    };
+    let mut reports = match config.compare_direct {
+        Some(_) => compare_direct(reports),
+        None => reports,
+    };
+    for (result, _) in reports.iter_mut().filter(|(result, _)| result.failover.is_none()) {
+        let phase = warmup.iter().flatten().find(|(phase, _)| phase.servername == result.servername);
+        result.warmup = phase.map(|(phase, _)| Phase::of(phase));
+        result.gate = gates.iter().find(|(target, _)| *target == result.servername).map(|(_, gate)| gate.clone());
+    }
+    drop(dashboard);
+
+    let down = reports
+        .iter()
+        .all(|(result, stats)| !result.setup_failed() && stats.consecutive_failures >= config.down_after);
+    let checks = match config.self_test {
+        true => self_test_checks(probe, &reports),
+        false => Vec::new(),
+    };
+    let status = report(&config, reports, &baseline, &sinks).await?;
+    if config.self_test {
+        return Ok(print_self_test(&checks));
+    }
+    if config.wait_down {
+        if !down {
+            error!("Still answering at --wait-timeout");
+            return Ok(Status::Critical);
+        }
+        return Ok(Status::Ok);
+    }
+    if config.await_healthy && !healthy {
+        error!(windows = window, "No healthy window within --await-timeout");
+        return Ok(Status::Critical);
+    }
+    Ok(status)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::VecDeque;
+    use std::sync::atomic::AtomicUsize;
+    use std::task::{Context, Waker};
+
+    /// Answers every request after `delay`, keeping count of the requests
+    /// outstanding and the most there ever were.
+    struct Delayed {
+        delay: Duration,
+        in_flight: AtomicUsize,
+        peak: AtomicUsize,
+    }
+
+    impl PingTransport for Delayed {
+        fn ping<'a>(&'a self, ip_addr: IpAddr, _ident: u16, _sequence: u16, payload: &'a [u8], _timeout: Duration) -> PingFuture<'a> {
+            let now = self.in_flight.fetch_add(1, Ordering::SeqCst) + 1;
+            self.peak.fetch_max(now, Ordering::SeqCst);
+            Box::pin(async move {
+                tokio::time::sleep(self.delay).await;
+                self.in_flight.fetch_sub(1, Ordering::SeqCst);
+                Ok(Echo {
+                    rtt: self.delay,
+                    handshake: None,
+                    reply: Reply {
+                        received_at: Instant::now(),
+                        source: ip_addr,
+                        payload: payload.to_vec(),
+                        ttl: None,
+                    },
+                })
+            })
+        }
+    }
+
+    #[tokio::test]
+    async fn window_bounds_packets_in_flight() {
+        const WINDOW: usize = 4;
+        let transport = Arc::new(Delayed {
+            delay: Duration::from_millis(20),
+            in_flight: AtomicUsize::new(0),
+            peak: AtomicUsize::new(0),
+        });
+        let window = WINDOW.to_string();
+        let config = Args::parse_from(["peckr", "127.0.0.1", "-c", "32", "--interval", "0", "--window", &window, "--quiet"]);
+        let stats = ping_target(
+            vec![(None, Probe::Transport(transport.clone()))],
+            Arc::new(config),
+            "127.0.0.1".to_string(),
+            vec![IpAddr::from([127, 0, 0, 1])],
+            1,
+            Arc::new(AtomicBool::new(false)),
+            None,
+            Sinks::default(),
+        )
+        .await;
+
+        assert_eq!((stats.sent, stats.received), (32, 32));
+        let peak = transport.peak.load(Ordering::SeqCst);
+        assert!(peak <= WINDOW, "{} packets in flight with --window {}", peak, WINDOW);
+        // Replies slower than the sends, so the window is what held them back.
+        assert!(peak > 1);
+        assert_eq!(transport.in_flight.load(Ordering::SeqCst), 0);
+    }
+
+    #[tokio::test]
+    async fn zero_interval_sends_without_delay() {
+        let config = Args::parse_from(["peckr", "192.0.2.1", "--interval", "0"]);
+        let delay = next_interval(&config, 0);
+        assert!(delay.is_zero());
+
+        let mut slot = SystemTime::now();
+        let stop = AtomicBool::new(false);
+        let mut wait = std::pin::pin!(wait_for_next_send(&config, &mut slot, delay, &stop));
+        let poll = wait.as_mut().poll(&mut Context::from_waker(Waker::noop()));
+        assert!(poll.is_ready(), "the send loop waited at --interval 0");
+    }
+
+    #[tokio::test]
+    async fn stop_cuts_the_wait_for_the_next_send_short() {
+        let config = Args::parse_from(["peckr", "192.0.2.1", "--interval", "60000"]);
+        let stop = Arc::new(AtomicBool::new(false));
+        let setter = stop.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            setter.store(true, Ordering::Relaxed);
+        });
+        let started = Instant::now();
+        let mut slot = SystemTime::now();
+        wait_for_next_send(&config, &mut slot, Duration::from_millis(config.interval), &stop).await;
+        assert!(started.elapsed() < Duration::from_secs(1), "waited {:?} after stop", started.elapsed());
+    }
+
+    /// Answers each request with the next scripted outcome, a timeout once
+    /// the script runs out, and records the sequence numbers it was sent.
+    struct Scripted {
+        answers: std::sync::Mutex<VecDeque<Result<Duration, PingError>>>,
+        sequences: std::sync::Mutex<Vec<u16>>,
+    }
+
+    impl Scripted {
+        fn new(answers: Vec<Result<Duration, PingError>>) -> Arc<Self> {
+            Arc::new(Self {
+                answers: std::sync::Mutex::new(answers.into()),
+                sequences: std::sync::Mutex::new(Vec::new()),
+            })
+        }
+    }
+
+    impl PingTransport for Scripted {
+        fn ping<'a>(&'a self, ip_addr: IpAddr, _ident: u16, sequence: u16, payload: &'a [u8], _timeout: Duration) -> PingFuture<'a> {
+            self.sequences.lock().unwrap().push(sequence);
+            let answer = self.answers.lock().unwrap().pop_front().unwrap_or(Err(PingError::Timeout));
+            Box::pin(async move {
+                answer.map(|rtt| Echo {
+                    rtt,
+                    handshake: None,
+                    reply: Reply {
+                        received_at: Instant::now(),
+                        source: ip_addr,
+                        payload: payload.to_vec(),
+                        ttl: None,
+                    },
+                })
+            })
+        }
+    }
+
+    /// Run the ping loop with `args` against `transport`, one packet at a
+    /// time so the script is consumed in sequence order.
+    async fn run_scripted(args: &[&str], transport: Arc<Scripted>) -> (Args, PingStats) {
+        let base = ["peckr", "127.0.0.1", "--interval", "0", "--window", "1", "--quiet"];
+        let config = Args::parse_from(base.iter().chain(args));
+        let stats = ping_target(
+            vec![(None, Probe::Transport(transport))],
+            Arc::new(config.clone()),
+            "127.0.0.1".to_string(),
+            vec![IpAddr::from([127, 0, 0, 1])],
+            1,
+            Arc::new(AtomicBool::new(false)),
+            None,
+            Sinks::default(),
+        )
+        .await;
+        (config, stats)
+    }
+
+    fn replies(count: usize, rtt_ms: u64) -> Vec<Result<Duration, PingError>> {
+        (0..count).map(|_| Ok(Duration::from_millis(rtt_ms))).collect()
+    }
+
+    #[tokio::test]
+    async fn loss_counts_timeouts_and_errors() {
+        let unreachable = PingError::Icmp { source: IpAddr::from([192, 0, 2, 1]), icmp_type: 3, code: 1 };
+        let script = vec![Ok(Duration::from_millis(5)), Err(PingError::Timeout), Ok(Duration::from_millis(7)), Err(unreachable)];
+        let (_, stats) = run_scripted(&["-c", "4"], Scripted::new(script)).await;
+
+        assert_eq!((stats.sent, stats.received), (4, 2));
+        assert_eq!(stats.packet_loss(), 50.0);
+        assert_eq!((stats.timeouts, stats.icmp_errors), (1, 1));
+        assert_eq!(stats.avg_rtt(), Duration::from_millis(6));
+    }
+
+    #[tokio::test]
+    async fn longest_loss_streak_tells_bursts_from_scattered_loss() {
+        let lost = || Err(PingError::Timeout);
+        let reply = || Ok(Duration::from_millis(5));
+        let script = vec![lost(), reply(), lost(), lost(), lost(), reply(), lost(), lost(), reply()];
+        let (_, stats) = run_scripted(&["-c", "9"], Scripted::new(script)).await;
+
+        assert_eq!(stats.max_loss_streak.map(|(longest, _)| longest), Some(3));
+        assert_eq!(stats.consecutive_failures, 0);
+
+        let (_, stats) = run_scripted(&["-c", "4"], Scripted::new(replies(4, 5))).await;
+        assert!(stats.max_loss_streak.is_none());
+    }
+
+    #[test]
+    fn absorb_thins_rounds_to_the_coarser_stride() {
+        let mut first = PingStats { max_samples: 8, ..PingStats::new() };
+        for seq in 0..32 {
+            first.update_with_success(Duration::from_millis(10), seq);
+        }
+        let mut second = PingStats { max_samples: 64, ..PingStats::new() };
+        for seq in 0..16 {
+            second.update_with_success(Duration::from_millis(50), seq);
+        }
+        assert_eq!((first.sample_stride, first.samples.len()), (8, 4));
+        assert_eq!((second.sample_stride, second.samples.len()), (1, 16));
+
+        first.absorb(&second);
+        // Every 8th reply of both rounds: 4 of the first's 32, 2 of the second's 16.
+        assert_eq!(first.sample_stride, 8);
+        let slow = first.samples.iter().filter(|sample| sample.rtt == Duration::from_millis(50)).count();
+        assert_eq!((first.samples.len(), slow), (6, 2));
+    }
+
+    #[test]
+    fn replies_overtaken_by_later_ones_count_as_reordered() {
+        let mut stats = PingStats::new();
+        for seq in [0, 1, 3, 2, 4, 7, 5, 6] {
+            stats.update_with_success(Duration::from_millis(5), seq);
+        }
+        assert_eq!(stats.reordered, 3);
+        assert_eq!(stats.reorder_ratio(), 3.0 / 8.0);
+
+        let config = Args::parse_from(["peckr", "127.0.0.1", "--max-reorder", "25"]);
+        let result = create_result(&config, "127.0.0.1", &stats);
+        assert!(result.objectives.iter().any(|objective| objective.name == "reorder" && !objective.passed));
+    }
+
+    #[tokio::test]
+    async fn thresholds_decide_status() {
+        let (config, stats) = run_scripted(&["-c", "10"], Scripted::new(replies(10, 20))).await;
+        assert!(create_result(&config, "127.0.0.1", &stats).status == Status::Ok);
+
+        let (config, stats) = run_scripted(&["-c", "10", "--max-latency", "10"], Scripted::new(replies(10, 20))).await;
+        let result = create_result(&config, "127.0.0.1", &stats);
+        assert!(result.status == Status::Critical);
+        assert!(result.objectives.iter().any(|objective| objective.name == "latency" && !objective.passed));
+
+        let one_lost = || Scripted::new(replies(9, 20).into_iter().chain([Err(PingError::Timeout)]).collect());
+        let (config, stats) = run_scripted(&["-c", "10", "--max-loss", "5"], one_lost()).await;
+        assert!(create_result(&config, "127.0.0.1", &stats).status == Status::Critical);
+        let (config, stats) = run_scripted(&["-c", "10", "--max-loss", "20", "--warn-loss", "5"], one_lost()).await;
+        assert!(create_result(&config, "127.0.0.1", &stats).status == Status::Degraded);
+    }
+
+    #[tokio::test]
+    async fn consecutive_losses_mark_the_target_down() {
+        let script = replies(3, 5).into_iter().chain((0..3).map(|_| Err(PingError::Timeout))).collect();
+        let (_, stats) = run_scripted(&["-c", "6", "--down-after", "3"], Scripted::new(script)).await;
+
+        assert_eq!(stats.consecutive_failures, 3);
+        let timeline: Vec<Status> = stats.timeline.iter().map(|transition| transition.status).collect();
+        assert!(timeline == [Status::Ok, Status::Critical]);
+    }
+
+    #[tokio::test]
+    async fn sequence_wraps_past_u16() {
+        let transport = Scripted::new(replies(4, 5));
+        let (_, stats) = run_scripted(&["-c", "4", "--seq-start", "65534"], transport.clone()).await;
+
+        assert_eq!(*transport.sequences.lock().unwrap(), [65534, 65535, 0, 1]);
+        assert_eq!(stats.received, 4);
+    }
 }
@@ -0,0 +1,114 @@
+//! MessagePack output (`--format msgpack`).
+//!
+//! Each result is one MessagePack map with the same fields as the JSON
+//! output, `--json-case` and `--omit-null` included, written as a record: a 4-byte big-endian
+//! length followed by that many bytes. The framing lets a reader split a
+//! stream or file of records without decoding them.
+//!
+//! The encoder works from the serialized `serde_json::Value`, so it only
+//! needs the types JSON has and always picks the smallest encoding.
+
+use serde::Serialize;
+use serde_json::Value;
+
+use crate::casing::{self, JsonCase};
+
+/// A length header: the fix form when `len` fits in its low bits, else the
+/// smallest of the 8-bit (strings only), 16-bit and 32-bit forms.
+fn write_len(out: &mut Vec<u8>, len: usize, (fix, fix_max): (u8, usize), (len8, len16, len32): (Option<u8>, u8, u8)) {
+    match (len, len8) {
+        (len, _) if len <= fix_max => out.push(fix | len as u8),
+        (len, Some(len8)) if len <= u8::MAX as usize => out.extend([len8, len as u8]),
+        (len, _) if len <= u16::MAX as usize => {
+            out.push(len16);
+            out.extend((len as u16).to_be_bytes());
+        }
+        (len, _) => {
+            out.push(len32);
+            out.extend((len as u32).to_be_bytes());
+        }
+    }
+}
+
+fn write_int(out: &mut Vec<u8>, n: i64) {
+    match n {
+        0..=0x7f => out.push(n as u8),
+        -32..=-1 => out.push(n as i8 as u8),
+        0x80..=0xff => out.extend([0xcc, n as u8]),
+        0x100..=0xffff => {
+            out.push(0xcd);
+            out.extend((n as u16).to_be_bytes());
+        }
+        0x1_0000..=0xffff_ffff => {
+            out.push(0xce);
+            out.extend((n as u32).to_be_bytes());
+        }
+        -0x80..=-33 => out.extend([0xd0, n as i8 as u8]),
+        -0x8000..=-0x81 => {
+            out.push(0xd1);
+            out.extend((n as i16).to_be_bytes());
+        }
+        -0x8000_0000..=-0x8001 => {
+            out.push(0xd2);
+            out.extend((n as i32).to_be_bytes());
+        }
+        n if n > 0 => {
+            out.push(0xcf);
+            out.extend((n as u64).to_be_bytes());
+        }
+        n => {
+            out.push(0xd3);
+            out.extend(n.to_be_bytes());
+        }
+    }
+}
+
+fn write_str(out: &mut Vec<u8>, s: &str) {
+    write_len(out, s.len(), (0xa0, 31), (Some(0xd9), 0xda, 0xdb));
+    out.extend(s.as_bytes());
+}
+
+fn encode(out: &mut Vec<u8>, value: &Value) {
+    match value {
+        Value::Null => out.push(0xc0),
+        Value::Bool(false) => out.push(0xc2),
+        Value::Bool(true) => out.push(0xc3),
+        Value::Number(n) => match (n.as_i64(), n.as_u64()) {
+            (Some(n), _) => write_int(out, n),
+            (None, Some(n)) => {
+                out.push(0xcf);
+                out.extend(n.to_be_bytes());
+            }
+            (None, None) => {
+                out.push(0xcb);
+                out.extend(n.as_f64().unwrap_or(f64::NAN).to_be_bytes());
+            }
+        },
+        Value::String(s) => write_str(out, s),
+        Value::Array(items) => {
+            write_len(out, items.len(), (0x90, 15), (None, 0xdc, 0xdd));
+            for item in items {
+                encode(out, item);
+            }
+        }
+        Value::Object(map) => {
+            write_len(out, map.len(), (0x80, 15), (None, 0xde, 0xdf));
+            for (key, value) in map {
+                write_str(out, key);
+                encode(out, value);
+            }
+        }
+    }
+}
+
+/// One length-prefixed record per value.
+pub fn records<T: Serialize>(values: &[T], case: JsonCase, omit_null: bool) -> Result<Vec<u8>, serde_json::Error> {
+    let mut out = Vec::new();
+    for value in values {
+        let mut record = Vec::new();
+        encode(&mut record, &casing::to_value(value, case, omit_null)?);
+        out.extend((record.len() as u32).to_be_bytes());
+        out.extend(record);
+    }
+    Ok(out)
+}
@@ -0,0 +1,190 @@
+//! MTR-style continuous traceroute (`--mtr`).
+//!
+//! Every round sends one probe per TTL, all at once, and folds each answer
+//! into that hop's own [`PingStats`]. The first round probes up to
+//! `--max-hops`; once the target (or an unreachable error) answers at some
+//! TTL, later rounds stop there.
+
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+
+use serde::Serialize;
+use tokio::task::JoinSet;
+use tracing::debug;
+
+use crate::icmp::{self, EchoClient};
+use crate::{Args, PingStats, Status};
+
+/// Accumulated answers for one TTL.
+pub struct Hop {
+    pub ttl: u8,
+    /// Router that answered most recently, if any did.
+    pub host: Option<IpAddr>,
+    pub stats: PingStats,
+}
+
+/// Final per-hop statistics; times are in milliseconds.
+#[derive(Serialize)]
+pub struct HopReport {
+    ttl: u8,
+    host: Option<IpAddr>,
+    loss: f64,
+    sent: u32,
+    received: u32,
+    avg: f64,
+    best: f64,
+    worst: f64,
+    stddev: f64,
+}
+
+fn ms(rtt: Duration) -> f64 {
+    rtt.as_secs_f64() * 1000.0
+}
+
+impl Hop {
+    fn best(&self) -> Duration {
+        self.stats.samples.iter().map(|s| s.rtt).min().unwrap_or_default()
+    }
+
+    fn worst(&self) -> Duration {
+        self.stats.samples.iter().map(|s| s.rtt).max().unwrap_or_default()
+    }
+
+    /// Population standard deviation of the RTTs, in milliseconds.
+    fn stddev(&self) -> f64 {
+        let samples = &self.stats.samples;
+        if samples.is_empty() {
+            return 0.0;
+        }
+        let mean = ms(self.stats.avg_rtt());
+        let variance = samples.iter().map(|s| (ms(s.rtt) - mean).powi(2)).sum::<f64>() / samples.len() as f64;
+        variance.sqrt()
+    }
+
+    pub fn report(&self) -> HopReport {
+        HopReport {
+            ttl: self.ttl,
+            host: self.host,
+            loss: self.stats.packet_loss(),
+            sent: self.stats.sent,
+            received: self.stats.received,
+            avg: ms(self.stats.avg_rtt()),
+            best: ms(self.best()),
+            worst: ms(self.worst()),
+            stddev: self.stddev(),
+        }
+    }
+}
+
+/// The run's status, judged on the last hop as a normal run judges the
+/// target: critical unless the target itself answered there and the hop's
+/// loss stayed within `--max-loss`, degraded above `--warn-loss`.
+pub fn status(config: &Args, hops: &[Hop], ip_addr: IpAddr) -> Status {
+    let Some(last) = hops.last().filter(|hop| hop.host == Some(ip_addr)) else {
+        return Status::Critical;
+    };
+    let loss = last.stats.packet_loss();
+    if loss >= 100.0 || loss > config.max_loss {
+        Status::Critical
+    } else if config.warn_loss.is_some_and(|warn_loss| loss > warn_loss) {
+        Status::Degraded
+    } else {
+        Status::Ok
+    }
+}
+
+pub fn print_table(target: &str, hops: &[Hop]) {
+    println!("MTR to {}", target);
+    println!(
+        "{:>3}  {:<39}  {:>6}  {:>5}  {:>5}  {:>8}  {:>8}  {:>8}  {:>8}",
+        "HOP", "HOST", "LOSS%", "SENT", "RECV", "AVG", "BEST", "WORST", "STDEV",
+    );
+    for hop in hops {
+        let host = hop.host.map(|ip| ip.to_string()).unwrap_or_else(|| "???".to_string());
+        println!(
+            "{:>3}  {:<39}  {:>6.1}  {:>5}  {:>5}  {:>8.2}  {:>8.2}  {:>8.2}  {:>8.2}",
+            hop.ttl,
+            host,
+            hop.stats.packet_loss(),
+            hop.stats.sent,
+            hop.stats.received,
+            ms(hop.stats.avg_rtt()),
+            ms(hop.best()),
+            ms(hop.worst()),
+            hop.stddev(),
+        );
+    }
+}
+
+/// Probe the path to `ip_addr` for `--count` rounds (endless with 0), one
+/// `--interval` apart, redrawing the table after each round when the output
+/// is human-readable.
+pub async fn run(client: &EchoClient, config: &Args, target: &str, ip_addr: IpAddr, stop: &AtomicBool) -> Vec<Hop> {
+    let mut hops: Vec<Hop> = (1..=config.max_hops)
+        .map(|ttl| Hop {
+            ttl,
+            host: None,
+            stats: PingStats::new(),
+        })
+        .collect();
+    let timeout = Duration::from_millis(config.timeout);
+    let live = config.format == crate::OutputFormat::Human && !config.quiet;
+    let mut sequence: u32 = 0;
+    let mut round = 0;
+    let ident = config.identifier(0);
+
+    loop {
+        if config.count > 0 && round >= config.count {
+            break;
+        }
+
+        let mut probes = JoinSet::new();
+        for hop in &hops {
+            let (client, ttl, seq) = (client.clone(), hop.ttl, sequence);
+            let payload = icmp::build_payload(config.size, seq, config.timestamp);
+            probes.spawn(async move {
+                let answer = client.probe(ip_addr, ident, seq as u16, ttl, &payload, timeout).await;
+                (ttl, seq, answer)
+            });
+            sequence += 1;
+        }
+
+        let mut path_len = hops.len();
+        while let Some(joined) = probes.join_next().await {
+            let Ok((ttl, seq, answer)) = joined else {
+                continue;
+            };
+            let hop = &mut hops[ttl as usize - 1];
+            match answer {
+                Ok(answer) => {
+                    debug!(target = %target, ttl, seq, from = %answer.source, rtt_us = answer.rtt.as_micros() as u64, "Hop answered");
+                    hop.host = Some(answer.source);
+                    hop.stats.update_with_success(answer.rtt, seq);
+                    if answer.reached {
+                        path_len = path_len.min(ttl as usize);
+                    }
+                }
+                Err(e) => {
+                    debug!(target = %target, ttl, seq, error = %e, "Hop did not answer");
+                    hop.stats.update_with_failure();
+                }
+            }
+        }
+        hops.truncate(path_len);
+        round += 1;
+
+        if live {
+            // Clear the screen and move the cursor home before redrawing.
+            print!("\x1b[2J\x1b[H");
+            print_table(target, &hops);
+        }
+
+        if stop.load(Ordering::Relaxed) {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(config.interval)).await;
+    }
+
+    hops
+}
@@ -0,0 +1,113 @@
+//! Capture of the probe traffic to a pcap file (`--pcap`).
+//!
+//! Packets are recorded at peckr's own socket rather than sniffed off the
+//! interface, so the file holds exactly the requests we sent and the replies
+//! we matched to them. Records use the raw-IP link type; when the socket
+//! hands us bare ICMP (sent packets, unprivileged DGRAM sockets) an IPv4
+//! header is synthesized around it.
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, Write};
+use std::net::{IpAddr, Ipv4Addr};
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tracing::warn;
+
+const PCAP_MAGIC: u32 = 0xa1b2_c3d4;
+const SNAPLEN: u32 = 65535;
+/// LINKTYPE_RAW: each record starts directly with the IP header.
+const LINKTYPE_RAW: u32 = 101;
+const IPPROTO_ICMP: u8 = 1;
+
+pub struct Capture {
+    file: Mutex<File>,
+    mark: Option<u32>,
+    vrf: Option<String>,
+    /// Local address per destination, for the headers we synthesize.
+    sources: Mutex<HashMap<IpAddr, Ipv4Addr>>,
+}
+
+impl Capture {
+    pub fn create(path: &str, mark: Option<u32>, vrf: Option<String>) -> io::Result<Self> {
+        let mut file = File::create(path)?;
+        let mut header = Vec::with_capacity(24);
+        header.extend_from_slice(&PCAP_MAGIC.to_le_bytes());
+        header.extend_from_slice(&2u16.to_le_bytes());
+        header.extend_from_slice(&4u16.to_le_bytes());
+        header.extend_from_slice(&0i32.to_le_bytes());
+        header.extend_from_slice(&0u32.to_le_bytes());
+        header.extend_from_slice(&SNAPLEN.to_le_bytes());
+        header.extend_from_slice(&LINKTYPE_RAW.to_le_bytes());
+        file.write_all(&header)?;
+        Ok(Self {
+            file: Mutex::new(file),
+            mark,
+            vrf,
+            sources: Mutex::default(),
+        })
+    }
+
+    fn local_address(&self, peer: Ipv4Addr) -> Ipv4Addr {
+        *self.sources.lock().unwrap().entry(IpAddr::V4(peer)).or_insert_with(|| {
+            match crate::route_source(IpAddr::V4(peer), self.mark, self.vrf.as_deref()) {
+                Some(IpAddr::V4(local)) => local,
+                _ => Ipv4Addr::UNSPECIFIED,
+            }
+        })
+    }
+
+    /// Record an ICMP message we are sending to `dest`.
+    pub fn sent(&self, dest: IpAddr, ttl: u8, message: &[u8]) {
+        if let IpAddr::V4(dest) = dest {
+            self.icmp(self.local_address(dest), dest, ttl, message);
+        }
+    }
+
+    /// Record a bare ICMP message received from `source`; the socket does
+    /// not tell us its TTL, so the synthesized header carries 0.
+    pub fn received(&self, source: IpAddr, message: &[u8]) {
+        if let IpAddr::V4(source) = source {
+            self.icmp(source, self.local_address(source), 0, message);
+        }
+    }
+
+    fn icmp(&self, source: Ipv4Addr, dest: Ipv4Addr, ttl: u8, message: &[u8]) {
+        let mut packet = ipv4_header(source, dest, ttl, message.len());
+        packet.extend_from_slice(message);
+        self.write(&packet);
+    }
+
+    /// Record a datagram that already starts with its IP header.
+    pub fn ip(&self, packet: &[u8]) {
+        self.write(packet);
+    }
+
+    fn write(&self, packet: &[u8]) {
+        let at = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+        let captured = packet.len().min(SNAPLEN as usize);
+        let mut record = Vec::with_capacity(16 + captured);
+        record.extend_from_slice(&(at.as_secs() as u32).to_le_bytes());
+        record.extend_from_slice(&at.subsec_micros().to_le_bytes());
+        record.extend_from_slice(&(captured as u32).to_le_bytes());
+        record.extend_from_slice(&(packet.len() as u32).to_le_bytes());
+        record.extend_from_slice(&packet[..captured]);
+        // One write per record keeps the file readable if peckr is killed.
+        if let Err(e) = self.file.lock().unwrap().write_all(&record) {
+            warn!("Failed to write pcap record: {}", e);
+        }
+    }
+}
+
+fn ipv4_header(source: Ipv4Addr, dest: Ipv4Addr, ttl: u8, payload_len: usize) -> Vec<u8> {
+    let mut header = vec![0; 20];
+    header[0] = 0x45;
+    header[2..4].copy_from_slice(&((20 + payload_len) as u16).to_be_bytes());
+    header[8] = ttl;
+    header[9] = IPPROTO_ICMP;
+    header[12..16].copy_from_slice(&source.octets());
+    header[16..20].copy_from_slice(&dest.octets());
+    let sum = crate::icmp::checksum(&header);
+    header[10..12].copy_from_slice(&sum.to_be_bytes());
+    header
+}
@@ -0,0 +1,101 @@
+//! Prometheus text exposition of the final per-target results.
+//!
+//! With exemplars enabled the output switches to the OpenMetrics text format,
+//! the only one that can carry them: each RTT histogram bucket gets the
+//! slowest retained sample that fell into it, with its sequence number and
+//! wall-clock time, so a dashboard can jump from an aggregate to the packet.
+
+use std::fmt::Write;
+use std::time::UNIX_EPOCH;
+
+use crate::{PingResult, PingStats, Sample};
+
+/// Upper bounds of the RTT histogram buckets, in seconds.
+pub const RTT_BUCKETS: [f64; 10] = [0.001, 0.002, 0.005, 0.01, 0.02, 0.05, 0.1, 0.2, 0.5, 1.0];
+
+fn escape(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+fn exemplar(sample: Option<&Sample>) -> String {
+    match sample {
+        Some(sample) => {
+            let at = sample.at.duration_since(UNIX_EPOCH).unwrap_or_default();
+            format!(
+                " # {{seq=\"{}\"}} {} {:.3}",
+                sample.seq,
+                sample.rtt.as_secs_f64(),
+                at.as_secs_f64()
+            )
+        }
+        None => String::new(),
+    }
+}
+
+fn write_counter(
+    out: &mut String,
+    reports: &[(PingResult, PingStats)],
+    openmetrics: bool,
+    name: &str,
+    help: &str,
+    value: impl Fn(&PingStats) -> u32,
+) {
+    // OpenMetrics declares counters without the `_total` suffix.
+    let family = if openmetrics { name.to_string() } else { format!("{}_total", name) };
+    let _ = writeln!(out, "# HELP {} {}", family, help);
+    let _ = writeln!(out, "# TYPE {} counter", family);
+    for (result, stats) in reports {
+        let _ = writeln!(out, "{}_total{{target=\"{}\"}} {}", name, escape(&result.servername), value(stats));
+    }
+}
+
+pub fn render(reports: &[(PingResult, PingStats)], exemplars: bool) -> String {
+    let mut out = String::new();
+
+    let _ = writeln!(out, "# HELP peckr_up Whether the target met the success thresholds.");
+    let _ = writeln!(out, "# TYPE peckr_up gauge");
+    for (result, _) in reports {
+        let _ = writeln!(out, "peckr_up{{target=\"{}\"}} {}", escape(&result.servername), result.success as u8);
+    }
+
+    write_counter(&mut out, reports, exemplars, "peckr_packets_sent", "Echo requests sent.", |s| s.sent);
+    write_counter(&mut out, reports, exemplars, "peckr_packets_received", "Echo replies received.", |s| s.received);
+
+    let _ = writeln!(out, "# HELP peckr_packet_loss_percent Packet loss over the run.");
+    let _ = writeln!(out, "# TYPE peckr_packet_loss_percent gauge");
+    for (result, stats) in reports {
+        let _ = writeln!(out, "peckr_packet_loss_percent{{target=\"{}\"}} {}", escape(&result.servername), stats.packet_loss());
+    }
+
+    let _ = writeln!(out, "# HELP peckr_rtt_seconds Round-trip time of received replies.");
+    let _ = writeln!(out, "# TYPE peckr_rtt_seconds histogram");
+    for (result, stats) in reports {
+        let target = escape(&result.servername);
+        let mut lower = f64::NEG_INFINITY;
+        let mut count = 0;
+        let bounds = RTT_BUCKETS.iter().copied().chain(std::iter::once(f64::INFINITY));
+        for (le, in_le) in bounds.zip(stats.rtt_buckets) {
+            let in_bucket = |s: &&Sample| s.rtt.as_secs_f64() > lower && s.rtt.as_secs_f64() <= le;
+            count += in_le;
+            let le_label = if le.is_infinite() { "+Inf".to_string() } else { le.to_string() };
+            let exemplar = if exemplars {
+                exemplar(stats.samples.iter().filter(in_bucket).max_by_key(|s| s.rtt))
+            } else {
+                String::new()
+            };
+            let _ = writeln!(
+                out,
+                "peckr_rtt_seconds_bucket{{target=\"{}\",le=\"{}\"}} {}{}",
+                target, le_label, count, exemplar
+            );
+            lower = le;
+        }
+        let _ = writeln!(out, "peckr_rtt_seconds_sum{{target=\"{}\"}} {}", target, stats.total_rtt.as_secs_f64());
+        let _ = writeln!(out, "peckr_rtt_seconds_count{{target=\"{}\"}} {}", target, stats.measured);
+    }
+
+    if exemplars {
+        let _ = writeln!(out, "# EOF");
+    }
+    out
+}
@@ -0,0 +1,124 @@
+//! Re-summarize a recorded run offline (`--replay`).
+//!
+//! The input is the NDJSON that `--log-format json` writes to stderr with
+//! `RUST_LOG=debug`. Its per-packet events are folded into `PingStats` in
+//! the order they were logged, exactly as the live run folded the packets,
+//! so the current threshold flags can be applied to an old capture.
+
+use std::collections::HashMap;
+use std::fs;
+use std::time::{Duration, UNIX_EPOCH};
+
+use serde_json::Value;
+
+use crate::{packet_health, Args, FailureKind, PingStats, Status};
+
+/// Version of the per-packet event fields, recorded as `schema` on every
+/// packet event. Bump it when a field replay relies on changes meaning.
+pub const SCHEMA: u64 = 1;
+
+/// Read the packet events in `path` and return the stats of every target,
+/// in the order the targets first appear. The timeline is judged against
+/// the thresholds in `config`.
+pub fn load(config: &Args, path: &str) -> Result<Vec<(String, PingStats)>, String> {
+    let contents = fs::read_to_string(path).map_err(|e| format!("Failed to read replay file {}: {}", path, e))?;
+    let mut targets: Vec<(String, PingStats)> = Vec::new();
+    let mut index: HashMap<String, usize> = HashMap::new();
+
+    for (number, line) in contents.lines().enumerate() {
+        let invalid = |reason: &str| format!("Invalid replay file {} line {}: {}", path, number + 1, reason);
+        if line.trim().is_empty() {
+            continue;
+        }
+        let event: Value = serde_json::from_str(line).map_err(|e| invalid(&e.to_string()))?;
+        let fields = &event["fields"];
+        let kind = match fields["message"].as_str() {
+            Some(message @ ("Echo reply" | "Further reply" | "Late reply" | "Ping failed" | "Reply from outside --expect-source"
+            | "Reply payload differs from the request" | "System clock stepped during the packet, RTT not counted")) => message,
+            _ => continue,
+        };
+        match fields["schema"].as_u64() {
+            Some(SCHEMA) => {}
+            Some(schema) => return Err(invalid(&format!("unsupported packet event schema {}", schema))),
+            None => return Err(invalid("packet event without a schema version")),
+        }
+        let target = fields["target"].as_str().ok_or_else(|| invalid("packet event without a target"))?;
+        let slot = *index.entry(target.to_string()).or_insert_with(|| {
+            targets.push((target.to_string(), PingStats::new()));
+            targets.len() - 1
+        });
+        let stats = &mut targets[slot].1;
+        let at = fields["received_at"].as_u64().or(fields["sent_at"].as_u64()).unwrap_or_default();
+        let at = UNIX_EPOCH + Duration::from_micros(at);
+        let sent_at = fields["sent_at"].as_u64().map_or(at, |sent_at| UNIX_EPOCH + Duration::from_micros(sent_at));
+        // Date a loss streak by the log, not by when it is replayed.
+        if stats.consecutive_failures == 0 {
+            stats.loss_streak_started = Some(at);
+        }
+
+        let health = match kind {
+            // Another answer to a --broadcast request already counted.
+            "Further reply" => {
+                let rtt = fields["rtt_us"].as_u64().ok_or_else(|| invalid("reply without rtt_us"))?;
+                if let Some(source) = fields["source"].as_str().and_then(|source| source.parse().ok()) {
+                    stats.record_responder(source, Duration::from_micros(rtt));
+                }
+                continue;
+            }
+            // Logged alongside the reply's own "Echo reply" event.
+            "Reply payload differs from the request" => {
+                stats.corrupted_replies += 1;
+                continue;
+            }
+            "Echo reply" => {
+                let rtt = fields["rtt_us"].as_u64().ok_or_else(|| invalid("reply without rtt_us"))?;
+                let seq = fields["seq"].as_u64().ok_or_else(|| invalid("reply without seq"))?;
+                stats.update_with_success(Duration::from_micros(rtt), seq as u32);
+                stats.track_local_network(false, at);
+                if let (Some(received_at), Some(sample)) = (fields["received_at"].as_u64(), stats.samples.last_mut()) {
+                    sample.at = UNIX_EPOCH + Duration::from_micros(received_at);
+                }
+                if let Some(ttl) = fields["ttl"].as_u64() {
+                    stats.record_reply_ttl(ttl as u8);
+                }
+                if let Some(source) = fields["source"].as_str().and_then(|source| source.parse().ok()) {
+                    stats.record_responder(source, Duration::from_micros(rtt));
+                }
+                packet_health(config, Some(Duration::from_micros(rtt)))
+            }
+            "Late reply" => {
+                let rtt = fields["rtt_us"].as_u64().ok_or_else(|| invalid("reply without rtt_us"))?;
+                stats.update_with_late();
+                packet_health(config, Some(Duration::from_micros(rtt)))
+            }
+            "Reply from outside --expect-source" => {
+                stats.update_with_failure();
+                stats.source_mismatches += 1;
+                Status::Critical
+            }
+            "Ping failed" => {
+                match fields["failure"].as_str().and_then(FailureKind::from_name) {
+                    Some(kind) => {
+                        stats.update_with_error(kind);
+                        stats.track_local_network(kind == FailureKind::NetworkDown, at);
+                        if let Some(mtu) = fields["mtu"].as_u64() {
+                            stats.record_path_mtu(mtu as u16);
+                        }
+                    }
+                    None => stats.update_with_failure(),
+                }
+                packet_health(config, None)
+            }
+            _ => {
+                stats.update_with_unmeasured();
+                packet_health(config, Some(Duration::ZERO))
+            }
+        };
+        stats.record_health(health, sent_at, config.down_after);
+    }
+
+    if targets.is_empty() {
+        return Err(format!("No packet events in replay file {}", path));
+    }
+    Ok(targets)
+}
@@ -0,0 +1,50 @@
+//! StatsD sink (`--statsd`) for environments without a Prometheus scraper.
+//!
+//! Metrics carry the target as a DogStatsD tag and use the same values as
+//! the Prometheus output: `peckr.up` and `peckr.loss` as gauges and the
+//! average RTT as the `peckr.rtt` timer, in milliseconds.
+
+use std::net::{ToSocketAddrs, UdpSocket};
+use tracing::warn;
+
+use crate::{PingResult, PingStats};
+
+pub struct StatsdSink {
+    socket: UdpSocket,
+}
+
+fn tag_value(value: &str) -> String {
+    // `,` separates tags and `|` separates fields in the datagram.
+    value.replace([',', '|', '#'], "_")
+}
+
+impl StatsdSink {
+    pub fn connect(addr: &str) -> Result<Self, String> {
+        let remote = addr
+            .to_socket_addrs()
+            .map_err(|e| format!("Invalid --statsd address {}: {}", addr, e))?
+            .next()
+            .ok_or_else(|| format!("Invalid --statsd address {}", addr))?;
+        let local = if remote.is_ipv4() { "0.0.0.0:0" } else { "[::]:0" };
+        let socket = UdpSocket::bind(local)
+            .and_then(|socket| socket.connect(remote).map(|_| socket))
+            .map_err(|e| format!("Failed to open StatsD socket to {}: {}", addr, e))?;
+        Ok(Self { socket })
+    }
+
+    /// Send the current metrics for one target. UDP delivery is best effort,
+    /// so a failure is only logged.
+    pub fn send(&self, result: &PingResult, stats: &PingStats) {
+        let tags = format!("#target:{}", tag_value(&result.servername));
+        let mut lines = vec![
+            format!("peckr.up:{}|g|{}", result.success as u8, tags),
+            format!("peckr.loss:{}|g|{}", stats.packet_loss(), tags),
+        ];
+        if stats.received > 0 {
+            lines.push(format!("peckr.rtt:{:.3}|ms|{}", stats.avg_rtt().as_secs_f64() * 1000.0, tags));
+        }
+        if let Err(e) = self.socket.send(lines.join("\n").as_bytes()) {
+            warn!(target = %result.servername, error = %e, "Failed to send StatsD metrics");
+        }
+    }
+}
@@ -0,0 +1,86 @@
+//! Results to the local syslog daemon (`--syslog`).
+//!
+//! Messages go to the local socket in the traditional BSD format
+//! (`<PRI>peckr[PID]: message`), which rsyslog, syslog-ng and journald all
+//! accept, under the `user` facility.
+
+use std::io;
+
+use crate::Status;
+
+/// LOG_USER.
+const FACILITY: u8 = 1;
+
+#[cfg(unix)]
+const SOCKET_PATHS: [&str; 2] = ["/dev/log", "/var/run/syslog"];
+
+#[derive(Clone, Copy)]
+pub enum Severity {
+    Err = 3,
+    Warning = 4,
+    Info = 6,
+}
+
+impl From<Status> for Severity {
+    fn from(status: Status) -> Self {
+        match status {
+            Status::Ok => Severity::Info,
+            Status::Degraded => Severity::Warning,
+            Status::Critical => Severity::Err,
+        }
+    }
+}
+
+pub struct Syslog {
+    #[cfg(unix)]
+    socket: std::os::unix::net::UnixDatagram,
+}
+
+impl Syslog {
+    /// Connect to the local syslog socket: `/dev/log` on Linux and most
+    /// Unixes, `/var/run/syslog` on macOS.
+    #[cfg(unix)]
+    pub fn connect() -> Result<Self, String> {
+        let socket = std::os::unix::net::UnixDatagram::unbound()
+            .map_err(|e| format!("Failed to create syslog socket: {}", e))?;
+        let mut error = None;
+        for path in SOCKET_PATHS {
+            match socket.connect(path) {
+                Ok(()) => return Ok(Self { socket }),
+                Err(e) => error = Some(e),
+            }
+        }
+        Err(format!(
+            "Failed to connect to syslog at {}: {}",
+            SOCKET_PATHS.join(" or "),
+            error.map(|e| e.to_string()).unwrap_or_default()
+        ))
+    }
+
+    #[cfg(not(unix))]
+    pub fn connect() -> Result<Self, String> {
+        Err("--syslog is only supported on Unix".to_string())
+    }
+
+    pub fn send(&self, severity: Severity, message: &str) {
+        let line = format!(
+            "<{}>peckr[{}]: {}",
+            FACILITY * 8 + severity as u8,
+            std::process::id(),
+            message
+        );
+        if let Err(e) = self.write(line.as_bytes()) {
+            tracing::warn!("Failed to write to syslog: {}", e);
+        }
+    }
+
+    #[cfg(unix)]
+    fn write(&self, line: &[u8]) -> io::Result<()> {
+        self.socket.send(line).map(|_| ())
+    }
+
+    #[cfg(not(unix))]
+    fn write(&self, _line: &[u8]) -> io::Result<()> {
+        Err(io::ErrorKind::Unsupported.into())
+    }
+}
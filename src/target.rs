@@ -0,0 +1,55 @@
+//! Targets given as URLs or `host:port` rather than a bare host.
+//!
+//! People paste what their browser or config file shows, so a target may be
+//! `https://example.com:8443/health`, `example.com:443` or `[2001:db8::1]:53`.
+//! Only the host is pinged; the port matters to `--tcp` alone.
+
+/// The parts of a target peckr uses.
+pub struct Target<'a> {
+    pub host: &'a str,
+    /// Explicit port, or the scheme's default port for `http` and `https`.
+    pub port: Option<u16>,
+}
+
+/// Split a target into host and port:
+///
+/// - `scheme://[user@]host[:port][/path]`: scheme, credentials and path are
+///   dropped; `http` and `https` imply ports 80 and 443.
+/// - `host:port` and `[ipv6]:port`.
+/// - Anything else, including a bare IPv6 address, is the host itself.
+pub fn parse(target: &str) -> Target<'_> {
+    let (scheme, rest) = match target.split_once("://") {
+        Some((scheme, rest)) => (Some(scheme), rest),
+        None => (None, target),
+    };
+    let authority = match scheme {
+        Some(_) => rest.split(['/', '?', '#']).next().unwrap_or_default(),
+        None => rest,
+    };
+    let authority = authority.rsplit_once('@').map_or(authority, |(_, host)| host);
+
+    let (host, port) = if let Some(bracketed) = authority.strip_prefix('[') {
+        match bracketed.split_once(']') {
+            Some((host, port)) => (host, port.strip_prefix(':').and_then(|port| port.parse().ok())),
+            None => (authority, None),
+        }
+    } else {
+        match authority.split_once(':') {
+            Some((host, port)) if !port.contains(':') => match port.parse() {
+                Ok(port) => (host, Some(port)),
+                Err(_) => (authority, None),
+            },
+            _ => (authority, None),
+        }
+    };
+
+    let default_port = match scheme.map(str::to_ascii_lowercase).as_deref() {
+        Some("http") => Some(80),
+        Some("https") => Some(443),
+        _ => None,
+    };
+    Target {
+        host,
+        port: port.or(default_port),
+    }
+}
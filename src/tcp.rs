@@ -0,0 +1,155 @@
+//! TCP connect probes (`--tcp`): the RTT is the time the handshake takes.
+//!
+//! Useful where ICMP is filtered or where the question is whether a service
+//! port answers. Connections are closed with a reset, so a fixed
+//! `--source-port` is not left in TIME_WAIT for the next probe.
+//!
+//! With `--http` each probe also sends a plain HTTP/1.1 request once
+//! connected, and the RTT runs until the first byte of the response.
+
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpSocket;
+
+use crate::icmp::PingError;
+
+fn new_socket(dest: &SocketAddr) -> io::Result<TcpSocket> {
+    if dest.is_ipv4() {
+        TcpSocket::new_v4()
+    } else {
+        TcpSocket::new_v6()
+    }
+}
+
+fn unspecified(dest: &SocketAddr, port: u16) -> SocketAddr {
+    let ip = match dest {
+        SocketAddr::V4(_) => IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+        SocketAddr::V6(_) => IpAddr::V6(Ipv6Addr::UNSPECIFIED),
+    };
+    SocketAddr::new(ip, port)
+}
+
+/// Bind to `source` and `port`, either of which may be left to the kernel.
+/// A fixed port is marked reusable so consecutive probes can share it.
+fn bind_source(socket: &TcpSocket, dest: &SocketAddr, source: Option<IpAddr>, port: Option<u16>) -> io::Result<()> {
+    if port.is_some() {
+        socket.set_reuseaddr(true)?;
+    }
+    let local = match source {
+        Some(source) => SocketAddr::new(source, port.unwrap_or(0)),
+        None => unspecified(dest, port.unwrap_or(0)),
+    };
+    socket.bind(local).map_err(|e| match (e.kind(), port) {
+        (io::ErrorKind::AddrInUse, Some(port)) => io::Error::new(
+            io::ErrorKind::AddrInUse,
+            format!("source port {} is already in use", port),
+        ),
+        _ => e,
+    })
+}
+
+/// Check up front that `port` can be bound, so a port held by another
+/// process fails the run with a clear error instead of every probe. The
+/// error comes with the kind of the underlying failure.
+pub fn check_source_port(dest: IpAddr, port: u16) -> Result<(), (io::ErrorKind, String)> {
+    let dest = SocketAddr::new(dest, 0);
+    let socket = new_socket(&dest).map_err(|e| (e.kind(), format!("Failed to create TCP socket: {}", e)))?;
+    bind_source(&socket, &dest, None, Some(port)).map_err(|e| {
+        let message = match e.kind() {
+            io::ErrorKind::AddrInUse => format!("--source-port {} is already in use", port),
+            io::ErrorKind::PermissionDenied => format!("--source-port {} needs privileges to bind", port),
+            _ => format!("Failed to bind --source-port {}: {}", port, e),
+        };
+        (e.kind(), message)
+    })
+}
+
+/// A socket for one probe to `dest`, bound, marked and put in the VRF as
+/// asked, to be connected.
+fn probe_socket(
+    dest: &SocketAddr,
+    source: Option<IpAddr>,
+    source_port: Option<u16>,
+    mark: Option<u32>,
+    vrf: Option<&str>,
+) -> io::Result<TcpSocket> {
+    let socket = new_socket(dest)?;
+    #[cfg(target_os = "linux")]
+    if let Some(mark) = mark {
+        socket2::SockRef::from(&socket).set_mark(mark)?;
+    }
+    #[cfg(target_os = "linux")]
+    if let Some(vrf) = vrf {
+        socket2::SockRef::from(&socket).bind_device(Some(vrf.as_bytes()))?;
+    }
+    #[cfg(not(target_os = "linux"))]
+    let _ = (mark, vrf);
+    if source.is_some() || source_port.is_some() {
+        bind_source(&socket, dest, source, source_port)?;
+    }
+    socket.set_linger(Some(Duration::ZERO))?;
+    Ok(socket)
+}
+
+/// Connect to `dest` from `source` (any local address if None) and return
+/// how long the handshake took. A refused connection counts as a failure
+/// like any other.
+pub async fn connect(
+    dest: SocketAddr,
+    source: Option<IpAddr>,
+    source_port: Option<u16>,
+    mark: Option<u32>,
+    vrf: Option<&str>,
+    timeout: Duration,
+) -> Result<Duration, PingError> {
+    let socket = probe_socket(&dest, source, source_port, mark, vrf)?;
+    let started = Instant::now();
+    match tokio::time::timeout(timeout, socket.connect(dest)).await {
+        Ok(Ok(_stream)) => Ok(started.elapsed()),
+        Ok(Err(e)) => Err(e.into()),
+        Err(_) => Err(PingError::Timeout),
+    }
+}
+
+/// Where the time of one `--http` request went.
+pub struct HttpTiming {
+    /// The TCP handshake.
+    pub connect: Duration,
+    /// From the request being sent to the first byte of the response.
+    pub ttfb: Duration,
+}
+
+/// Connect to `dest` as `connect` does, send `request` and wait for the
+/// first byte of the response; `timeout` bounds the whole exchange. The
+/// rest of the response is not read. A connection closed before any
+/// response counts as a failure.
+pub async fn http(
+    dest: SocketAddr,
+    source: Option<IpAddr>,
+    source_port: Option<u16>,
+    mark: Option<u32>,
+    vrf: Option<&str>,
+    timeout: Duration,
+    request: &[u8],
+) -> Result<HttpTiming, PingError> {
+    let socket = probe_socket(&dest, source, source_port, mark, vrf)?;
+    let exchange = async {
+        let started = Instant::now();
+        let mut stream = socket.connect(dest).await?;
+        let connect = started.elapsed();
+        stream.write_all(request).await?;
+        let sent = Instant::now();
+        let mut first = [0u8; 1];
+        if stream.read(&mut first).await? == 0 {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "connection closed without a response"));
+        }
+        Ok(HttpTiming { connect, ttfb: sent.elapsed() })
+    };
+    match tokio::time::timeout(timeout, exchange).await {
+        Ok(Ok(timing)) => Ok(timing),
+        Ok(Err(e)) => Err(e.into()),
+        Err(_) => Err(PingError::Timeout),
+    }
+}
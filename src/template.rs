@@ -0,0 +1,133 @@
+//! One custom line per target (`--template`).
+//!
+//! ```text
+//! --template '{target} {loss}% {avg}ms {status}'
+//! ```
+//!
+//! Placeholders are resolved when the template is parsed, so a typo is
+//! reported at startup. `{{` and `}}` stand for literal braces.
+
+use crate::expr::Metric;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Field {
+    /// The server name, as in the JSON result.
+    Target,
+    Status,
+    /// RFC 3550 jitter in milliseconds.
+    Jitter,
+    /// Why the target failed; empty when it did not.
+    Error,
+    /// One of the `--success-expr` metrics.
+    Metric(Metric),
+}
+
+const FIELDS: [(&str, Field); 4] = [
+    ("target", Field::Target),
+    ("status", Field::Status),
+    ("jitter", Field::Jitter),
+    ("error", Field::Error),
+];
+
+impl Field {
+    fn from_name(name: &str) -> Option<Field> {
+        let metrics = Metric::NAMES.iter().map(|(name, metric)| (*name, Field::Metric(*metric)));
+        FIELDS.into_iter().chain(metrics).find(|(n, _)| *n == name).map(|(_, field)| field)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Piece {
+    Text(String),
+    Field(Field),
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Template(Vec<Piece>);
+
+impl Template {
+    /// The line for one target, with `value` giving each field's text.
+    pub fn render(&self, value: &dyn Fn(Field) -> String) -> String {
+        self.0
+            .iter()
+            .map(|piece| match piece {
+                Piece::Text(text) => text.clone(),
+                Piece::Field(field) => value(*field),
+            })
+            .collect()
+    }
+}
+
+pub fn parse(input: &str) -> Result<Template, String> {
+    let mut pieces = Vec::new();
+    let mut text = String::new();
+    let mut chars = input.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '{' if chars.as_str().starts_with('{') => {
+                chars.next();
+                text.push('{');
+            }
+            '}' if chars.as_str().starts_with('}') => {
+                chars.next();
+                text.push('}');
+            }
+            '{' => {
+                let Some((name, rest)) = chars.as_str().split_once('}') else {
+                    return Err("unclosed '{' in template".to_string());
+                };
+                let field = Field::from_name(name.trim()).ok_or_else(|| {
+                    let known: Vec<&str> = FIELDS.iter().map(|(n, _)| *n).chain(Metric::NAMES.iter().map(|(n, _)| *n)).collect();
+                    format!("unknown placeholder '{{{}}}' (known: {})", name, known.join(", "))
+                })?;
+                if !text.is_empty() {
+                    pieces.push(Piece::Text(std::mem::take(&mut text)));
+                }
+                pieces.push(Piece::Field(field));
+                chars = rest.chars();
+            }
+            '}' => return Err("unmatched '}' in template, write '}}' for a literal one".to_string()),
+            c => text.push(c),
+        }
+    }
+    if !text.is_empty() {
+        pieces.push(Piece::Text(text));
+    }
+    Ok(Template(pieces))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn render(input: &str) -> String {
+        let value = |field: Field| match field {
+            Field::Target => "192.0.2.1".to_string(),
+            Field::Status => "ok".to_string(),
+            Field::Metric(Metric::Loss) => "0.0".to_string(),
+            _ => "?".to_string(),
+        };
+        parse(input).unwrap_or_else(|e| panic!("{}: {}", input, e)).render(&value)
+    }
+
+    #[test]
+    fn placeholders_are_replaced() {
+        assert_eq!(render("{target} {loss}% { status }"), "192.0.2.1 0.0% ok");
+        assert_eq!(render("no placeholders"), "no placeholders");
+    }
+
+    #[test]
+    fn doubled_braces_are_literal() {
+        assert_eq!(render("{{target}}"), "{target}");
+        assert_eq!(render("{{{target}}}"), "{192.0.2.1}");
+        assert_eq!(render("}}{{"), "}{");
+    }
+
+    #[test]
+    fn malformed_templates_are_rejected() {
+        let e = parse("{target} {tagret}").unwrap_err();
+        assert!(e.starts_with("unknown placeholder '{tagret}'"), "{}", e);
+        assert_eq!(parse("{target").unwrap_err(), "unclosed '{' in template");
+        assert_eq!(parse("target}").unwrap_err(), "unmatched '}' in template, write '}}' for a literal one");
+    }
+}
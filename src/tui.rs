@@ -0,0 +1,240 @@
+//! Full-screen dashboard on the terminal (`--tui`).
+//!
+//! The dashboard is drawn on stderr with plain ANSI escapes, so stdout stays
+//! free for the summary printed once it closes. Every target's stats are
+//! redrawn a few times a second: one row per target with the running loss,
+//! average and p95, a graph of the first target's recent RTTs, and the most
+//! recent log events, which go to the dashboard instead of stderr while it
+//! is open. `q` stops the run like Ctrl+C does.
+
+use std::collections::VecDeque;
+use std::io::{self, Write};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use tokio::sync::Mutex;
+use tokio::task::JoinHandle;
+
+use crate::{create_result, Args, PingStats, INTERRUPTED};
+
+const REDRAW: Duration = Duration::from_millis(200);
+/// Log lines kept for the events pane.
+const MAX_EVENTS: usize = 100;
+const GRAPH_ROWS: usize = 8;
+const BLOCKS: [char; 8] = ['▁', '▂', '▃', '▄', '▅', '▆', '▇', '█'];
+
+/// The stats of every target pinged, in the order they started.
+static TARGETS: std::sync::Mutex<Vec<(String, Arc<Mutex<PingStats>>)>> = std::sync::Mutex::new(Vec::new());
+static EVENTS: std::sync::Mutex<VecDeque<String>> = std::sync::Mutex::new(VecDeque::new());
+
+/// Show `stats` as `target`'s row, replacing the one of an earlier run of
+/// the same target.
+pub fn watch(target: &str, stats: Arc<Mutex<PingStats>>) {
+    let mut targets = TARGETS.lock().unwrap();
+    match targets.iter_mut().find(|(name, _)| name == target) {
+        Some((_, watched)) => *watched = stats,
+        None => targets.push((target.to_string(), stats)),
+    }
+}
+
+/// Log writer that keeps the latest lines for the events pane.
+pub struct Events;
+
+impl Write for Events {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut events = EVENTS.lock().unwrap();
+        for line in String::from_utf8_lossy(buf).lines().filter(|line| !line.is_empty()) {
+            if events.len() == MAX_EVENTS {
+                events.pop_front();
+            }
+            events.push_back(line.to_string());
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+pub struct Dashboard {
+    redraw: JoinHandle<()>,
+    #[cfg(target_os = "linux")]
+    saved: Option<libc::termios>,
+}
+
+impl Dashboard {
+    /// Switch the terminal to the dashboard and start drawing it. `q` on
+    /// stdin sets `stop`.
+    pub fn open(config: Arc<Args>, stop: Arc<AtomicBool>) -> Self {
+        #[cfg(target_os = "linux")]
+        let saved = raw_input();
+        eprint!("\x1b[?1049h\x1b[?25l");
+
+        std::thread::spawn(move || {
+            use std::io::Read;
+
+            for byte in io::stdin().lock().bytes() {
+                if let Ok(b'q' | b'Q') = byte {
+                    INTERRUPTED.store(true, Ordering::Relaxed);
+                    stop.store(true, Ordering::Relaxed);
+                    return;
+                }
+            }
+        });
+
+        let redraw = tokio::spawn(async move {
+            let started = Instant::now();
+            let mut ticker = tokio::time::interval(REDRAW);
+            loop {
+                ticker.tick().await;
+                let frame = render(&config, started.elapsed()).await;
+                let mut stderr = io::stderr().lock();
+                let _ = stderr.write_all(frame.as_bytes());
+                let _ = stderr.flush();
+            }
+        });
+
+        Self {
+            redraw,
+            #[cfg(target_os = "linux")]
+            saved,
+        }
+    }
+}
+
+impl Drop for Dashboard {
+    /// Give the terminal back as it was, also when the run ends in an error.
+    fn drop(&mut self) {
+        self.redraw.abort();
+        eprint!("\x1b[?25h\x1b[?1049l");
+        #[cfg(target_os = "linux")]
+        if let Some(saved) = &self.saved {
+            unsafe { libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, saved) };
+        }
+    }
+}
+
+/// Take keys from stdin as they are pressed, without echoing them. Ctrl+C
+/// still raises SIGINT. Returns the settings to restore, or None when stdin
+/// is not a terminal.
+#[cfg(target_os = "linux")]
+fn raw_input() -> Option<libc::termios> {
+    let mut saved: libc::termios = unsafe { std::mem::zeroed() };
+    if unsafe { libc::tcgetattr(libc::STDIN_FILENO, &mut saved) } != 0 {
+        return None;
+    }
+    let mut raw = saved;
+    raw.c_lflag &= !(libc::ICANON | libc::ECHO);
+    raw.c_cc[libc::VMIN] = 1;
+    raw.c_cc[libc::VTIME] = 0;
+    unsafe { libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &raw) };
+    Some(saved)
+}
+
+/// Columns and rows of the terminal on stderr.
+#[cfg(target_os = "linux")]
+fn terminal_size() -> (usize, usize) {
+    let mut size: libc::winsize = unsafe { std::mem::zeroed() };
+    match unsafe { libc::ioctl(libc::STDERR_FILENO, libc::TIOCGWINSZ, &mut size) } {
+        0 if size.ws_col > 0 && size.ws_row > 0 => (size.ws_col as usize, size.ws_row as usize),
+        _ => (80, 24),
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn terminal_size() -> (usize, usize) {
+    (80, 24)
+}
+
+fn ms(rtt: Duration) -> String {
+    format!("{:.2}ms", rtt.as_secs_f64() * 1000.0)
+}
+
+/// One column per RTT, the tallest scaled to the full graph height.
+fn graph(rtts: &[Duration], rows: usize) -> Vec<String> {
+    let max = rtts.iter().max().copied().unwrap_or_default().max(Duration::from_micros(1));
+    // Height of each column in eighths of a row.
+    let heights: Vec<usize> = rtts
+        .iter()
+        .map(|rtt| ((rtt.as_secs_f64() / max.as_secs_f64()) * (rows * 8) as f64).ceil().max(1.0) as usize)
+        .collect();
+    (0..rows)
+        .rev()
+        .map(|row| {
+            heights
+                .iter()
+                .map(|&height| match height.saturating_sub(row * 8) {
+                    0 => ' ',
+                    eighths => BLOCKS[eighths.min(8) - 1],
+                })
+                .collect()
+        })
+        .collect()
+}
+
+async fn render(config: &Args, elapsed: Duration) -> String {
+    let (width, height) = terminal_size();
+    let targets = TARGETS.lock().unwrap().clone();
+    let mut lines = vec![
+        format!(
+            "peckr  {} target{}  {}s  (q or Ctrl+C to stop)",
+            targets.len(),
+            if targets.len() == 1 { "" } else { "s" },
+            elapsed.as_secs()
+        ),
+        String::new(),
+        format!(
+            "{:<24} {:>6} {:>6} {:>7} {:>10} {:>10} {:>10}  STATUS",
+            "TARGET", "SENT", "RECV", "LOSS", "LAST", "AVG", "P95"
+        ),
+    ];
+    let mut recent = Vec::new();
+    for (index, (target, stats)) in targets.iter().enumerate() {
+        let stats = stats.lock().await;
+        let status = create_result(config, target, &stats).status;
+        let last = stats.samples.last().map_or_else(|| "-".to_string(), |sample| ms(sample.rtt));
+        let (avg, p95) = match stats.received {
+            0 => ("-".to_string(), "-".to_string()),
+            _ => (ms(stats.avg_rtt()), ms(stats.percentile(95.0))),
+        };
+        lines.push(format!(
+            "{:<24} {:>6} {:>6} {:>6.1}% {:>10} {:>10} {:>10}  {}",
+            target,
+            stats.sent,
+            stats.received,
+            stats.packet_loss(),
+            last,
+            avg,
+            p95,
+            status.name()
+        ));
+        if index == 0 {
+            let columns = width.saturating_sub(2);
+            let skip = stats.samples.len().saturating_sub(columns);
+            recent = stats.samples[skip..].iter().map(|sample| sample.rtt).collect();
+        }
+    }
+
+    if let Some((target, _)) = targets.first() {
+        let max = recent.iter().max().copied().unwrap_or_default();
+        lines.push(String::new());
+        lines.push(format!("RTT {}, last {} replies, peak {}", target, recent.len(), ms(max)));
+        let rows = GRAPH_ROWS.min(height.saturating_sub(lines.len() + 4)).max(1);
+        lines.extend(graph(&recent, rows).into_iter().map(|row| format!("  {}", row)));
+    }
+
+    lines.push(String::new());
+    lines.push("Events".to_string());
+    let room = height.saturating_sub(lines.len());
+    let events = EVENTS.lock().unwrap();
+    lines.extend(events.iter().skip(events.len().saturating_sub(room)).cloned());
+
+    // No newline after the bottom row, which would scroll the screen.
+    let rows: Vec<String> = lines.iter().take(height).map(|line| line.chars().take(width).collect()).collect();
+    let mut frame = String::from("\x1b[H");
+    frame.push_str(&rows.join("\x1b[K\r\n"));
+    frame.push_str("\x1b[K\x1b[J");
+    frame
+}
@@ -0,0 +1,121 @@
+//! Probes through a Linux VRF (`--vrf`).
+//!
+//! Every socket peckr opens is bound to the VRF's master device with
+//! SO_BINDTODEVICE. For a VRF that does more than pick the egress device:
+//! the kernel then looks routes up in the VRF's own table, so targets only
+//! reachable inside the routing domain answer, and source addresses and
+//! MTUs come from that domain too.
+//!
+//! Binding to an ordinary device would be accepted by the kernel and
+//! silently route by the main table, so the device's link kind is checked
+//! over rtnetlink first.
+
+use std::io;
+
+#[cfg(target_os = "linux")]
+use std::io::{Read, Write};
+
+/// The link kind the kernel reports for a VRF master device.
+const VRF_KIND: &str = "vrf";
+
+#[cfg(target_os = "linux")]
+mod netlink {
+    pub const NLMSG_HDRLEN: usize = 16;
+    pub const IFINFOMSG_LEN: usize = 16;
+    pub const RTATTR_HDRLEN: usize = 4;
+    pub const NLMSG_ERROR: u16 = 2;
+    pub const RTM_GETLINK: u16 = 18;
+    pub const RTM_NEWLINK: u16 = 16;
+    pub const NLM_F_REQUEST: u16 = 1;
+    pub const IFLA_LINKINFO: u16 = 18;
+    pub const IFLA_INFO_KIND: u16 = 1;
+    /// Nested attributes carry this flag in their type.
+    pub const NLA_TYPE_MASK: u16 = 0x3fff;
+}
+
+/// Check that `name` is a VRF master device, with an error saying what it
+/// is otherwise.
+pub fn check(name: &str) -> Result<(), String> {
+    match link_kind(name) {
+        Ok(Some(kind)) if kind == VRF_KIND => Ok(()),
+        Ok(Some(kind)) => Err(format!("--vrf {} is a {} device, not a VRF", name, kind)),
+        Ok(None) => Err(format!("--vrf {} is not a VRF", name)),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Err(format!("--vrf {}: no such device", name)),
+        Err(e) => Err(format!("Failed to look up --vrf {}: {}", name, e)),
+    }
+}
+
+/// The `IFLA_INFO_KIND` of device `name`, such as `vrf` or `veth`; None for
+/// plain devices that report no kind.
+#[cfg(target_os = "linux")]
+fn link_kind(name: &str) -> io::Result<Option<String>> {
+    use netlink::*;
+
+    let c_name = std::ffi::CString::new(name).map_err(|_| io::Error::from(io::ErrorKind::NotFound))?;
+    let index = unsafe { libc::if_nametoindex(c_name.as_ptr()) };
+    if index == 0 {
+        return Err(io::Error::from(io::ErrorKind::NotFound));
+    }
+
+    let mut request = Vec::with_capacity(NLMSG_HDRLEN + IFINFOMSG_LEN);
+    request.extend_from_slice(&((NLMSG_HDRLEN + IFINFOMSG_LEN) as u32).to_ne_bytes());
+    request.extend_from_slice(&RTM_GETLINK.to_ne_bytes());
+    request.extend_from_slice(&NLM_F_REQUEST.to_ne_bytes());
+    request.extend_from_slice(&1u32.to_ne_bytes()); // sequence
+    request.extend_from_slice(&0u32.to_ne_bytes()); // port ID, filled in by the kernel
+    // struct ifinfomsg: family, padding, type, index, flags, change mask.
+    request.extend_from_slice(&[libc::AF_UNSPEC as u8, 0, 0, 0]);
+    request.extend_from_slice(&(index as i32).to_ne_bytes());
+    request.extend_from_slice(&[0; 8]);
+
+    let socket = socket2::Socket::new(
+        socket2::Domain::from(libc::AF_NETLINK),
+        socket2::Type::RAW,
+        Some(socket2::Protocol::from(libc::NETLINK_ROUTE)),
+    )?;
+    (&socket).write_all(&request)?;
+    let mut response = vec![0u8; 16384];
+    let len = (&socket).read(&mut response)?;
+    let response = &response[..len];
+
+    let u16_at = |at: usize| response.get(at..at + 2).map(|b| u16::from_ne_bytes([b[0], b[1]]));
+    match u16_at(4) {
+        Some(RTM_NEWLINK) => {}
+        Some(NLMSG_ERROR) => {
+            let errno = response.get(NLMSG_HDRLEN..NLMSG_HDRLEN + 4).map_or(0, |b| i32::from_ne_bytes([b[0], b[1], b[2], b[3]]));
+            return Err(io::Error::from_raw_os_error(-errno));
+        }
+        _ => return Err(io::Error::new(io::ErrorKind::InvalidData, "unexpected rtnetlink reply")),
+    }
+
+    let end = response.len().min(u32::from_ne_bytes(response[..4].try_into().unwrap_or_default()) as usize);
+    Ok(attribute(response, NLMSG_HDRLEN + IFINFOMSG_LEN, end, IFLA_LINKINFO)
+        .and_then(|(start, end)| attribute(response, start, end, IFLA_INFO_KIND))
+        .map(|(start, end)| String::from_utf8_lossy(&response[start..end]).trim_end_matches('\0').to_string()))
+}
+
+#[cfg(not(target_os = "linux"))]
+fn link_kind(_name: &str) -> io::Result<Option<String>> {
+    Err(io::Error::new(io::ErrorKind::Unsupported, "VRFs are a Linux feature"))
+}
+
+/// The payload bounds of the first `wanted` attribute among those between
+/// `at` and `end`.
+#[cfg(target_os = "linux")]
+fn attribute(message: &[u8], mut at: usize, end: usize, wanted: u16) -> Option<(usize, usize)> {
+    use netlink::*;
+
+    while at + RTATTR_HDRLEN <= end {
+        let len = u16::from_ne_bytes([message[at], message[at + 1]]) as usize;
+        let kind = u16::from_ne_bytes([message[at + 2], message[at + 3]]) & NLA_TYPE_MASK;
+        if len < RTATTR_HDRLEN || at + len > end {
+            return None;
+        }
+        if kind == wanted {
+            return Some((at + RTATTR_HDRLEN, at + len));
+        }
+        // Attributes are padded to 4 bytes.
+        at += (len + 3) & !3;
+    }
+    None
+}
@@ -0,0 +1,93 @@
+//! Results pushed to an HTTP endpoint (`--webhook`).
+//!
+//! Every result is POSTed as the JSON object `--format json` prints for it:
+//! each `--report-every` report while the run goes on, then the final result
+//! of every target. A background task does the sending so a slow endpoint
+//! never holds up the pings: results wait in a bounded queue, a failed POST
+//! is retried with exponential backoff, and a result that finds the queue
+//! full is dropped with a warning.
+//!
+//! Only plain `http://` URLs are supported.
+
+use std::sync::Mutex;
+use std::time::Duration;
+
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+use tracing::warn;
+
+use crate::http::Endpoint;
+
+/// Results waiting to be sent; more than this and the endpoint is not
+/// keeping up.
+const QUEUE: usize = 64;
+/// Retries after a failed POST, waiting 1s, 2s, 4s... in between.
+const RETRIES: u32 = 3;
+const FIRST_BACKOFF: Duration = Duration::from_secs(1);
+/// Limit on connecting, sending and reading the status line of one POST.
+const POST_TIMEOUT: Duration = Duration::from_secs(5);
+/// How long the results still queued at the end of the run may take.
+const FINISH_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// POST `body`, retrying with backoff; a result that still fails is given
+/// up on with a warning.
+async fn deliver(endpoint: &Endpoint, body: &str) {
+    let mut backoff = FIRST_BACKOFF;
+    for attempt in 0..=RETRIES {
+        let error = match tokio::time::timeout(POST_TIMEOUT, endpoint.post(body)).await {
+            Ok(Ok(())) => return,
+            Ok(Err(e)) => e,
+            Err(_) => "timed out".to_string(),
+        };
+        if attempt == RETRIES {
+            warn!(webhook = %endpoint.authority, "Webhook POST failed, dropping the result: {}", error);
+            return;
+        }
+        warn!(webhook = %endpoint.authority, "Webhook POST failed, retrying in {:?}: {}", backoff, error);
+        tokio::time::sleep(backoff).await;
+        backoff *= 2;
+    }
+}
+
+pub struct Webhook {
+    queue: Mutex<Option<mpsc::Sender<String>>>,
+    worker: Mutex<Option<JoinHandle<()>>>,
+}
+
+impl Webhook {
+    /// Check the URL and start the sending task.
+    pub fn connect(url: &str) -> Result<Self, String> {
+        let endpoint = Endpoint::parse("--webhook", url)?;
+        let (queue, mut pending) = mpsc::channel::<String>(QUEUE);
+        let worker = tokio::spawn(async move {
+            while let Some(body) = pending.recv().await {
+                deliver(&endpoint, &body).await;
+            }
+        });
+        Ok(Self {
+            queue: Mutex::new(Some(queue)),
+            worker: Mutex::new(Some(worker)),
+        })
+    }
+
+    /// Queue one result, a JSON object, without waiting.
+    pub fn send(&self, body: String) {
+        if let Some(queue) = self.queue.lock().unwrap().as_ref() {
+            if let Err(mpsc::error::TrySendError::Full(_)) = queue.try_send(body) {
+                warn!("Webhook queue full, dropping a result");
+            }
+        }
+    }
+
+    /// Stop taking results and give the queued ones a last chance to be
+    /// sent before the process exits.
+    pub async fn finish(&self) {
+        self.queue.lock().unwrap().take();
+        let Some(worker) = self.worker.lock().unwrap().take() else {
+            return;
+        };
+        if tokio::time::timeout(FINISH_TIMEOUT, worker).await.is_err() {
+            warn!("Webhook still sending at exit, results not delivered");
+        }
+    }
+}